@@ -1,11 +1,25 @@
 use kvs::engine::sled::SledKvsEngine;
 use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
-use std::path::Path;
+use std::net::TcpListener;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
 
-use criterion::{criterion_group, criterion_main, Criterion};
-use kvs::engine::store::KvStore;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kvs::client::KvsClient;
+use kvs::engine::index::IndexKind;
+use kvs::engine::store::{KvStore, KvStoreConfig, SyncPolicy};
 use kvs::engine::KvsEngine;
+use kvs::server;
+use kvs::thread_pool::naive::NaiveThreadPool;
+use kvs::thread_pool::rayon::RayonThreadPool;
+use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::ThreadPool;
+use tempfile::TempDir;
 
 fn gen_keys_values(num: usize, size: usize) -> Vec<(String, String)> {
     let mut kvs: Vec<(String, String)> = Vec::with_capacity(num);
@@ -28,8 +42,10 @@ fn gen_keys_values(num: usize, size: usize) -> Vec<(String, String)> {
 }
 
 fn bench_write(c: &mut Criterion) {
-    let kv_store: KvStore<String, String> = KvStore::open(Path::new("./benches/kvstore")).unwrap();
-    let sled_store: SledKvsEngine = SledKvsEngine::new(Path::new("./benches/sledstore")).unwrap();
+    let kv_dir = TempDir::new().unwrap();
+    let sled_dir = TempDir::new().unwrap();
+    let kv_store: KvStore<String, String> = KvStore::open(kv_dir.path()).unwrap();
+    let sled_store: SledKvsEngine = SledKvsEngine::new(sled_dir.path()).unwrap();
 
     let mut group = c.benchmark_group("write");
     group.sample_size(10);
@@ -56,38 +72,670 @@ fn bench_write(c: &mut Criterion) {
     group.finish();
 }
 
-// fn kvs_write(c: &mut Criterion) {
-//     let mut kv_store: KvStore<String, String> = KvStore::open(Path::new("./benches")).unwrap();
-//     let mut group = c.benchmark_group("kvs_write");
-
-//     group.sample_size(2);
-//     for kv in ks_vs {
-//         group.bench_with_input(BenchmarkId::from_parameter(format!("{},{}", kv.0, kv.1)), &kv, |b, kv| {
-//           b.iter(|| {
-//               kv_store
-//                   .set(kv.0.clone(), kv.1.clone())
-//                   .expect("error while writing values");
-//           })
-//         });
-//     }
-//     group.finish();
-// }
-
-// fn sled_write(c: &mut Criterion) {
-//     let mut sled_store: SledKvsEngine = SledKvsEngine::new(Path::new("./benches")).unwrap();
-//     let ks_vs = gen_keys_values(100, 1000000);
-//     let mut group = c.benchmark_group("kvs_write");
-//     for kv in ks_vs {
-//         group.bench_with_input(BenchmarkId::from_parameter(format!("{},{}", kv.0, kv.1)), &kv, |b, kv| {
-//           b.iter(|| {
-//               sled_store
-//                   .set(kv.0.clone(), kv.1.clone())
-//                   .expect("error while writing values");
-//           })
-//         });
-//     }
-//     group.finish();
-// }
-
-criterion_group!(benches, bench_write);
+// Overwrites a fixed set of keys so dead records pile up and the kvs store has to compact under
+// the load, which is where its write cost differs most from sled's
+fn bench_write_sizes(c: &mut Criterion) {
+    const KEYS: usize = 100;
+    let mut group = c.benchmark_group("write_size");
+    group.sample_size(10);
+    for value_size in [10, 1024, 100 * 1024] {
+        let value: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(value_size)
+            .map(char::from)
+            .collect();
+        group.throughput(Throughput::Bytes(value_size as u64));
+
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("kvs_store", value_size),
+            &value,
+            |b, value| {
+                let mut key_id = 0;
+                b.iter(|| {
+                    key_id = (key_id + 1) % KEYS;
+                    kv_store
+                        .set(format!("key{}", key_id), value.clone())
+                        .expect("error while writing values");
+                })
+            },
+        );
+        drop(kv_store);
+
+        let temp_dir = TempDir::new().unwrap();
+        let sled_store = SledKvsEngine::new(temp_dir.path()).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("sled_store", value_size),
+            &value,
+            |b, value| {
+                let mut key_id = 0;
+                b.iter(|| {
+                    key_id = (key_id + 1) % KEYS;
+                    sled_store
+                        .set(format!("key{}", key_id), value.clone())
+                        .expect("error while writing values");
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+const READ_KEYS: usize = 10_000;
+
+fn populate<E: KvsEngine<String, String>>(engine: &E) {
+    for key_id in 0..READ_KEYS {
+        engine
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .expect("error while writing values");
+    }
+}
+
+// Hot reads keep hitting the same handful of keys, while cold reads walk every key in a random
+// order, so the difference shows what the page cache and index locality are worth
+fn bench_read_engine<E: KvsEngine<String, String>>(c: &mut Criterion, name: &str, engine: E) {
+    populate(&engine);
+    let mut cold_keys: Vec<String> = (0..READ_KEYS).map(|id| format!("key{}", id)).collect();
+    cold_keys.shuffle(&mut thread_rng());
+    let hot_keys = &cold_keys[..10];
+
+    let mut group = c.benchmark_group("read");
+    for (temperature, keys) in [("hot", hot_keys), ("cold", &cold_keys[..])] {
+        group.bench_function(BenchmarkId::new(name, temperature), |b| {
+            let mut keys = keys.iter().cycle();
+            b.iter(|| {
+                engine
+                    .get(keys.next().unwrap().clone())
+                    .expect("error while reading values")
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_read(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    bench_read_engine(
+        c,
+        "kvs_store",
+        KvStore::<String, String>::open(temp_dir.path()).unwrap(),
+    );
+    let temp_dir = TempDir::new().unwrap();
+    bench_read_engine(
+        c,
+        "sled_store",
+        SledKvsEngine::new(temp_dir.path()).unwrap(),
+    );
+}
+
+/// Keys read by each range scan in the index benchmark
+const RANGE_LEN: usize = 100;
+
+// Point lookups favour the hash index, while range scans favour the ordered one, which only
+// visits the keys in range instead of every key in the store
+fn bench_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index");
+    for (name, index) in [("hash", IndexKind::Hash), ("ordered", IndexKind::Ordered)] {
+        let temp_dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            index,
+            ..KvStoreConfig::default()
+        };
+        let kv_store: KvStore<String, String> =
+            KvStore::open_with_config(temp_dir.path(), config).unwrap();
+        // Padded so the keys sort numerically and every range holds RANGE_LEN of them
+        let mut keys: Vec<String> = (0..READ_KEYS).map(|id| format!("key{:05}", id)).collect();
+        for key in &keys {
+            kv_store
+                .set(key.clone(), "value".to_owned())
+                .expect("error while writing values");
+        }
+        keys.shuffle(&mut thread_rng());
+
+        group.bench_function(BenchmarkId::new("point", name), |b| {
+            let mut keys = keys.iter().cycle();
+            b.iter(|| {
+                kv_store
+                    .get(keys.next().unwrap().clone())
+                    .expect("error while reading values")
+            })
+        });
+        group.bench_function(BenchmarkId::new("range", name), |b| {
+            let mut starts = (0..READ_KEYS - RANGE_LEN).cycle();
+            b.iter(|| {
+                let start = starts.next().unwrap();
+                let pairs = kv_store
+                    .scan(
+                        Bound::Included(format!("key{:05}", start)),
+                        Bound::Excluded(format!("key{:05}", start + RANGE_LEN)),
+                    )
+                    .expect("error while scanning");
+                assert_eq!(pairs.len(), RANGE_LEN);
+                pairs
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Pairs loaded per measured iteration of the bulk load benchmark
+const INGEST_KEYS: usize = 1_000_000;
+
+// A fresh store filled from scratch, with a `set` per pair against one `ingest` of them all
+fn bench_ingest(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(INGEST_KEYS as u64));
+    let pairs = || (0..INGEST_KEYS).map(|id| (format!("key{}", id), format!("value{}", id)));
+    group.bench_function("set_loop", |b| {
+        b.iter_batched(
+            || TempDir::new().unwrap(),
+            |temp_dir| {
+                let kv_store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+                for (key, value) in pairs() {
+                    kv_store
+                        .set(key, value)
+                        .expect("error while writing values");
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("ingest", |b| {
+        b.iter_batched(
+            || TempDir::new().unwrap(),
+            |temp_dir| {
+                let kv_store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+                kv_store.ingest(pairs()).expect("error while ingesting");
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+// Small values read back at random, where the syscall per `pread` is most of the cost of a get
+fn bench_mmap_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_path");
+    for (name, mmap_reads) in [("pread", false), ("mmap", true)] {
+        let temp_dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            mmap_reads,
+            ..KvStoreConfig::default()
+        };
+        let kv_store: KvStore<String, String> =
+            KvStore::open_with_config(temp_dir.path(), config).unwrap();
+        let mut keys: Vec<String> = (0..READ_KEYS).map(|id| format!("key{}", id)).collect();
+        for key in &keys {
+            kv_store
+                .set(key.clone(), "value".to_owned())
+                .expect("error while writing values");
+        }
+        keys.shuffle(&mut thread_rng());
+
+        group.bench_function(name, |b| {
+            let mut keys = keys.iter().cycle();
+            b.iter(|| {
+                kv_store
+                    .get(keys.next().unwrap().clone())
+                    .expect("error while reading values")
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Operations per measured iteration of the mixed workload, split evenly between the threads
+const MIXED_OPS: usize = 1_000;
+/// Percentages of operations in the mixed workload that are reads
+const READ_PERCENTAGES: [u32; 2] = [50, 90];
+
+// Spreads a batch of gets and sets over the shared queue pool the server uses, to see how each
+// engine holds up as more threads contend for its index and log
+fn bench_mixed_engine<E: KvsEngine<String, String>>(c: &mut Criterion, name: &str, engine: E) {
+    populate(&engine);
+    let mut group = c.benchmark_group(format!("mixed/{}", name));
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(MIXED_OPS as u64));
+    for read_percentage in READ_PERCENTAGES {
+        for threads in [1, 2, 4, 8] {
+            let pool = SharedQueueThreadPool::new(threads).unwrap();
+            group.bench_function(
+                BenchmarkId::new(format!("{}%_reads", read_percentage), threads),
+                |b| {
+                    b.iter_custom(|iters| {
+                        let start = Instant::now();
+                        for _ in 0..iters {
+                            let (done, finished) = mpsc::channel();
+                            for _ in 0..threads {
+                                let engine = engine.clone();
+                                let done = done.clone();
+                                pool.spawn(move || {
+                                    let mut rng = thread_rng();
+                                    for _ in 0..MIXED_OPS / threads as usize {
+                                        let key = format!("key{}", rng.gen_range(0..READ_KEYS));
+                                        if rng.gen_range(0..100) < read_percentage {
+                                            engine.get(key).expect("error while reading values");
+                                        } else {
+                                            engine
+                                                .set(key, "value".to_owned())
+                                                .expect("error while writing values");
+                                        }
+                                    }
+                                    done.send(()).unwrap();
+                                })
+                                .expect("error while spawning job");
+                            }
+                            drop(done);
+                            for _ in finished {}
+                        }
+                        start.elapsed()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_mixed(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    bench_mixed_engine(
+        c,
+        "kvs_store",
+        KvStore::<String, String>::open(temp_dir.path()).unwrap(),
+    );
+    let temp_dir = TempDir::new().unwrap();
+    bench_mixed_engine(
+        c,
+        "sled_store",
+        SledKvsEngine::new(temp_dir.path()).unwrap(),
+    );
+}
+
+/// Requests each client sends per measured iteration of the server benchmark
+const SERVER_OPS_PER_CLIENT: usize = 100;
+
+/// Concurrent clients driving the server, taken from `KVS_BENCH_CLIENTS` if set
+fn server_clients() -> usize {
+    std::env::var("KVS_BENCH_CLIENTS")
+        .ok()
+        .and_then(|clients| clients.parse().ok())
+        .unwrap_or(8)
+}
+
+// A connection holds on to a pool thread until it closes, so each client connects, does its
+// share of gets and sets and hangs up. Once clients outnumber threads they queue for a turn,
+// which is where each pool's throughput should flatten out
+fn bench_server_pool<P: ThreadPool>(c: &mut Criterion, name: &str) {
+    let clients = server_clients();
+    let mut group = c.benchmark_group(format!("server/{}", name));
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(
+        (clients * SERVER_OPS_PER_CLIENT) as u64,
+    ));
+    for threads in [1, 2, 4, 8] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::<String, String>::open(temp_dir.path()).unwrap();
+        populate(&store);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                server::serve(listener, store, P::new(threads).unwrap(), &shutdown)
+                    .expect("server failed")
+            })
+        };
+
+        group.bench_function(BenchmarkId::from_parameter(threads), |b| {
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                for _ in 0..iters {
+                    let handles: Vec<_> = (0..clients)
+                        .map(|_| {
+                            thread::spawn(move || {
+                                let mut client: KvsClient =
+                                    KvsClient::connect(addr).expect("unable to connect");
+                                let mut rng = thread_rng();
+                                for op in 0..SERVER_OPS_PER_CLIENT {
+                                    let key = format!("key{}", rng.gen_range(0..READ_KEYS));
+                                    if op % 2 == 0 {
+                                        client.get(key).expect("error while reading values");
+                                    } else {
+                                        client
+                                            .set(key, "value".to_owned())
+                                            .expect("error while writing values");
+                                    }
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                }
+                start.elapsed()
+            })
+        });
+
+        shutdown.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+    }
+    group.finish();
+}
+
+fn bench_server(c: &mut Criterion) {
+    bench_server_pool::<NaiveThreadPool>(c, "naive");
+    bench_server_pool::<SharedQueueThreadPool>(c, "shared_queue");
+    bench_server_pool::<RayonThreadPool>(c, "rayon");
+}
+
+fn bench_sync_policy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync_policy");
+    group.sample_size(10);
+    for (name, sync_policy) in [
+        ("every_write", SyncPolicy::EveryWrite),
+        ("every_100", SyncPolicy::EveryN(100)),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let config = KvStoreConfig {
+            sync_policy,
+            ..KvStoreConfig::default()
+        };
+        let kv_store: KvStore<String, String> =
+            KvStore::open_with_config(temp_dir.path(), config).unwrap();
+        group.bench_function(name, |b| {
+            let mut kv_vec = gen_keys_values(1000, 100);
+            b.iter(|| {
+                let (key, val) = kv_vec
+                    .pop()
+                    .unwrap_or(("key".to_string(), "value".to_string()));
+                kv_store.set(key, val).expect("error while writing values");
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Records written per iteration of the buffer size benchmark
+const BULK_LOAD_RECORDS: usize = 1_000;
+
+// With syncs out of the way the buffer size decides how often a bulk load makes a write call.
+// Each iteration loads a fresh store, so the log never grows past what the page cache absorbs
+// and the numbers don't turn into a measure of the disk
+fn bench_write_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_buffer");
+    group.sample_size(10);
+    let value: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(1024)
+        .map(char::from)
+        .collect();
+    group.throughput(Throughput::Bytes((BULK_LOAD_RECORDS * value.len()) as u64));
+    for write_buffer_size in [8 * 1024, 64 * 1024, 1024 * 1024] {
+        let config = KvStoreConfig {
+            sync_policy: SyncPolicy::OnDropOnly,
+            write_buffer_size,
+            ..KvStoreConfig::default()
+        };
+        group.bench_with_input(
+            BenchmarkId::from_parameter(write_buffer_size),
+            &value,
+            |b, value| {
+                b.iter_batched(
+                    || {
+                        let temp_dir = TempDir::new().unwrap();
+                        let kv_store: KvStore<String, String> =
+                            KvStore::open_with_config(temp_dir.path(), config.clone()).unwrap();
+                        (temp_dir, kv_store)
+                    },
+                    |(temp_dir, kv_store)| {
+                        for key_id in 0..BULK_LOAD_RECORDS {
+                            kv_store
+                                .set(format!("key{}", key_id), value.clone())
+                                .expect("error while writing values");
+                        }
+                        // Dropped outside the measurement, along with its final flush
+                        (temp_dir, kv_store)
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+// Replay streams the log instead of loading it whole, so memory stays flat as the log grows and
+// open time should scale roughly linearly with the number of records
+fn bench_open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    group.sample_size(10);
+    for records in [10_000, 100_000] {
+        let temp_dir = TempDir::new().unwrap();
+        {
+            let kv_store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+            for (key, val) in gen_keys_values(records, 100) {
+                kv_store.set(key, val).expect("error while writing values");
+            }
+        }
+        group.bench_with_input(
+            BenchmarkId::from_parameter(records),
+            &temp_dir,
+            |b, temp_dir| {
+                b.iter(|| {
+                    let kv_store: KvStore<String, String> =
+                        KvStore::open(temp_dir.path()).expect("error while opening store");
+                    kv_store
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+// Replaying into an index sized for every key up front saves rehashing it as it grows. The
+// saved index is turned off, since with one the key count is known anyway
+fn bench_open_presized(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open_presized");
+    group.sample_size(10);
+    for records in [100_000, 1_000_000] {
+        let temp_dir = TempDir::new().unwrap();
+        {
+            let kv_store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+            for (key, val) in gen_keys_values(records, 10) {
+                kv_store.set(key, val).expect("error while writing values");
+            }
+        }
+        for (name, expected_keys) in [("default", None), ("presized", Some(records))] {
+            let config = KvStoreConfig {
+                index_snapshots: false,
+                expected_keys,
+                ..KvStoreConfig::default()
+            };
+            group.bench_with_input(BenchmarkId::new(name, records), &temp_dir, |b, temp_dir| {
+                b.iter(|| {
+                    let kv_store: KvStore<String, String> =
+                        KvStore::open_with_config(temp_dir.path(), config.clone())
+                            .expect("error while opening store");
+                    kv_store
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Sets made by each writer thread per iteration of the concurrent write benchmark
+const WRITES_PER_THREAD: usize = 1_000;
+
+// Writers to different keys only queue up for the append itself, so throughput should hold up
+// better as threads are added than it would with every set serialized end to end. Syncs are
+// left to the drop so the disk doesn't dominate
+fn bench_concurrent_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_write");
+    group.sample_size(10);
+    let config = KvStoreConfig {
+        sync_policy: SyncPolicy::OnDropOnly,
+        ..KvStoreConfig::default()
+    };
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements((threads * WRITES_PER_THREAD) as u64));
+        let temp_dir = TempDir::new().unwrap();
+        let kv_store: KvStore<String, String> =
+            KvStore::open_with_config(temp_dir.path(), config.clone()).unwrap();
+        let values = gen_keys_values(WRITES_PER_THREAD, 100);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        for thread_id in 0..threads {
+                            let kv_store = kv_store.clone();
+                            let values = &values;
+                            scope.spawn(move || {
+                                for (key, val) in values {
+                                    kv_store
+                                        .set(format!("{}-{}", thread_id, key), val.clone())
+                                        .expect("error while writing values");
+                                }
+                            });
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Keys the hot key benchmark's readers and writer all fight over
+const HOT_KEYS: usize = 16;
+/// Gets made by each reader thread per iteration of the hot key benchmark
+const HOT_READS_PER_THREAD: usize = 1_000;
+
+// Readers hammer a handful of keys while a writer keeps overwriting them. The index hands back
+// a copy of each entry, so no shard stays locked across a read from the log and the writer
+// isn't held up behind them
+fn bench_hot_keys(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hot_keys");
+    group.sample_size(10);
+    let temp_dir = TempDir::new().unwrap();
+    let kv_store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+    let keys: Vec<String> = (0..HOT_KEYS).map(|i| format!("hot{}", i)).collect();
+    for key in &keys {
+        kv_store.set(key.clone(), "value".to_owned()).unwrap();
+    }
+    for readers in [1, 4] {
+        group.throughput(Throughput::Elements(
+            (readers * HOT_READS_PER_THREAD) as u64,
+        ));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(readers),
+            &readers,
+            |b, &readers| {
+                b.iter(|| {
+                    let done = AtomicBool::new(false);
+                    thread::scope(|scope| {
+                        let writer = scope.spawn(|| {
+                            let mut rng = thread_rng();
+                            while !done.load(Ordering::SeqCst) {
+                                let key = keys.choose(&mut rng).unwrap().clone();
+                                kv_store
+                                    .set(key, "value".to_owned())
+                                    .expect("error while writing values");
+                            }
+                        });
+                        let readers: Vec<_> = (0..readers)
+                            .map(|_| {
+                                scope.spawn(|| {
+                                    let mut rng = thread_rng();
+                                    for _ in 0..HOT_READS_PER_THREAD {
+                                        let key = keys.choose(&mut rng).unwrap().clone();
+                                        kv_store.get(key).expect("error while reading values");
+                                    }
+                                })
+                            })
+                            .collect();
+                        for reader in readers {
+                            reader.join().unwrap();
+                        }
+                        done.store(true, Ordering::SeqCst);
+                        writer.join().unwrap();
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Pairs written per iteration of the sled batch benchmark
+const SLED_BATCH_RECORDS: usize = 1_000;
+
+// sled's `set` flushes after every insert, where `set_many` applies one `sled::Batch` and
+// flushes once, so the gap is roughly one flush per pair
+fn bench_sled_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sled_batch");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(SLED_BATCH_RECORDS as u64));
+    let pairs: Vec<(String, String)> = (0..SLED_BATCH_RECORDS)
+        .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+        .collect();
+    let fresh_engine = || {
+        let temp_dir = TempDir::new().unwrap();
+        let engine: SledKvsEngine<String, String> = SledKvsEngine::new(temp_dir.path()).unwrap();
+        (temp_dir, engine)
+    };
+    group.bench_function("set", |b| {
+        b.iter_batched(
+            fresh_engine,
+            |(temp_dir, engine)| {
+                for (key, value) in &pairs {
+                    engine
+                        .set(key.clone(), value.clone())
+                        .expect("error while writing values");
+                }
+                (temp_dir, engine)
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("set_many", |b| {
+        b.iter_batched(
+            fresh_engine,
+            |(temp_dir, engine)| {
+                engine
+                    .set_many(pairs.clone())
+                    .expect("error while writing values");
+                (temp_dir, engine)
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_write,
+    bench_write_sizes,
+    bench_concurrent_write,
+    bench_hot_keys,
+    bench_read,
+    bench_index,
+    bench_mmap_reads,
+    bench_ingest,
+    bench_mixed,
+    bench_server,
+    bench_sync_policy,
+    bench_write_buffer,
+    bench_open,
+    bench_open_presized,
+    bench_sled_batch
+);
 criterion_main!(benches);
@@ -0,0 +1,80 @@
+use crate::engine::store::{Key, Value};
+use crate::protocol::{encode_frame, KvRequest, KvResponse, DEFAULT_MAX_FRAME_SIZE};
+use crate::{KvsError, Result};
+use serde::de::DeserializeOwned;
+use std::io;
+use std::marker::PhantomData;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// An async connection to a kvs server over TCP, speaking the same frames as `KvsClient`. A
+/// connection carries one request at a time, so tasks talking to the server concurrently each
+/// open their own
+pub struct AsyncKvsClient<K = String, V = String> {
+    stream: TcpStream,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K: Key, V: Value> AsyncKvsClient<K, V> {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<AsyncKvsClient<K, V>> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|e| KvsError::ConnectionFailed(e.to_string()))?;
+        Ok(AsyncKvsClient {
+            stream,
+            phantom: PhantomData,
+        })
+    }
+
+    pub async fn set(&mut self, key: K, value: V) -> Result<()> {
+        self.request(KvRequest::Set((key, value))).await?;
+        Ok(())
+    }
+
+    pub async fn get(&mut self, key: K) -> Result<Option<V>> {
+        self.request(KvRequest::Get(key)).await
+    }
+
+    pub async fn remove(&mut self, key: K) -> Result<()> {
+        self.request(KvRequest::Rm(key)).await?;
+        Ok(())
+    }
+
+    /// Sends `request` and waits for its response. Errors from the server come back as `Err`.
+    /// The request is taken by value so the future stays `Send` whatever the key type
+    pub async fn request(&mut self, request: KvRequest<K, V>) -> Result<Option<V>> {
+        let frame = encode_frame(&request)?;
+        drop(request);
+        self.stream.write_all(&frame).await?;
+        let response: KvResponse<V, K> = self.read_frame().await?.ok_or_else(|| {
+            KvsError::IOError("connection closed before a response arrived".to_owned())
+        })?;
+        response.value
+    }
+
+    /// Reads one frame the way `protocol::read_frame` does
+    async fn read_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let mut len = [0u8; 4];
+        let mut filled = 0;
+        while filled < len.len() {
+            match self.stream.read(&mut len[filled..]).await? {
+                0 if filled == 0 => return Ok(None),
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended part way through a frame length",
+                    )
+                    .into())
+                }
+                read => filled += read,
+            }
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(KvsError::ValueTooLarge);
+        }
+        let mut payload = vec![0u8; len];
+        self.stream.read_exact(&mut payload).await?;
+        Ok(Some(serde_json::from_slice(&payload)?))
+    }
+}
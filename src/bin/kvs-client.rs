@@ -1,10 +1,12 @@
 use clap::{Args, Parser, Subcommand};
-use kvs::protocol::{KvRequest, KvResponse};
-use kvs::{KvsError, Result};
-use std::{
-    io::Write,
-    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpStream},
-};
+use kvs::client::KvsClient;
+use kvs::engine::watch::WatchEvent;
+use kvs::protocol::KvRequest;
+use kvs::transport::ServerAddr;
+use kvs::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process;
+use std::time::Duration;
 
 #[derive(Debug, Args)]
 struct SetArgs {
@@ -27,11 +29,26 @@ struct RmArgs {
     key: String,
 }
 
+#[derive(Debug, Args)]
+struct WatchArgs {
+    /// prefix of the keys to watch, empty for every key
+    #[clap(default_value = "")]
+    prefix: String,
+}
+
 #[derive(Debug, Subcommand)]
 enum Method {
     Set(SetArgs),
     Get(GetArgs),
     Rm(RmArgs),
+    /// compact the server's store, if it allows admin requests
+    Compact,
+    /// show the server engine's counters
+    Stats,
+    /// print changes to keys starting with a prefix as they happen
+    Watch(WatchArgs),
+    /// check the server is up, printing the round trip time
+    Ping,
 }
 
 impl From<Method> for KvRequest<String, String> {
@@ -40,6 +57,10 @@ impl From<Method> for KvRequest<String, String> {
             Method::Set(set_args) => KvRequest::Set((set_args.key, set_args.value)),
             Method::Get(set_args) => KvRequest::Get(set_args.key),
             Method::Rm(set_args) => KvRequest::Rm(set_args.key),
+            Method::Compact => KvRequest::Compact,
+            Method::Stats => KvRequest::Stats,
+            Method::Watch(watch_args) => KvRequest::Watch(watch_args.prefix),
+            Method::Ping => KvRequest::Ping,
         }
     }
 }
@@ -51,55 +72,70 @@ struct KvClientArgs {
     #[clap(subcommand)]
     method: Method,
 
-    /// address to connect to the server
-    #[clap(short, long, value_parser, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-    addr: SocketAddr,
-}
+    /// address to connect to the server, or unix:<path> for a UNIX domain socket
+    #[clap(short, long, value_parser, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+    addr: ServerAddr,
 
-fn make_request(
-    command: &KvRequest<String, String>,
-    mut stream: TcpStream,
-) -> Result<KvResponse<String>> {
-    serde_json::to_writer(&mut stream, command)?;
-    stream.write(b"\n\n")?;
-    stream.shutdown(Shutdown::Write)?;
-    let response: KvResponse<String> = serde_json::from_reader(&stream)?;
-    Ok(response)
-}
+    /// milliseconds to wait when connecting, and for each read or write on the connection
+    #[clap(long, value_parser, default_value_t = 5000)]
+    timeout: u64,
 
-fn main() -> Result<()> {
-    let args = KvClientArgs::parse();
+    /// times to retry if the server refuses the connection
+    #[clap(long, value_parser, default_value_t = 3)]
+    retries: u32,
 
-    let stream = TcpStream::connect(args.addr)?;
+    /// token to send first, for a server started with --auth-token
+    #[clap(long, value_parser, env = "KVS_AUTH_TOKEN")]
+    auth_token: Option<String>,
+}
 
-    let server_command: KvRequest<String, String> = args.method.into();
+fn run(args: KvClientArgs) -> Result<()> {
+    let mut client: KvsClient =
+        KvsClient::connect_with(args.addr, Duration::from_millis(args.timeout), args.retries)?;
+    if let Some(token) = &args.auth_token {
+        client.authenticate(token)?;
+    }
 
-    match make_request(&server_command, stream)?.value {
-        Ok(optional_value) => match optional_value {
-            Some(val) => {
-                println!("{}", val);
-                Ok(())
+    if let Method::Ping = args.method {
+        let latency = client.ping()?;
+        println!("pong in {:.3}ms", latency.as_secs_f64() * 1000.0);
+        return Ok(());
+    }
+    if let Method::Stats = args.method {
+        let stats = client.stats()?;
+        println!("keys: {}", stats.keys);
+        println!("live bytes: {}", stats.live_bytes);
+        println!("dead bytes: {}", stats.uncompressed_bytes);
+        println!("file size: {}", stats.file_size);
+        println!("compactions: {}", stats.compactions);
+        return Ok(());
+    }
+    if let Method::Watch(watch_args) = args.method {
+        for event in client.watch(watch_args.prefix)? {
+            match event? {
+                WatchEvent::Set(key, value) => println!("set {} {}", key, value),
+                WatchEvent::Removed(key) => println!("rm {}", key),
             }
-            None => {
-                match server_command {
-                    KvRequest::Get(_k) => {
-                        println!("Key not found!");
-                    }
-                    _ => {}
-                };
-                Ok(())
+        }
+        return Ok(());
+    }
+    let server_command: KvRequest<String, String> = args.method.into();
+
+    match client.request(&server_command)? {
+        Some(val) => println!("{}", val),
+        None => {
+            if let KvRequest::Get(_k) = server_command {
+                println!("Key not found!");
             }
-        },
-        Err(e) => {
-            match e {
-                KvsError::NonExistantKey => {
-                    eprintln!("Key not found!");
-                }
-                _ => {
-                    eprintln!("{:?}", e);
-                }
-            };
-            Err(e)
         }
     }
+    Ok(())
+}
+
+fn main() {
+    let args = KvClientArgs::parse();
+    if let Err(e) = run(args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
@@ -1,19 +1,60 @@
 use clap::clap_derive::ArgEnum;
 use clap::Parser;
 use kvs::{
-    engine::KvsEngine, thread_pool::shared_queue::SharedQueueThreadPool, thread_pool::ThreadPool,
+    engine::sled::SledKvsEngine,
+    engine::store::{KvStore, KvStoreConfig},
+    engine::{self, KvsEngine},
+    server::{self, AuthToken, ServerConfig},
+    thread_pool::naive::NaiveThreadPool,
+    thread_pool::rayon::RayonThreadPool,
+    thread_pool::shared_queue::SharedQueueThreadPool,
+    thread_pool::ThreadPool,
+    transport::{Listener, ServerAddr},
     KvsError, Result,
 };
 use log::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, OpenOptions},
-    io::Write,
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
-    path::Path,
+    fs::{self, File, OpenOptions},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
 };
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const DEFAULT_VERBOSITY: usize = 2;
+const MAX_VERBOSITY: usize = 4;
+const CONFIG_WRITE_RETRIES: usize = 50;
+const CONFIG_WRITE_WAIT: Duration = Duration::from_millis(20);
+
+/// Set from the signal handler once SIGINT or SIGTERM arrives
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_shutdown_signal(_signal: libc::c_int) {
+    // Storing to an atomic is about the only thing that is safe inside a signal handler
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+fn install_signal_handlers() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only touches an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(
+                signal,
+                handle_shutdown_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers() {}
 
 #[derive(Debug, Clone, ArgEnum, PartialEq, Serialize, Deserialize)]
 pub enum KvsEngineType {
@@ -21,106 +62,250 @@ pub enum KvsEngineType {
     Kvs,
 }
 
+#[derive(Debug, Clone, ArgEnum)]
+pub enum ThreadPoolType {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
 #[derive(Debug, Parser)] // requires `derive` feature
 #[clap(author, version, about, long_about = None)]
 struct KvServerArgs {
-    #[clap(short, long, value_parser, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000))]
-    addr: SocketAddr,
+    /// address to listen on, or unix:<path> for a UNIX domain socket
+    #[clap(short, long, value_parser, default_value_t = ServerAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 4000)))]
+    addr: ServerAddr,
     #[clap(short, long, value_enum)]
     engine: Option<KvsEngineType>,
-    // #[clap(short = 'v', long, parse(from_occurrences))]
-    // verbose: usize,
+    /// overwrite a corrupt config.info with --engine instead of refusing to start
+    #[clap(long, requires = "engine")]
+    force_engine: bool,
+    /// copy the data over to --engine when the directory uses the other engine, instead of
+    /// refusing to start
+    #[clap(long, requires = "engine")]
+    migrate_engine: bool,
+    /// directory the engine's data and config.info are kept in
+    #[clap(long, value_parser, env = "KVS_DATA_DIR", default_value = "./db")]
+    data_dir: PathBuf,
+    /// thread pool used to handle connections
+    #[clap(long, value_enum, default_value_t = ThreadPoolType::SharedQueue)]
+    pool: ThreadPoolType,
+    /// number of threads in the pool
+    #[clap(long, value_parser, default_value_t = 10)]
+    threads: u32,
+    /// log more, repeat for even more (-vv for trace)
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// largest key in bytes the kvs engine accepts
+    #[clap(long, value_parser)]
+    max_key_size: Option<usize>,
+    /// largest value in bytes the kvs engine accepts
+    #[clap(long, value_parser)]
+    max_value_size: Option<usize>,
+    /// serve admin requests such as compact
+    #[clap(long)]
+    allow_admin: bool,
+    /// seconds a client has to send each request in full before it is closed, 0 to wait forever
+    #[clap(long, value_parser, default_value_t = 30)]
+    read_timeout: u64,
+    /// token clients must send before any other request
+    #[clap(long, value_parser, env = "KVS_AUTH_TOKEN")]
+    auth_token: Option<AuthToken>,
+    /// most connections served at once, others are refused until some close
+    #[clap(long, value_parser)]
+    max_connections: Option<usize>,
+    /// log less, repeat to only log errors
+    #[clap(short, long, action = clap::ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
 }
 
-fn parse_kv_config(db_path: &Path, engine: Option<KvsEngineType>) -> Result<KvsEngineType> {
-    if !db_path.exists() {
-        fs::create_dir_all(&db_path)?;
-    }
+/// Info is the default, each -v steps towards trace and each -q towards errors only
+fn log_verbosity(verbose: u8, quiet: u8) -> usize {
+    (DEFAULT_VERBOSITY + verbose as usize)
+        .saturating_sub(quiet as usize)
+        .min(MAX_VERBOSITY)
+}
+
+/// Settles which engine `db_path` uses. The first server to start there records its choice with
+/// `create_new`, so when several start at once exactly one choice wins and the rest are checked
+/// against it. A corrupt record is only replaced when `force_engine` is set, and a different
+/// engine only migrated to when `migrate` is
+fn parse_kv_config(
+    db_path: &Path,
+    engine: Option<KvsEngineType>,
+    force_engine: bool,
+    migrate: bool,
+) -> Result<KvsEngineType> {
+    fs::create_dir_all(db_path)?;
     let config_file_path = db_path.join("config.info");
-    if config_file_path.exists() {
-        let previous_config: KvsEngineType = serde_json::from_reader(
-            OpenOptions::new()
-                .write(false)
-                .read(true)
-                .open(&config_file_path)
-                .unwrap(),
-        )?;
-        match engine {
-            Some(e) => {
+    let new_engine = engine.clone().unwrap_or(KvsEngineType::Kvs);
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&config_file_path)
+    {
+        Ok(new_config_file) => {
+            serde_json::to_writer(&new_config_file, &new_engine)?;
+            new_config_file.sync_all()?;
+            Ok(new_engine)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let previous_config = match read_kv_config(&config_file_path) {
+                Err(KvsError::CorruptConfig(msg)) if force_engine => {
+                    warn!("{}, overwriting it with {:?}", msg, new_engine);
+                    write_kv_config(&config_file_path, &new_engine)?;
+                    return Ok(new_engine);
+                }
+                read => read?,
+            };
+            if let Some(e) = engine {
                 if previous_config != e {
-                    return Err(KvsError::WrongEngine);
+                    if !migrate {
+                        return Err(KvsError::WrongEngine);
+                    }
+                    migrate_engine(db_path, &previous_config, &e)?;
+                    // Only once the new engine holds everything, so an interrupted migration
+                    // leaves the old one in use
+                    write_kv_config(&config_file_path, &e)?;
+                    return Ok(e);
                 }
             }
-            _ => {}
-        };
-        Ok(previous_config)
-    } else {
-        let new_config_file = std::fs::File::create(&config_file_path)?;
-        let new_engine = engine.unwrap_or(KvsEngineType::Kvs);
-        serde_json::to_writer(new_config_file, &new_engine)?;
-        Ok(new_engine)
+            Ok(previous_config)
+        }
+        Err(e) => Err(e.into()),
     }
 }
 
-fn start_listening(addr: SocketAddr, store: impl KvsEngine<String, String>) -> kvs::Result<()> {
-    let listener = TcpListener::bind(addr)?;
-    let thread_pool = SharedQueueThreadPool::new(10)?;
-    for stream in listener.incoming() {
-        match stream {
-            Ok(mut s) => {
-                let store = store.clone();
-                thread_pool.spawn(move || match serde_json::from_reader(&s) {
-                    Ok(deserialized) => {
-                        debug!("Got from stream: {:?}", deserialized);
-                        let result = match deserialized {
-                            kvs::protocol::KvRequest::Set(kv) => {
-                                store.set(kv.0, kv.1).map(|_| None)
-                            }
-                            kvs::protocol::KvRequest::Get(k) => store.get(k),
-                            kvs::protocol::KvRequest::Rm(k) => store.remove(k).map(|_| None),
-                        };
-                        debug!("Response from store: {:?}", result);
-                        serde_json::to_writer(&s, &kvs::protocol::KvResponse { value: result })
-                            .unwrap();
-                        s.write(b"\n\n").unwrap();
-                        drop(s);
-                    }
-                    Err(err) => {
-                        info!("Could not parse message: {}", err.to_string());
-                    }
-                });
-            }
-            Err(e) => {
-                warn!("Errored in stream: {}", e);
-            }
-        }
+/// Where `engine` keeps its data under `db_path`
+fn engine_dir(db_path: &Path, engine: &KvsEngineType) -> PathBuf {
+    match engine {
+        KvsEngineType::Kvs => db_path.join("store"),
+        KvsEngineType::Sled => db_path.join("sled"),
     }
+}
+
+/// Copies everything `from` holds into a fresh `to`. Anything `to` already has is left over
+/// from before an earlier switch away from it or from an interrupted migration, so it is
+/// cleared first. The data `from` leaves behind is kept
+fn migrate_engine(db_path: &Path, from: &KvsEngineType, to: &KvsEngineType) -> Result<()> {
+    let from_dir = engine_dir(db_path, from);
+    let to_dir = engine_dir(db_path, to);
+    if to_dir.exists() {
+        fs::remove_dir_all(&to_dir)?;
+    }
+    let copied = match to {
+        KvsEngineType::Kvs => engine::migrate(
+            &SledKvsEngine::<String, String>::new(&from_dir)?,
+            &KvStore::open(&to_dir)?,
+            |_| {},
+        )?,
+        KvsEngineType::Sled => engine::migrate(
+            &KvStore::<String, String>::open(&from_dir)?,
+            &SledKvsEngine::new(&to_dir)?,
+            |_| {},
+        )?,
+    };
+    info!("migrated {} keys from {:?} to {:?}", copied, from, to);
     Ok(())
 }
 
-fn main() -> kvs::Result<()> {
-    stderrlog::new()
-        .module(module_path!())
-        .verbosity(2)
-        .init()
-        .unwrap();
-    warn!("version: {}", VERSION);
+/// Replaces `config.info` through a temporary file, so a crash part way leaves the old one
+fn write_kv_config(config_file_path: &Path, engine: &KvsEngineType) -> Result<()> {
+    let tmp_path = config_file_path.with_extension("info.tmp");
+    let tmp_file = File::create(&tmp_path)?;
+    serde_json::to_writer(&tmp_file, engine)?;
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, config_file_path)?;
+    Ok(())
+}
 
-    let args = KvServerArgs::parse();
+/// Reads the engine recorded in `config.info`. A server that has only just created the file may
+/// not have written to it yet, so an empty file is given a moment to fill in. One that still
+/// doesn't parse is `CorruptConfig`
+fn read_kv_config(config_file_path: &Path) -> Result<KvsEngineType> {
+    let mut contents = fs::read_to_string(config_file_path)?;
+    for _ in 0..CONFIG_WRITE_RETRIES {
+        if !contents.is_empty() {
+            break;
+        }
+        thread::sleep(CONFIG_WRITE_WAIT);
+        contents = fs::read_to_string(config_file_path)?;
+    }
+    serde_json::from_str(&contents).map_err(|e| {
+        KvsError::CorruptConfig(format!(
+            "{} can't be read ({}), fix or remove it, or pass --force-engine to overwrite it",
+            config_file_path.display(),
+            e
+        ))
+    })
+}
 
-    info!("configuration: {:?}", args);
+fn start_listening<P: ThreadPool>(
+    args: &KvServerArgs,
+    store: impl KvsEngine<String, String>,
+) -> Result<()> {
+    let listener = Listener::bind(&args.addr)?;
+    let config = ServerConfig {
+        allow_admin: args.allow_admin,
+        read_timeout: (args.read_timeout > 0).then(|| Duration::from_secs(args.read_timeout)),
+        auth_token: args.auth_token.clone(),
+        max_connections: args.max_connections,
+        ..ServerConfig::default()
+    };
+    server::serve_with_config(listener, store, P::new(args.threads)?, &SHUTDOWN, config)
+}
 
-    let path = Path::new("./db");
+fn run(args: KvServerArgs) -> Result<()> {
+    let path = args.data_dir.as_path();
 
-    let engine = parse_kv_config(path, args.engine)?;
+    let engine = parse_kv_config(
+        path,
+        args.engine.clone(),
+        args.force_engine,
+        args.migrate_engine,
+    )?;
 
     info!("final engine: {:?}", engine);
 
     match engine {
-        KvsEngineType::Kvs => start_listening(args.addr, kvs::engine::store::KvStore::open(&path.join("store"))?),
-        KvsEngineType::Sled => start_listening(
-            args.addr,
-            kvs::engine::sled::SledKvsEngine::new(&path.join("sled"))?,
-        ), // Need to implement Sled Engine
+        KvsEngineType::Kvs => {
+            let config = KvStoreConfig {
+                max_key_size: args.max_key_size,
+                max_value_size: args.max_value_size,
+                ..KvStoreConfig::default()
+            };
+            serve(
+                &args,
+                KvStore::open_with_config(&engine_dir(path, &engine), config)?,
+            )
+        }
+        KvsEngineType::Sled => serve(&args, SledKvsEngine::new(&engine_dir(path, &engine))?),
+    }
+}
+
+fn serve(args: &KvServerArgs, store: impl KvsEngine<String, String>) -> Result<()> {
+    match args.pool {
+        ThreadPoolType::Naive => start_listening::<NaiveThreadPool>(args, store),
+        ThreadPoolType::SharedQueue => start_listening::<SharedQueueThreadPool>(args, store),
+        ThreadPoolType::Rayon => start_listening::<RayonThreadPool>(args, store),
+    }
+}
+
+fn main() {
+    let args = KvServerArgs::parse();
+
+    stderrlog::new()
+        .module(module_path!())
+        .module("kvs")
+        .verbosity(log_verbosity(args.verbose, args.quiet))
+        .init()
+        .unwrap();
+    warn!("version: {}", VERSION);
+    info!("configuration: {:?}", args);
+    install_signal_handlers();
+
+    if let Err(e) = run(args) {
+        error!("{}", e);
+        process::exit(1);
     }
 }
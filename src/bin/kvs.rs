@@ -0,0 +1,148 @@
+use clap::clap_derive::ArgEnum;
+use clap::{Parser, Subcommand};
+use kvs::engine::sled::SledKvsEngine;
+use kvs::engine::store::KvStore;
+use kvs::engine::{self, KvsEngine};
+use kvs::{KvsError, Result};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// How many pairs `migrate` copies between progress reports
+const MIGRATE_PROGRESS_INTERVAL: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, ArgEnum)]
+enum EngineType {
+    Kvs,
+    Sled,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// check every record in the log without changing anything, reporting the first bad one
+    Validate,
+    /// rewrite the log with only live values, reporting the bytes reclaimed
+    Compact,
+    /// copy every live pair from one engine's directory into a new one for another, leaving the
+    /// source as it was
+    Migrate {
+        #[clap(long, value_enum)]
+        from: EngineType,
+        #[clap(long, value_enum)]
+        to: EngineType,
+        /// the source engine's own directory, such as <data-dir>/store for kvs
+        #[clap(long, value_parser)]
+        src: PathBuf,
+        /// where to create the new engine, which must not already hold anything
+        #[clap(long, value_parser)]
+        dst: PathBuf,
+    },
+}
+
+/// Works on the kvs engine's store directly, for use while the server is stopped
+#[derive(Debug, Parser)] // requires `derive` feature
+#[clap(author, version, about, long_about = None)]
+struct KvArgs {
+    #[clap(subcommand)]
+    command: Command,
+
+    /// the data directory kvs-server was started with
+    #[clap(long, value_parser, env = "KVS_DATA_DIR", default_value = "./db")]
+    data_dir: PathBuf,
+}
+
+/// Copies `from` into a new `to` engine at `dst`, reporting progress on stderr
+fn migrate_into<F: KvsEngine<String, String>>(
+    from: &F,
+    to: EngineType,
+    dst: &Path,
+) -> Result<usize> {
+    let progress = |copied: usize| {
+        if copied.is_multiple_of(MIGRATE_PROGRESS_INTERVAL) {
+            eprintln!("copied {} pairs", copied);
+        }
+    };
+    match to {
+        EngineType::Kvs => engine::migrate(from, &KvStore::open(dst)?, progress),
+        EngineType::Sled => engine::migrate(from, &SledKvsEngine::new(dst)?, progress),
+    }
+}
+
+/// The source is opened read-only where the engine allows it. sled has no such mode, but is
+/// only read from
+fn migrate(from: EngineType, to: EngineType, src: &Path, dst: &Path) -> Result<usize> {
+    if !src.exists() {
+        return Err(KvsError::IOError(format!(
+            "{} does not exist",
+            src.display()
+        )));
+    }
+    if dst.exists() && dst.read_dir()?.next().is_some() {
+        return Err(KvsError::IOError(format!(
+            "{} already holds data",
+            dst.display()
+        )));
+    }
+    match from {
+        EngineType::Kvs => migrate_into(&KvStore::open_read_only(src)?, to, dst),
+        EngineType::Sled => migrate_into(&SledKvsEngine::new(src)?, to, dst),
+    }
+}
+
+/// Returns whether the store checked out
+fn run(args: KvArgs) -> Result<bool> {
+    // Where kvs-server keeps the kvs engine's store
+    let store_path = args.data_dir.join("store");
+    let store_path = store_path.as_path();
+    match args.command {
+        Command::Validate => {
+            let report = KvStore::<String, String>::validate(store_path)?;
+            match report.error {
+                None => {
+                    println!(
+                        "ok: {} records in {} log files",
+                        report.records, report.files
+                    );
+                    Ok(true)
+                }
+                Some((path, e)) => {
+                    println!("{}: {}", path.display(), e);
+                    Ok(false)
+                }
+            }
+        }
+        Command::Compact => {
+            // Opening would otherwise create an empty store where there was none
+            if !store_path.exists() {
+                return Err(KvsError::FileListEmpty);
+            }
+            let store = KvStore::<String, String>::open(store_path)?;
+            let before = store.stats()?.file_size;
+            store.compact()?;
+            let after = store.stats()?.file_size;
+            println!(
+                "reclaimed {} bytes ({} -> {})",
+                before.saturating_sub(after),
+                before,
+                after
+            );
+            Ok(true)
+        }
+        Command::Migrate { from, to, src, dst } => {
+            let copied = migrate(from, to, &src, &dst)?;
+            println!("migrated {} pairs from {:?} to {:?}", copied, from, to);
+            Ok(true)
+        }
+    }
+}
+
+fn main() {
+    let args = KvArgs::parse();
+    match run(args) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::engine::store::{Key, KvRecord, KvStoreStats, Value};
+use crate::engine::watch::WatchEvent;
+use crate::protocol::{read_frame, write_frame, KvRequest, KvResponse};
+use crate::transport::{ServerAddr, Stream};
+use crate::{KvsError, Result};
+use std::io;
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A connection to a kvs server that stays open across requests
+pub struct KvsClient<K = String, V = String> {
+    stream: Stream,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K: Key, V: Value> KvsClient<K, V> {
+    /// Connects with a 5 second timeout, retrying a refused connection 3 times. `addr` is a TCP
+    /// address or, as `unix:` followed by a path, a UNIX domain socket
+    pub fn connect(addr: impl Into<ServerAddr>) -> Result<KvsClient<K, V>> {
+        KvsClient::connect_with(addr, Duration::from_secs(5), 3)
+    }
+
+    /// Connects with a timeout, retrying refused connections with exponential backoff since the
+    /// server may still be starting up. `timeout` also applies to each read and write
+    pub fn connect_with(
+        addr: impl Into<ServerAddr>,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<KvsClient<K, V>> {
+        let addr = addr.into();
+        let mut backoff = Duration::from_millis(100);
+        let mut attempt = 0;
+        loop {
+            match Stream::connect(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(timeout))?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    return Ok(KvsClient {
+                        stream,
+                        phantom: PhantomData,
+                    });
+                }
+                // A UNIX socket that isn't there yet is a server still starting too
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+                    ) && attempt < retries =>
+                {
+                    attempt += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    return Err(KvsError::ConnectionFailed(format!(
+                        "{} after {} attempts: {}",
+                        addr,
+                        attempt + 1,
+                        e
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Sends the token a server started with one needs before it serves anything else on this
+    /// connection. Fails with `Unauthorized` if it is wrong
+    pub fn authenticate(&mut self, token: &str) -> Result<()> {
+        self.request(&KvRequest::Auth(token.to_owned()))?;
+        Ok(())
+    }
+
+    /// Checks the server is answering, returning the round trip time
+    pub fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        self.request(&KvRequest::Ping)?;
+        Ok(start.elapsed())
+    }
+
+    pub fn set(&mut self, key: K, value: V) -> Result<()> {
+        self.request(&KvRequest::Set((key, value)))?;
+        Ok(())
+    }
+
+    pub fn get(&mut self, key: K) -> Result<Option<V>> {
+        self.request(&KvRequest::Get(key))
+    }
+
+    pub fn remove(&mut self, key: K) -> Result<()> {
+        self.request(&KvRequest::Rm(key))?;
+        Ok(())
+    }
+
+    /// Gets `key`, falling back to `default` if it isn't set
+    pub fn get_or(&mut self, key: K, default: V) -> Result<V> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// Gets every key in one round trip, returning the values in the same order
+    pub fn get_many(&mut self, keys: Vec<K>) -> Result<Vec<Option<V>>> {
+        many_values(self.exchange(&KvRequest::GetMany(keys))?)
+    }
+
+    /// Every pair whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&mut self, prefix: K) -> Result<Vec<(K, V)>> {
+        prefix_pairs(self.exchange(&KvRequest::ScanPrefix(prefix))?)
+    }
+
+    /// Asks the server to compact its store, which it only does if it allows admin requests
+    pub fn compact(&mut self) -> Result<()> {
+        self.request(&KvRequest::Compact)?;
+        Ok(())
+    }
+
+    /// The server engine's counters
+    pub fn stats(&mut self) -> Result<KvStoreStats> {
+        engine_stats(self.exchange(&KvRequest::Stats)?)
+    }
+
+    /// Reads `key` along with its version, for use in `commit`
+    pub fn get_versioned(&mut self, key: K) -> Result<(Option<V>, u64)> {
+        versioned_value(self.exchange(&KvRequest::GetVersioned(key))?)
+    }
+
+    /// Applies `writes` only if every key in `reads` is still at the version it was read at,
+    /// returning whether it did
+    pub fn commit(&mut self, reads: Vec<(K, u64)>, writes: Vec<KvRecord<K, V>>) -> Result<bool> {
+        committed(self.request(&KvRequest::Commit { reads, writes }))
+    }
+
+    /// Subscribes to changes of every key starting with `prefix`. The connection is given over
+    /// to the subscription, and reads on it no longer time out since events may be far apart
+    pub fn watch(mut self, prefix: K) -> Result<Watch<K, V>> {
+        self.request(&KvRequest::Watch(prefix))?;
+        self.stream.set_read_timeout(None)?;
+        Ok(Watch {
+            stream: self.stream,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Sends `request` and waits for its response. Errors from the server come back as `Err`
+    pub fn request(&mut self, request: &KvRequest<K, V>) -> Result<Option<V>> {
+        self.exchange(request)?.value
+    }
+
+    /// Like `request`, but only fails if the connection does, leaving server errors in the
+    /// response
+    fn exchange(&mut self, request: &KvRequest<K, V>) -> Result<KvResponse<V, K>> {
+        write_frame(&mut self.stream, request)?;
+        self.read_response()
+    }
+
+    fn read_response(&mut self) -> Result<KvResponse<V, K>> {
+        read_frame(&mut self.stream)?.ok_or_else(|| {
+            KvsError::IOError("connection closed before a response arrived".to_owned())
+        })
+    }
+}
+
+/// The changes a `KvsClient::watch` subscribed to, in the order they happened. Ends when the
+/// server closes the connection
+pub struct Watch<K, V> {
+    stream: Stream,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K: Key, V: Value> Iterator for Watch<K, V> {
+    type Item = Result<WatchEvent<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_frame(&mut self.stream).transpose()
+    }
+}
+
+struct PoolState<K, V> {
+    idle: Vec<KvsClient<K, V>>,
+    // Idle connections plus the ones handed out to requests in flight
+    open: usize,
+}
+
+struct PoolShared<K, V> {
+    addr: ServerAddr,
+    timeout: Duration,
+    retries: u32,
+    max_size: usize,
+    state: Mutex<PoolState<K, V>>,
+    returned: Condvar,
+}
+
+/// Hands out connections to a kvs server from a pool of at most `max_size`, opening them as
+/// needed. Once the pool is full, requests wait for a connection to come back. Cloned handles
+/// share the same pool
+pub struct KvsClientPool<K = String, V = String> {
+    shared: Arc<PoolShared<K, V>>,
+}
+
+impl<K, V> Clone for KvsClientPool<K, V> {
+    fn clone(&self) -> Self {
+        KvsClientPool {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<K: Key, V: Value> KvsClientPool<K, V> {
+    /// Uses the same timeout and retries as `KvsClient::connect`
+    pub fn new(addr: impl Into<ServerAddr>, max_size: usize) -> Result<KvsClientPool<K, V>> {
+        KvsClientPool::with_options(addr, max_size, Duration::from_secs(5), 3)
+    }
+
+    pub fn with_options(
+        addr: impl Into<ServerAddr>,
+        max_size: usize,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<KvsClientPool<K, V>> {
+        if max_size == 0 {
+            return Err(KvsError::ConnectionFailed(
+                "a pool needs room for at least one connection".to_owned(),
+            ));
+        }
+        Ok(KvsClientPool {
+            shared: Arc::new(PoolShared {
+                addr: addr.into(),
+                timeout,
+                retries,
+                max_size,
+                state: Mutex::new(PoolState {
+                    idle: Vec::new(),
+                    open: 0,
+                }),
+                returned: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Checks the server is answering over a pooled connection, returning the round trip time
+    pub fn ping(&self) -> Result<Duration> {
+        let start = Instant::now();
+        self.request(&KvRequest::Ping)?;
+        Ok(start.elapsed())
+    }
+
+    pub fn set(&self, key: K, value: V) -> Result<()> {
+        self.request(&KvRequest::Set((key, value)))?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: K) -> Result<Option<V>> {
+        self.request(&KvRequest::Get(key))
+    }
+
+    pub fn remove(&self, key: K) -> Result<()> {
+        self.request(&KvRequest::Rm(key))?;
+        Ok(())
+    }
+
+    /// Gets `key`, falling back to `default` if it isn't set
+    pub fn get_or(&self, key: K, default: V) -> Result<V> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// Gets every key in one round trip, returning the values in the same order
+    pub fn get_many(&self, keys: Vec<K>) -> Result<Vec<Option<V>>> {
+        many_values(self.exchange(&KvRequest::GetMany(keys))?)
+    }
+
+    /// Every pair whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, prefix: K) -> Result<Vec<(K, V)>> {
+        prefix_pairs(self.exchange(&KvRequest::ScanPrefix(prefix))?)
+    }
+
+    /// Asks the server to compact its store, which it only does if it allows admin requests
+    pub fn compact(&self) -> Result<()> {
+        self.request(&KvRequest::Compact)?;
+        Ok(())
+    }
+
+    /// The server engine's counters
+    pub fn stats(&self) -> Result<KvStoreStats> {
+        engine_stats(self.exchange(&KvRequest::Stats)?)
+    }
+
+    /// Reads `key` along with its version, for use in `commit`
+    pub fn get_versioned(&self, key: K) -> Result<(Option<V>, u64)> {
+        versioned_value(self.exchange(&KvRequest::GetVersioned(key))?)
+    }
+
+    /// Applies `writes` only if every key in `reads` is still at the version it was read at,
+    /// returning whether it did
+    pub fn commit(&self, reads: Vec<(K, u64)>, writes: Vec<KvRecord<K, V>>) -> Result<bool> {
+        committed(self.request(&KvRequest::Commit { reads, writes }))
+    }
+
+    /// Sends `request` over a pooled connection. Errors from the server come back as `Err`
+    pub fn request(&self, request: &KvRequest<K, V>) -> Result<Option<V>> {
+        self.exchange(request)?.value
+    }
+
+    /// A connection that fails is dropped from the pool. If it had been sitting idle the server
+    /// may have closed it in the meantime, so the request is tried once more on a fresh one,
+    /// as long as that can't apply it twice: either it never made it out, or it only reads
+    fn exchange(&self, request: &KvRequest<K, V>) -> Result<KvResponse<V, K>> {
+        let (mut client, reused) = self.checkout()?;
+        let written = write_frame(&mut client.stream, request);
+        let retry = reused && (written.is_err() || request.is_read_only());
+        match written.and_then(|()| client.read_response()) {
+            Ok(response) => {
+                self.checkin(Some(client));
+                Ok(response)
+            }
+            Err(e) if !retry => {
+                self.checkin(None);
+                Err(e)
+            }
+            Err(_) => {
+                // The new connection takes over the dead one's slot
+                let mut client = self.open()?;
+                let response = client.exchange(request);
+                self.checkin(response.is_ok().then_some(client));
+                response
+            }
+        }
+    }
+
+    /// Number of connections the pool has open, whether idle or in use
+    pub fn size(&self) -> usize {
+        self.shared
+            .state
+            .lock()
+            .map(|state| state.open)
+            .unwrap_or(0)
+    }
+
+    /// Takes an idle connection, or opens a new one if there is room, returning whether it was
+    /// reused
+    fn checkout(&self) -> Result<(KvsClient<K, V>, bool)> {
+        let mut state = self.shared.state.lock()?;
+        loop {
+            if let Some(client) = state.idle.pop() {
+                return Ok((client, true));
+            }
+            if state.open < self.shared.max_size {
+                state.open += 1;
+                drop(state);
+                return Ok((self.open()?, false));
+            }
+            state = self.shared.returned.wait(state)?;
+        }
+    }
+
+    /// Connects for a slot already counted in `open`, giving the slot back if that fails
+    fn open(&self) -> Result<KvsClient<K, V>> {
+        let connected = KvsClient::connect_with(
+            self.shared.addr.clone(),
+            self.shared.timeout,
+            self.shared.retries,
+        );
+        if connected.is_err() {
+            self.checkin(None);
+        }
+        connected
+    }
+
+    /// Returns a connection to the pool, or with `None` gives up its slot
+    fn checkin(&self, client: Option<KvsClient<K, V>>) {
+        // A poisoned lock only means another thread panicked mid-update of plain counters
+        let mut state = self
+            .shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match client {
+            Some(client) => state.idle.push(client),
+            None => state.open -= 1,
+        }
+        drop(state);
+        self.shared.returned.notify_one();
+    }
+}
+
+fn many_values<V, K>(response: KvResponse<V, K>) -> Result<Vec<Option<V>>> {
+    response.value?;
+    response
+        .values
+        .ok_or_else(|| KvsError::SerializationError("response is missing its values".to_owned()))
+}
+
+fn prefix_pairs<V, K>(response: KvResponse<V, K>) -> Result<Vec<(K, V)>> {
+    response.value?;
+    response
+        .pairs
+        .ok_or_else(|| KvsError::SerializationError("response is missing its pairs".to_owned()))
+}
+
+fn engine_stats<V, K>(response: KvResponse<V, K>) -> Result<KvStoreStats> {
+    response.value?;
+    response
+        .stats
+        .ok_or_else(|| KvsError::SerializationError("response is missing its stats".to_owned()))
+}
+
+fn versioned_value<V, K>(response: KvResponse<V, K>) -> Result<(Option<V>, u64)> {
+    let value = response.value?;
+    let version = response.version.ok_or_else(|| {
+        KvsError::SerializationError("response is missing its version".to_owned())
+    })?;
+    Ok((value, version))
+}
+
+fn committed<V>(response: Result<Option<V>>) -> Result<bool> {
+    match response {
+        Ok(_) => Ok(true),
+        Err(KvsError::Conflict) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{KvsError, Result};
+
+/// Compression applied to large records before they are written to the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionKind {
+    /// A small LZ77 style compressor. It only finds repeats through a single-entry hash table,
+    /// so it trades ratio for speed, but repetitive text and JSON shrink dramatically
+    Lz,
+}
+
+impl CompressionKind {
+    /// Id stored in front of each record. 0 is reserved for uncompressed records
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            CompressionKind::Lz => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Result<Option<CompressionKind>> {
+        match id {
+            0 => Ok(None),
+            1 => Ok(Some(CompressionKind::Lz)),
+            _ => Err(KvsError::Compression(format!(
+                "unknown compression id {}",
+                id
+            ))),
+        }
+    }
+
+    pub fn compress(self, input: &[u8]) -> Vec<u8> {
+        match self {
+            CompressionKind::Lz => compress(input),
+        }
+    }
+
+    pub fn decompress(self, input: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionKind::Lz => decompress(input),
+        }
+    }
+}
+
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 16;
+const LITERALS: u8 = 0;
+const MATCH: u8 = 1;
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop {
+        let byte = *input
+            .get(*pos)
+            .ok_or_else(|| KvsError::Compression("truncated varint".to_owned()))?;
+        *pos += 1;
+        if shift >= usize::BITS {
+            return Err(KvsError::Compression("varint overflow".to_owned()));
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn write_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    if !literals.is_empty() {
+        out.push(LITERALS);
+        write_varint(out, literals.len());
+        out.extend_from_slice(literals);
+    }
+}
+
+/// The output is the input length followed by a sequence of literal runs and back references
+/// of (distance, length) into the already decoded output
+fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    write_varint(&mut out, input.len());
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let slot = hash(&input[pos..]);
+        let candidate = table[slot];
+        table[slot] = pos;
+        if candidate != usize::MAX
+            && input[candidate..candidate + MIN_MATCH] == input[pos..pos + MIN_MATCH]
+        {
+            let mut len = MIN_MATCH;
+            while pos + len < input.len() && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+            write_literals(&mut out, &input[literal_start..pos]);
+            out.push(MATCH);
+            write_varint(&mut out, pos - candidate);
+            write_varint(&mut out, len);
+            pos += len;
+            literal_start = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    write_literals(&mut out, &input[literal_start..]);
+    out
+}
+
+fn decompress(input: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    let len = read_varint(input, &mut pos)?;
+    // Don't trust the stated length for the allocation, a corrupt header could ask for anything
+    let mut out = Vec::with_capacity(len.min(input.len() * 64));
+    while pos < input.len() {
+        let tag = input[pos];
+        pos += 1;
+        match tag {
+            LITERALS => {
+                let count = read_varint(input, &mut pos)?;
+                let literals = pos
+                    .checked_add(count)
+                    .and_then(|end| input.get(pos..end))
+                    .ok_or_else(|| KvsError::Compression("truncated literals".to_owned()))?;
+                out.extend_from_slice(literals);
+                pos += count;
+            }
+            MATCH => {
+                let distance = read_varint(input, &mut pos)?;
+                let count = read_varint(input, &mut pos)?;
+                if distance == 0 || distance > out.len() || count > len - out.len() {
+                    return Err(KvsError::Compression("invalid back reference".to_owned()));
+                }
+                // Copy byte by byte since a match may overlap the bytes it is producing
+                let start = out.len() - distance;
+                for i in 0..count {
+                    out.push(out[start + i]);
+                }
+            }
+            _ => return Err(KvsError::Compression(format!("unknown tag {}", tag))),
+        }
+        if out.len() > len {
+            return Err(KvsError::Compression(
+                "output longer than expected".to_owned(),
+            ));
+        }
+    }
+    if out.len() != len {
+        return Err(KvsError::Compression(
+            "output shorter than expected".to_owned(),
+        ));
+    }
+    Ok(out)
+}
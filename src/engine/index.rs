@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use dashmap::DashMap;
+
+use super::store::{Key, ValueData};
+
+/// Which structure a `KvStore` keeps its index in, chosen with `KvStoreConfig::index`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// A sharded hash map. Point lookups and writes from many threads barely contend, but a scan
+    /// has to visit every key and sort the ones in range
+    #[default]
+    Hash,
+    /// A B-tree behind one read-write lock. A scan only visits the keys in range and they come
+    /// out in order, but every write briefly blocks all lookups
+    Ordered,
+}
+
+/// Where each live key's latest record is
+pub(crate) enum Index<K> {
+    Hash(DashMap<K, ValueData>),
+    Ordered(RwLock<BTreeMap<K, ValueData>>),
+}
+
+impl<K: Key> Index<K> {
+    /// Room for `capacity` keys up front. A B-tree has nothing to presize, so it ignores it
+    pub(crate) fn with_capacity(kind: IndexKind, capacity: usize) -> Index<K> {
+        match kind {
+            IndexKind::Hash => Index::Hash(DashMap::with_capacity(capacity)),
+            IndexKind::Ordered => Index::Ordered(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    /// A copy of the entry, so no shard or tree lock is still held by the time the caller
+    /// reads the value from disk
+    pub(crate) fn get(&self, key: &K) -> Option<ValueData> {
+        match self {
+            Index::Hash(map) => map.get(key).map(|entry| *entry.value()),
+            Index::Ordered(tree) => read(tree).get(key).copied(),
+        }
+    }
+
+    pub(crate) fn insert(&self, key: K, value_data: ValueData) -> Option<ValueData> {
+        match self {
+            Index::Hash(map) => map.insert(key, value_data),
+            Index::Ordered(tree) => write(tree).insert(key, value_data),
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &K) -> Option<ValueData> {
+        match self {
+            Index::Hash(map) => map.remove(key).map(|(_, value_data)| value_data),
+            Index::Ordered(tree) => write(tree).remove(key),
+        }
+    }
+
+    /// The entries with keys between `start` and `end` that pass `filter`. They are in key order
+    /// only if `is_ordered`
+    pub(crate) fn entries(
+        &self,
+        start: Bound<&K>,
+        end: Bound<&K>,
+        filter: impl Fn(&ValueData) -> bool,
+    ) -> Vec<(K, ValueData)> {
+        match self {
+            Index::Hash(map) => map
+                .iter()
+                .filter(|entry| (start, end).contains(entry.key()) && filter(entry.value()))
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+            Index::Ordered(tree) => read(tree)
+                .range::<K, _>((start, end))
+                .filter(|(_, value_data)| filter(value_data))
+                .map(|(key, value_data)| (key.clone(), *value_data))
+                .collect(),
+        }
+    }
+
+    /// Folds over every entry, in key order only if `is_ordered`
+    pub(crate) fn fold<T>(&self, init: T, mut f: impl FnMut(T, &K, &ValueData) -> T) -> T {
+        match self {
+            Index::Hash(map) => map
+                .iter()
+                .fold(init, |acc, entry| f(acc, entry.key(), entry.value())),
+            Index::Ordered(tree) => read(tree)
+                .iter()
+                .fold(init, |acc, (key, value_data)| f(acc, key, value_data)),
+        }
+    }
+
+    pub(crate) fn retain(&self, mut f: impl FnMut(&K, &mut ValueData) -> bool) {
+        match self {
+            Index::Hash(map) => map.retain(|key, value_data| f(key, value_data)),
+            Index::Ordered(tree) => write(tree).retain(|key, value_data| f(key, value_data)),
+        }
+    }
+
+    pub(crate) fn is_ordered(&self) -> bool {
+        matches!(self, Index::Ordered(_))
+    }
+}
+
+// The tree is only ever left mid-update by a panic inside BTreeMap itself, so a poisoned lock is
+// still safe to use
+fn read<K>(tree: &RwLock<BTreeMap<K, ValueData>>) -> RwLockReadGuard<'_, BTreeMap<K, ValueData>> {
+    tree.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write<K>(tree: &RwLock<BTreeMap<K, ValueData>>) -> RwLockWriteGuard<'_, BTreeMap<K, ValueData>> {
+    tree.write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,96 @@
+use std::ops::{Bound, RangeBounds};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+
+use super::super::KvsError;
+use super::store::{Key, KvStoreStats, Value};
+use super::watch::{WatchEvent, Watchers};
+use super::{KvsEngine, Result};
+
+/// Keeps everything in memory and never touches the disk, so nothing survives the last handle
+/// being dropped. Clones share the same map
+pub struct InMemoryKvsEngine<K = String, V = String> {
+    map: Arc<DashMap<K, V>>,
+    watchers: Arc<Watchers<K, V>>,
+}
+
+impl<K: Key, V: Value> InMemoryKvsEngine<K, V> {
+    pub fn new() -> InMemoryKvsEngine<K, V> {
+        InMemoryKvsEngine {
+            map: Arc::new(DashMap::new()),
+            watchers: Arc::new(Watchers::new()),
+        }
+    }
+}
+
+impl<K: Key, V: Value> Default for InMemoryKvsEngine<K, V> {
+    fn default() -> Self {
+        InMemoryKvsEngine::new()
+    }
+}
+
+impl<K, V> Clone for InMemoryKvsEngine<K, V> {
+    fn clone(&self) -> Self {
+        InMemoryKvsEngine {
+            map: Arc::clone(&self.map),
+            watchers: Arc::clone(&self.watchers),
+        }
+    }
+}
+
+impl<K, V> KvsEngine<K, V> for InMemoryKvsEngine<K, V>
+where
+    K: Key + Sync,
+    V: Value + Sync,
+{
+    fn set(&self, key: K, value: V) -> Result<()> {
+        // Notifying before the entry's shard is unlocked keeps events for a key in the order
+        // its writes landed
+        let entry = self.map.entry(key).insert(value);
+        self.watchers.notify(entry.key(), Some(entry.value()));
+        Ok(())
+    }
+    fn get(&self, key: K) -> Result<Option<V>> {
+        Ok(self.map.get(&key).map(|value| value.clone()))
+    }
+    fn remove(&self, key: K) -> Result<()> {
+        match self.map.entry(key) {
+            Entry::Occupied(entry) => {
+                self.watchers.notify(entry.key(), None);
+                entry.remove();
+                Ok(())
+            }
+            Entry::Vacant(_) => Err(KvsError::NonExistantKey),
+        }
+    }
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<Vec<(K, V)>> {
+        let mut pairs: Vec<(K, V)> = self
+            .map
+            .iter()
+            .filter(|entry| (start.as_ref(), end.as_ref()).contains(entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+    /// There is no log to reclaim, removed values are freed straight away
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Nothing is ever written anywhere durable
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    fn stats(&self) -> Result<KvStoreStats> {
+        Ok(KvStoreStats {
+            keys: self.map.len(),
+            ..KvStoreStats::default()
+        })
+    }
+    fn watch(&self, prefix: K) -> Result<Receiver<WatchEvent<K, V>>> {
+        Ok(self.watchers.watch(prefix))
+    }
+}
@@ -1,10 +1,89 @@
-use crate::Result;
+use std::ops::Bound;
+
+use crate::{KvsError, Result};
+use std::sync::mpsc::Receiver;
+use store::{KvRecord, KvStoreStats};
+use watch::WatchEvent;
+
+/// A stream of pairs from `KvsEngine::iter`
+pub type Pairs<K, V> = Box<dyn Iterator<Item = Result<(K, V)>>>;
 
 pub trait KvsEngine<K, V>: Clone + Send + 'static {
     fn set(&self, key: K, value: V) -> Result<()>;
     fn get(&self, key: K) -> Result<Option<V>>;
     fn remove(&self, key: K) -> Result<()>;
+    /// Returns every live pair whose key falls between `start` and `end`, in ascending key
+    /// order. The result is a snapshot, so writes racing with the scan may or may not be seen
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<Vec<(K, V)>>;
+    /// Reclaims the space taken by overwritten and removed values
+    fn compact(&self) -> Result<()>;
+    /// Makes every write so far durable, whatever the engine's own sync settings
+    fn flush(&self) -> Result<()>;
+    /// Counters describing the engine. Ones it doesn't track are left at zero
+    fn stats(&self) -> Result<KvStoreStats>;
+    /// Delivers an event for every later set or removal of a key starting with `prefix`, until
+    /// the receiver is dropped
+    fn watch(&self, prefix: K) -> Result<Receiver<WatchEvent<K, V>>>;
+    /// The value of each of `keys`, in the same order. By default they are read one at a time
+    fn get_many(&self, keys: Vec<K>) -> Result<Vec<Option<V>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+    /// Sets every pair in order, so a key given twice ends up with its last value. By default
+    /// they are set one at a time, and a failure part way leaves the earlier ones set
+    fn set_many(&self, pairs: Vec<(K, V)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+    /// Every live pair in no particular order, read as the iterator advances where the engine
+    /// can so they needn't all be in memory at once. Writes made meanwhile may or may not be seen
+    fn iter(&self) -> Result<Pairs<K, V>>
+    where
+        K: 'static,
+        V: 'static,
+    {
+        let pairs = self.scan(Bound::Unbounded, Bound::Unbounded)?;
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+    /// Reads `key` along with a version that changes with every write to it, for `commit`. An
+    /// absent key is at version 0
+    fn get_versioned(&self, _key: K) -> Result<(Option<V>, u64)> {
+        Err(KvsError::Unsupported)
+    }
+    /// Applies `writes` as one batch only if every key in `reads` is still at the version it was
+    /// read at, returning whether it did
+    fn commit(&self, _reads: Vec<(K, u64)>, _writes: Vec<KvRecord<K, V>>) -> Result<bool> {
+        Err(KvsError::Unsupported)
+    }
+}
+
+/// Copies every live pair in `from` into `to` and flushes it, returning how many were copied.
+/// The pairs are streamed through `iter`, calling `progress` with the count so far after each
+/// one. Writes made to `from` meanwhile may be missed
+pub fn migrate<K, V, F, T>(from: &F, to: &T, mut progress: impl FnMut(usize)) -> Result<usize>
+where
+    K: 'static,
+    V: 'static,
+    F: KvsEngine<K, V>,
+    T: KvsEngine<K, V>,
+{
+    let mut copied = 0;
+    for pair in from.iter()? {
+        let (key, value) = pair?;
+        to.set(key, value)?;
+        copied += 1;
+        progress(copied);
+    }
+    to.flush()?;
+    Ok(copied)
 }
 
+pub mod compression;
+pub mod index;
+pub mod memory;
+pub mod positional;
 pub mod sled;
+pub mod storage;
 pub mod store;
+pub mod watch;
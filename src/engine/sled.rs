@@ -1,21 +1,75 @@
+use std::ops::{Bound, RangeBounds};
 use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Once};
+use std::thread;
 
-use sled::Db;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::{Batch, Db, Event};
 
 use super::super::KvsError;
-use super::{KvsEngine, Result};
+use super::store::{Key, KvRecord, KvStoreStats, Value};
+use super::watch::{WatchEvent, Watchers};
+use super::{KvsEngine, Pairs, Result};
 
-#[derive(Clone)]
-pub struct SledKvsEngine {
+/// Stores keys and values in sled, MessagePack-encoded so any `Key` and `Value` types work
+pub struct SledKvsEngine<K = String, V = String> {
     db: Db,
+    watchers: Arc<Watchers<K, V>>,
+    /// Starts the one thread that feeds `watchers`, on the first `watch`
+    watching: Arc<Once>,
 }
 
-impl SledKvsEngine {
-    pub fn new(db_dir: &Path) -> Result<SledKvsEngine> {
+impl<K, V> Clone for SledKvsEngine<K, V> {
+    fn clone(&self) -> Self {
+        SledKvsEngine {
+            db: self.db.clone(),
+            watchers: Arc::clone(&self.watchers),
+            watching: Arc::clone(&self.watching),
+        }
+    }
+}
+
+impl<K: Key, V: Value> SledKvsEngine<K, V> {
+    pub fn new(db_dir: &Path) -> Result<SledKvsEngine<K, V>> {
         Ok(SledKvsEngine {
             db: sled::open(db_dir)?,
+            watchers: Arc::new(Watchers::new()),
+            watching: Arc::new(Once::new()),
         })
     }
+
+    /// Bytes sled is currently using on disk
+    pub fn size_on_disk(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+}
+
+impl<K: Key, V: Value> SledKvsEngine<K, V> {
+    /// Applies all of `ops` in order as one `sled::Batch` followed by a single flush. Everything
+    /// is encoded before the batch is applied, so either every record lands or none do.
+    ///
+    /// As with `KvStore::write_batch`, an `Rm` of a key that doesn't exist is not an error.
+    /// sled keeps no expiry, so a set with one is `Unsupported`
+    pub fn write_batch(&self, ops: Vec<KvRecord<K, V>>) -> Result<()> {
+        let mut batch = Batch::default();
+        for op in ops {
+            match op {
+                KvRecord::Set((key, value)) => batch.insert(encode(&key)?, encode(&value)?),
+                KvRecord::SetWithMeta((key, value, meta)) if meta.expires_at.is_none() => {
+                    batch.insert(encode(&key)?, encode(&value)?)
+                }
+                KvRecord::Rm(key) => batch.remove(encode(&key)?),
+                KvRecord::SetExpiring(_) | KvRecord::SetWithMeta(_) => {
+                    return Err(KvsError::Unsupported)
+                }
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
 }
 
 impl From<sled::Error> for KvsError {
@@ -24,20 +78,35 @@ impl From<sled::Error> for KvsError {
     }
 }
 
-impl KvsEngine<String, String> for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    Ok(rmp_serde::to_vec(value)?)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    Ok(rmp_serde::from_slice(bytes)?)
+}
+
+fn decode_event<K: Key, V: Value>(event: Event) -> Result<WatchEvent<K, V>> {
+    Ok(match event {
+        Event::Insert { key, value } => WatchEvent::Set(decode(&key)?, decode(&value)?),
+        Event::Remove { key } => WatchEvent::Removed(decode(&key)?),
+    })
+}
+
+impl<K: Key, V: Value> KvsEngine<K, V> for SledKvsEngine<K, V> {
+    fn set(&self, key: K, value: V) -> Result<()> {
+        self.db.insert(encode(&key)?, encode(&value)?)?;
         self.db.flush()?;
         Ok(())
     }
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .db
-            .get(key.as_bytes())
-            .map(|op| op.map(|v| String::from_utf8(v.to_vec()).unwrap()))?)
+    fn get(&self, key: K) -> Result<Option<V>> {
+        match self.db.get(encode(&key)?)? {
+            Some(value) => Ok(Some(decode(&value)?)),
+            None => Ok(None),
+        }
     }
-    fn remove(&self, key: String) -> Result<()> {
-        match self.db.remove(key.as_bytes())? {
+    fn remove(&self, key: K) -> Result<()> {
+        match self.db.remove(encode(&key)?)? {
             Some(_v) => {
                 self.db.flush()?;
                 Ok(())
@@ -45,8 +114,84 @@ impl KvsEngine<String, String> for SledKvsEngine {
             None => Err(KvsError::NonExistantKey),
         }
     }
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<Vec<(K, V)>> {
+        // The encoded bytes don't sort the way the keys do, so sled's own range scan can't be
+        // used. Every pair is decoded and filtered instead
+        let mut pairs = Vec::new();
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            let key: K = decode(&key)?;
+            if (start.as_ref(), end.as_ref()).contains(&key) {
+                pairs.push((key, decode(&value)?));
+            }
+        }
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(pairs)
+    }
+    /// sled has no manual compaction, its segment cleaner reclaims space in the background once
+    /// segments are mostly dead. Rewriting every live pair moves it onto fresh segments, which
+    /// leaves the old ones empty for the cleaner to take back.
+    ///
+    /// Each rewrite is a compare-and-swap against the value that was read, so a key written
+    /// concurrently keeps its newer value.
+    fn compact(&self) -> Result<()> {
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            // A failed swap means someone else already rewrote the key, which is just as good
+            let _ = self
+                .db
+                .compare_and_swap(&key, Some(&value), Some(value.clone()))?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+    /// sled only reports its key count and size on disk
+    fn stats(&self) -> Result<KvStoreStats> {
+        Ok(KvStoreStats {
+            keys: self.db.len(),
+            file_size: self.db.size_on_disk()?,
+            ..KvStoreStats::default()
+        })
+    }
+    /// Keys are encoded with a length in front, so an encoded prefix isn't a byte prefix of the
+    /// keys it matches. The first watch starts a single thread that follows every change in
+    /// sled and hands it to the matching watchers, however many there are, until sled shuts
+    /// down. Dropped receivers are forgotten at the next matching write
+    fn watch(&self, prefix: K) -> Result<Receiver<WatchEvent<K, V>>> {
+        let receiver = self.watchers.watch(prefix);
+        self.watching.call_once(|| {
+            let subscriber = self.db.watch_prefix(Vec::new());
+            let watchers = Arc::clone(&self.watchers);
+            thread::spawn(move || {
+                for event in subscriber {
+                    match decode_event::<K, V>(event) {
+                        Ok(WatchEvent::Set(key, value)) => watchers.notify(&key, Some(&value)),
+                        Ok(WatchEvent::Removed(key)) => watchers.notify(&key, None),
+                        // Pairs written with other types can't be decoded, and can't match anyway
+                        Err(_) => {}
+                    }
+                }
+            });
+        });
+        Ok(receiver)
+    }
+    /// Walks sled's tree directly, decoding each pair as it is reached
+    fn iter(&self) -> Result<Pairs<K, V>> {
+        Ok(Box::new(self.db.iter().map(|kv| {
+            let (key, value) = kv?;
+            Ok((decode(&key)?, decode(&value)?))
+        })))
+    }
+    /// One `write_batch`, so the pairs land atomically with a single flush
+    fn set_many(&self, pairs: Vec<(K, V)>) -> Result<()> {
+        self.write_batch(pairs.into_iter().map(KvRecord::Set).collect())
+    }
 }
-impl Drop for SledKvsEngine {
+impl<K, V> Drop for SledKvsEngine<K, V> {
     fn drop(&mut self) {
         self.db
             .flush()
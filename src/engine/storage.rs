@@ -0,0 +1,458 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use memmap2::{Mmap, MmapOptions};
+
+use super::positional::read_exact_at;
+use crate::Result;
+
+/// One append-only log. Reads go through `read_at` so they never disturb the end being appended
+/// to, and can run alongside an append.
+///
+/// There is no `replace`, since a log is never rewritten in place. Compaction writes the live
+/// records to a new log from `LogBackend::create_staged`, makes it live with `publish` and then
+/// removes the old ones, so a crash part way through leaves either the old logs or the new one
+pub trait LogStorage: Send + Sync {
+    /// Adds `bytes` to the end of the log, returning the offset they start at
+    fn append(&self, bytes: &[u8]) -> io::Result<u64>;
+    /// Fills `buf` from the log starting at `offset`
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    fn len(&self) -> io::Result<u64>;
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    /// Cuts the log back to `len` bytes, throwing away a partly written record
+    fn truncate(&self, len: u64) -> io::Result<()>;
+    /// Makes everything appended so far durable
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// Where a store's logs live, each under its file id. `KvStore::open_with_storage` takes any
+/// implementation, so a store can be kept somewhere other than the local disk
+pub trait LogBackend: Send + Sync {
+    /// Ids of every log, oldest first
+    fn log_ids(&self) -> Result<Vec<u64>>;
+    /// Creates an empty log, failing if `file_id` is taken
+    fn create(&self, file_id: u64) -> Result<Arc<dyn LogStorage>>;
+    fn open(&self, file_id: u64) -> Result<Arc<dyn LogStorage>>;
+    /// Forgets a log. Handles already open keep working, so a snapshot taken before compaction
+    /// can still be read
+    fn remove(&self, file_id: u64) -> Result<()>;
+    /// Creates a log that stays out of `log_ids` until `publish`, so a crash while it is being
+    /// filled can't leave a half-written log for the next open to find. By default it is just
+    /// `create`, for backends with nothing to survive a crash
+    fn create_staged(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        self.create(file_id)
+    }
+    /// Makes a staged log a regular one in a single atomic step. Along with `create_staged` this
+    /// is how a store replaces its logs
+    fn publish(&self, _file_id: u64) -> Result<()> {
+        Ok(())
+    }
+    /// Throws away a staged log that won't be published
+    fn discard_staged(&self, file_id: u64) -> Result<()> {
+        self.remove(file_id)
+    }
+    /// Keeps `bytes` as the index snapshot, replacing any earlier one. A backend can ignore
+    /// this, in which case every open replays all of the logs
+    fn save_snapshot(&self, _bytes: &[u8]) -> Result<()> {
+        Ok(())
+    }
+    /// The bytes last given to `save_snapshot`, if there are any
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+/// Logs kept as `<id>.kvs` files in a directory, which has to exist already. Ids are padded to
+/// six digits so the files list in order
+pub struct FileBackend {
+    dir: PathBuf,
+    read_only: bool,
+    mmap_reads: bool,
+}
+
+impl FileBackend {
+    pub fn new(dir: PathBuf) -> FileBackend {
+        FileBackend {
+            dir,
+            read_only: false,
+            mmap_reads: false,
+        }
+    }
+
+    /// Opens logs without write access, so nothing can be appended through them
+    pub(crate) fn read_only(dir: PathBuf) -> FileBackend {
+        FileBackend {
+            read_only: true,
+            ..FileBackend::new(dir)
+        }
+    }
+
+    /// Serves reads by copying out of a memory map of each log rather than with a `pread`.
+    ///
+    /// A mapped file must not be changed underneath the map by anyone else. The store itself
+    /// only ever appends past what is mapped, drops the map before truncating, and removes
+    /// rather than rewrites old logs, which leaves existing maps valid. Another process writing
+    /// to or truncating a log while it is open can crash this one with `SIGBUS` or hand back
+    /// bytes that change as they are read.
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> FileBackend {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+}
+
+pub(crate) fn log_path(dir_path: &Path, file_id: u64) -> PathBuf {
+    dir_path.join(format!("{:06}.kvs", file_id))
+}
+
+/// Where `FileBackend` builds a staged log before renaming it to its `log_path`
+fn staged_log_path(dir_path: &Path, file_id: u64) -> PathBuf {
+    dir_path.join(format!("{:06}.kvs.tmp", file_id))
+}
+
+/// Removes staged logs left behind by a process that stopped before publishing them
+pub(crate) fn remove_staged_logs(dir_path: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir_path)? {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with(".kvs.tmp"))
+        {
+            log::warn!("Removing unfinished log {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Ids of the log files in `dir_path`, oldest first. Anything not named `<id>.kvs` is ignored
+pub(crate) fn log_file_ids(dir_path: &Path) -> Result<Vec<u64>> {
+    let mut file_ids = Vec::new();
+    for entry in fs::read_dir(dir_path)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "kvs") {
+            if let Some(file_id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                file_ids.push(file_id);
+            }
+        }
+    }
+    file_ids.sort_unstable();
+    Ok(file_ids)
+}
+
+impl LogBackend for FileBackend {
+    fn log_ids(&self) -> Result<Vec<u64>> {
+        log_file_ids(&self.dir)
+    }
+
+    fn create(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(log_path(&self.dir, file_id))?;
+        Ok(Arc::new(FileLog::new(file, self.mmap_reads)?))
+    }
+
+    fn open(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(!self.read_only)
+            .open(log_path(&self.dir, file_id))?;
+        Ok(Arc::new(FileLog::new(file, self.mmap_reads)?))
+    }
+
+    fn remove(&self, file_id: u64) -> Result<()> {
+        fs::remove_file(log_path(&self.dir, file_id))?;
+        Ok(())
+    }
+
+    fn create_staged(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(staged_log_path(&self.dir, file_id))?;
+        Ok(Arc::new(FileLog::new(file, self.mmap_reads)?))
+    }
+
+    fn publish(&self, file_id: u64) -> Result<()> {
+        fs::rename(
+            staged_log_path(&self.dir, file_id),
+            log_path(&self.dir, file_id),
+        )?;
+        // The rename is only durable once the directory entry is
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    fn discard_staged(&self, file_id: u64) -> Result<()> {
+        fs::remove_file(staged_log_path(&self.dir, file_id))?;
+        Ok(())
+    }
+
+    fn save_snapshot(&self, bytes: &[u8]) -> Result<()> {
+        // Written aside and renamed over the old one, so a crash leaves one or the other whole
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(bytes)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        Ok(())
+    }
+
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Where `FileBackend` keeps the index snapshot, next to the logs
+const SNAPSHOT_FILE: &str = "INDEX";
+
+// The file is opened for appending, so every write lands at the end wherever reads have been.
+// The length is tracked alongside so `append` can report where a write started
+struct FileLog {
+    file: File,
+    len: Mutex<u64>,
+    mmap_reads: bool,
+    // The file as it was when last mapped. A read past its end maps the file again, which only
+    // happens to the active log as it grows
+    map: RwLock<Option<Mmap>>,
+}
+
+impl FileLog {
+    fn new(file: File, mmap_reads: bool) -> Result<FileLog> {
+        let len = file.metadata()?.len();
+        Ok(FileLog {
+            file,
+            len: Mutex::new(len),
+            mmap_reads,
+            map: RwLock::new(None),
+        })
+    }
+
+    /// Copies `buf.len()` bytes at `offset` out of the map, remapping if it is too short.
+    /// Returns false if the file itself is too short
+    fn read_mapped(&self, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+        let range = offset as usize..offset as usize + buf.len();
+        if let Some(bytes) = self
+            .read_map()
+            .as_ref()
+            .and_then(|map| map.get(range.clone()))
+        {
+            buf.copy_from_slice(bytes);
+            return Ok(true);
+        }
+        let mut map = self.write_map();
+        if map.as_ref().is_none_or(|map| map.len() < range.end) {
+            let len = *self.lock_len();
+            // Mapping an empty file fails on some platforms, and there is nothing to read anyway
+            *map = match len {
+                0 => None,
+                // SAFETY: see `FileBackend::mmap_reads`. The store never changes bytes below
+                // `len`, and truncation takes this lock to drop the map first
+                _ => Some(unsafe { MmapOptions::new().len(len as usize).map(&self.file)? }),
+            };
+        }
+        match map.as_ref().and_then(|map| map.get(range)) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Nothing is left half done if a panic poisons the lock, so it is still usable
+    fn read_map(&self) -> RwLockReadGuard<'_, Option<Mmap>> {
+        self.map
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_map(&self) -> RwLockWriteGuard<'_, Option<Mmap>> {
+        self.map
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_len(&self) -> MutexGuard<'_, u64> {
+        // The length is only updated once the file has been, so a poisoned lock is still right
+        self.len
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl LogStorage for FileLog {
+    fn append(&self, bytes: &[u8]) -> io::Result<u64> {
+        let mut len = self.lock_len();
+        let offset = *len;
+        (&self.file).write_all(bytes)?;
+        *len += bytes.len() as u64;
+        Ok(offset)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.mmap_reads && self.read_mapped(offset, buf)? {
+            return Ok(());
+        }
+        read_exact_at(&self.file, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(*self.lock_len())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        // Reading a mapped page the file no longer reaches is a SIGBUS, so the map goes first
+        let mut map = self.write_map();
+        *map = None;
+        let mut current = self.lock_len();
+        self.file.set_len(len)?;
+        *current = len;
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Logs kept in memory, gone once the last handle to them is dropped
+#[derive(Default)]
+pub struct MemoryBackend {
+    logs: Mutex<BTreeMap<u64, Arc<MemoryLog>>>,
+}
+
+impl LogBackend for MemoryBackend {
+    fn log_ids(&self) -> Result<Vec<u64>> {
+        Ok(self.logs.lock()?.keys().copied().collect())
+    }
+
+    fn create(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        let log = Arc::new(MemoryLog::default());
+        let mut logs = self.logs.lock()?;
+        if logs.contains_key(&file_id) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+        logs.insert(file_id, Arc::clone(&log));
+        Ok(log)
+    }
+
+    fn open(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        match self.logs.lock()?.get(&file_id) {
+            Some(log) => Ok(Arc::clone(log) as Arc<dyn LogStorage>),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+
+    fn remove(&self, file_id: u64) -> Result<()> {
+        self.logs.lock()?.remove(&file_id);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemoryLog {
+    bytes: RwLock<Vec<u8>>,
+}
+
+// Nothing is left half done if a panic poisons the lock, so it is still usable
+impl MemoryLog {
+    fn read(&self) -> RwLockReadGuard<'_, Vec<u8>> {
+        self.bytes
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Vec<u8>> {
+        self.bytes
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl LogStorage for MemoryLog {
+    fn append(&self, bytes: &[u8]) -> io::Result<u64> {
+        let mut log = self.write();
+        let offset = log.len() as u64;
+        log.extend_from_slice(bytes);
+        Ok(offset)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let log = self.read();
+        let start = offset as usize;
+        let bytes = log
+            .get(start..start + buf.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.read().len() as u64)
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.write().truncate(len as usize);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Write` over the end of a log, for putting a `BufWriter` in front of it
+pub(crate) struct LogWriter(pub(crate) Arc<dyn LogStorage>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Read` from a log, for replaying it through a `BufReader`
+pub(crate) struct LogReader {
+    log: Arc<dyn LogStorage>,
+    position: u64,
+    len: u64,
+}
+
+impl LogReader {
+    /// Reads from `position` up to wherever the log ends now
+    pub(crate) fn new(log: Arc<dyn LogStorage>, position: u64) -> io::Result<LogReader> {
+        let len = log.len()?;
+        Ok(LogReader { log, position, len })
+    }
+}
+
+impl Read for LogReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min((self.len - self.position) as usize);
+        self.log.read_at(self.position, &mut buf[..n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -5,100 +7,883 @@ use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::hash::Hash;
-use std::io;
+use std::hash::Hasher;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::io::BufWriter;
-use std::io::Cursor;
+use std::io::Read;
 use std::io::Write;
 use std::marker::PhantomData;
-use std::os::unix::prelude::FileExt;
+use std::ops::Bound;
+use std::ops::Deref;
+use std::ops::DerefMut;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::MutexGuard;
 use std::sync::PoisonError;
 use std::sync::RwLock;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::Ordering;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
-use dashmap::DashMap;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use super::super::KvsError;
-use super::KvsEngine;
+use super::compression::CompressionKind;
+use super::index::{Index, IndexKind};
+use super::storage::{
+    log_file_ids, log_path, remove_staged_logs, FileBackend, LogBackend, LogReader, LogStorage,
+    LogWriter, MemoryBackend,
+};
+use super::watch::{WatchEvent, Watchers};
 use super::Result;
+use super::{KvsEngine, Pairs};
 pub trait Key:
-    Debug + Display + Clone + Eq + Hash + Serialize + for<'de> Deserialize<'de> + Send + 'static
+    Debug + Display + Clone + Eq + Ord + Hash + Serialize + for<'de> Deserialize<'de> + Send + 'static
 {
+    /// Whether `prefix` is a prefix of this key, as used by `ScanPrefix`. Keys that extend a
+    /// prefix must sort right after it. Without a natural notion of prefix a key only has
+    /// itself as one
+    fn has_prefix(&self, prefix: &Self) -> bool {
+        self == prefix
+    }
+
+    /// The bound just past every key that has this one as a prefix, where a prefix scan can
+    /// stop. Left as `Unbounded`, a scan reads on until the first key without the prefix
+    fn prefix_end(&self) -> Bound<Self> {
+        Bound::Unbounded
+    }
 }
 pub trait Value:
     Debug + Display + Clone + Serialize + for<'de> Deserialize<'de> + Send + 'static
 {
 }
 
-impl Key for String {}
+impl Key for String {
+    fn has_prefix(&self, prefix: &Self) -> bool {
+        self.starts_with(prefix.as_str())
+    }
+
+    /// The prefix with its last char bumped to the next one, dropping any trailing chars that
+    /// are already the largest there is. A prefix of nothing but those has no end
+    fn prefix_end(&self) -> Bound<Self> {
+        let mut end = self.clone();
+        while let Some(last) = end.pop() {
+            let next = match last as u32 + 1 {
+                // Surrogates aren't chars, so the one after them is next
+                0xD800 => Some('\u{E000}'),
+                next => char::from_u32(next),
+            };
+            if let Some(next) = next {
+                end.push(next);
+                return Bound::Excluded(end);
+            }
+        }
+        Bound::Unbounded
+    }
+}
 impl Value for String {}
+impl Key for u64 {
+    fn prefix_end(&self) -> Bound<Self> {
+        Bound::Included(*self)
+    }
+}
+impl Value for u64 {}
 
+/// A single entry in the log, also used to describe the operations in a `write_batch`
 #[derive(Serialize, Deserialize, Debug)]
-enum KvRecord<K, V> {
+pub enum KvRecord<K, V> {
     Set((K, V)),
     Rm(K),
+    /// A set that stops counting once the wall clock passes the given milliseconds since the
+    /// UNIX epoch
+    SetExpiring((K, V, u64)),
+    /// A set along with when it was written. The store writes every set this way, converting
+    /// the other two as they go in, so those only turn up in logs from before timestamps
+    SetWithMeta((K, V, RecordMeta)),
 }
 
-#[derive(Debug)]
-struct ValueData {
+/// The version 2 layout of a record on disk, which `decode_record` turns back into a `KvRecord`.
+/// Fields added later go at the end marked `#[serde(default)]`, so records written before them
+/// still decode
+#[derive(Serialize, Deserialize)]
+struct StoredRecord<K, V> {
+    key: K,
+    /// `None` for a removal
+    value: Option<V>,
+    written_at: u64,
+    expires_at: Option<u64>,
+}
+
+impl<K, V> StoredRecord<K, V> {
+    fn into_record(self) -> KvRecord<K, V> {
+        match self.value {
+            Some(value) => KvRecord::SetWithMeta((
+                self.key,
+                value,
+                RecordMeta {
+                    written_at: self.written_at,
+                    expires_at: self.expires_at,
+                },
+            )),
+            None => KvRecord::Rm(self.key),
+        }
+    }
+}
+
+/// What the store notes about a set besides the pair itself
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordMeta {
+    /// Wall clock time of the write, in milliseconds since the UNIX epoch
+    pub written_at: u64,
+    /// As for `SetExpiring`
+    pub expires_at: Option<u64>,
+}
+
+impl<K, V> KvRecord<K, V> {
+    fn key(&self) -> &K {
+        match self {
+            KvRecord::Set(kv) => &kv.0,
+            KvRecord::Rm(key) => key,
+            KvRecord::SetExpiring(kve) => &kve.0,
+            KvRecord::SetWithMeta(kvm) => &kvm.0,
+        }
+    }
+
+    /// `None` for a removal
+    fn value(&self) -> Option<&V> {
+        match self {
+            KvRecord::Set(kv) => Some(&kv.1),
+            KvRecord::Rm(_) => None,
+            KvRecord::SetExpiring(kve) => Some(&kve.1),
+            KvRecord::SetWithMeta(kvm) => Some(&kvm.1),
+        }
+    }
+
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            KvRecord::SetExpiring(kve) => Some(kve.2),
+            KvRecord::SetWithMeta(kvm) => kvm.2.expires_at,
+            _ => None,
+        }
+    }
+
+    /// Zero for records from before timestamps were kept
+    fn written_at(&self) -> u64 {
+        match self {
+            KvRecord::SetWithMeta(kvm) => kvm.2.written_at,
+            _ => 0,
+        }
+    }
+
+    fn stored(&self) -> StoredRecord<&K, &V> {
+        StoredRecord {
+            key: self.key(),
+            value: self.value(),
+            written_at: self.written_at(),
+            expires_at: self.expires_at(),
+        }
+    }
+
+    /// The value this record leaves its key with at `now`, if any
+    fn into_live_value(self, now: u64) -> Option<V> {
+        match self {
+            KvRecord::Set(kv) => Some(kv.1),
+            KvRecord::Rm(_) => None,
+            KvRecord::SetExpiring(kve) => (kve.2 > now).then_some(kve.1),
+            KvRecord::SetWithMeta(kvm) => match kvm.2.expires_at {
+                Some(expires_at) if expires_at <= now => None,
+                _ => Some(kvm.1),
+            },
+        }
+    }
+
+    /// Stamps a set with the time it is being written
+    fn stamped(self, now: u64) -> KvRecord<K, V> {
+        let meta = |expires_at| RecordMeta {
+            written_at: now,
+            expires_at,
+        };
+        match self {
+            KvRecord::Set((key, value)) => KvRecord::SetWithMeta((key, value, meta(None))),
+            KvRecord::SetExpiring((key, value, expires_at)) => {
+                KvRecord::SetWithMeta((key, value, meta(Some(expires_at))))
+            }
+            record => record,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ValueData {
+    file_id: u64,
     size: usize,
     offset: u64,
+    expires_at: Option<u64>,
+    // Bumped by every write to the key, for `commit` to check against. Only meaningful for as
+    // long as the store is open
+    version: u64,
+}
+
+impl ValueData {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= now)
+            .unwrap_or(false)
+    }
+}
+
+fn stamp_all<K, V>(records: Vec<KvRecord<K, V>>) -> Vec<KvRecord<K, V>> {
+    let now = now_millis();
+    records
+        .into_iter()
+        .map(|record| record.stamped(now))
+        .collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64
 }
 
 struct BufWriterWithPosition<T: Write> {
     buf_writer: BufWriter<T>,
-    path: PathBuf,
+    file_id: u64,
     position: u64,
+    writes_since_sync: usize,
+    last_sync: Instant,
+    last_index_save: Instant,
+}
+
+impl BufWriterWithPosition<LogWriter> {
+    /// Writes out the buffer and makes everything in the log durable
+    fn sync(&mut self) -> Result<()> {
+        self.buf_writer.flush()?;
+        self.buf_writer.get_ref().0.sync()?;
+        Ok(())
+    }
+}
+
+// `BufWriter` flushes on drop as well, but throws away any error. The store syncs the writer
+// when its last handle goes, so this only has work to do if that was skipped
+impl<T: Write> Drop for BufWriterWithPosition<T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.buf_writer.flush() {
+            log::error!("Error flushing log writer when dropped: {:?}", e);
+        }
+    }
+}
+
+/// Serialization format used for the records in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    MessagePack,
+    /// Larger and slower than MessagePack, but a log can be read by eye when debugging
+    Json,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::MessagePack => 1,
+            Codec::Json => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Codec> {
+        match id {
+            1 => Ok(Codec::MessagePack),
+            2 => Ok(Codec::Json),
+            _ => Err(KvsError::WrongCodec),
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::MessagePack => Ok(rmp_serde::to_vec(value)?),
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Codec::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+        }
+    }
+}
+
+/// Decides when buffered writes are pushed out of the `BufWriter` to the log file.
+///
+/// Anything written since the last sync only lives in process memory, so it is lost if the
+/// process dies without dropping the store. Reads always see buffered writes regardless of the
+/// policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every `set` and `remove`
+    EveryWrite,
+    /// Sync after every `n` writes
+    EveryN(usize),
+    /// Sync on the first write at least this long after the previous sync. There is no
+    /// background timer, so an idle store holds its buffered writes until the next write
+    Interval(Duration),
+    /// Only sync when the store is dropped or the buffer fills up
+    OnDropOnly,
+}
+
+/// Tuning knobs for a `KvStore`, passed to `KvStore::open_with_config`
+#[derive(Debug, Clone)]
+pub struct KvStoreConfig {
+    /// Number of bytes of overwritten or removed records allowed to build up in the log before
+    /// a write triggers compaction
+    pub compaction_threshold: u64,
+    /// Compact the log when the last handle to the store is dropped
+    pub compact_on_drop: bool,
+    pub sync_policy: SyncPolicy,
+    /// Also `sync_data` the log file whenever the policy syncs. A flush alone hands the data to
+    /// the OS, which survives the process crashing but not the machine losing power
+    pub fsync: bool,
+    /// Must match the codec the store was created with
+    pub codec: Codec,
+    /// How the in-memory index is kept. Only affects this open, nothing about it is on disk
+    pub index: IndexKind,
+    /// Compress records whose encoded size is at least `compression_threshold` bytes. Each
+    /// record notes its own compression, so this can be changed between opens
+    pub compression: Option<CompressionKind>,
+    pub compression_threshold: usize,
+    /// Once the active log grows past this many bytes it is left read-only and writes move on
+    /// to a new file. Compaction folds all of them back into one
+    pub max_file_size: u64,
+    /// Capacity of the buffer in front of the active log. Only worth raising with a sync policy
+    /// that lets writes build up, since every sync empties it
+    pub write_buffer_size: usize,
+    /// Largest key `set` accepts, measured in encoded bytes
+    pub max_key_size: Option<usize>,
+    /// Largest value `set` accepts, measured in encoded bytes
+    pub max_value_size: Option<usize>,
+    /// Read values out of memory maps of the logs instead of with a syscall per read. See
+    /// `FileBackend::mmap_reads` for what that asks of anything else touching the files
+    pub mmap_reads: bool,
+    /// Save the index when the last handle closes, after each compaction and every
+    /// `index_snapshot_interval` of writes, so the next open only replays what was written after
+    /// it instead of every log
+    pub index_snapshots: bool,
+    /// How long writes can go on before the index is saved again, bounding how much a crash
+    /// leaves to replay. `None` only saves it on close and after compaction
+    pub index_snapshot_interval: Option<Duration>,
+    /// Roughly how many keys the store holds, used to size the index before replay so it
+    /// doesn't grow a step at a time. Left unset, the saved index's key count is used if there
+    /// is one
+    pub expected_keys: Option<usize>,
+    /// Layout of the records this open writes. Every layout is always readable, so this only
+    /// matters to other builds reading the logs
+    pub record_format: RecordFormat,
+}
+
+/// The layouts a record can be written in, noted in the high four bits of its payload's first
+/// byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecordFormat {
+    /// The `KvRecord` enum as is. Noted as 0, so builds from before record versions can read it
+    V1,
+    /// A `StoredRecord`, whose fields can be added to without another version
+    #[default]
+    V2,
+}
+
+impl RecordFormat {
+    fn tag(self) -> u8 {
+        match self {
+            RecordFormat::V1 => 0,
+            RecordFormat::V2 => 2,
+        }
+    }
+
+    /// The store format version a manifest needs for other builds to know they can read it
+    fn format_version(self) -> u32 {
+        match self {
+            RecordFormat::V1 => 1,
+            RecordFormat::V2 => 2,
+        }
+    }
+}
+
+impl Default for KvStoreConfig {
+    fn default() -> Self {
+        KvStoreConfig {
+            compaction_threshold: 1024 * 1024,
+            compact_on_drop: false,
+            sync_policy: SyncPolicy::EveryWrite,
+            fsync: false,
+            codec: Codec::MessagePack,
+            index: IndexKind::Hash,
+            compression: None,
+            compression_threshold: 4 * 1024,
+            max_file_size: 64 * 1024 * 1024,
+            write_buffer_size: 8 * 1024,
+            max_key_size: None,
+            max_value_size: None,
+            mmap_reads: false,
+            index_snapshots: true,
+            index_snapshot_interval: Some(Duration::from_secs(60)),
+            expected_keys: None,
+            record_format: RecordFormat::default(),
+        }
+    }
+}
+
+/// Every log file starts with these magic bytes followed by the id of the codec it was written
+/// with, so a store can't be reopened with a codec that would misread it
+const LOG_MAGIC: &[u8; 3] = b"KVS";
+const LOG_HEADER_SIZE: u64 = 4;
+
+fn log_header(codec: Codec) -> [u8; LOG_HEADER_SIZE as usize] {
+    [LOG_MAGIC[0], LOG_MAGIC[1], LOG_MAGIC[2], codec.id()]
+}
+
+/// Written next to the data directory when a store is first opened, so a store opened with a
+/// codec or by a build that can't read it fails up front rather than on its first record
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    format_version: u32,
+    codec: Codec,
+}
+
+const MANIFEST_FILE: &str = "MANIFEST";
+/// Bumped whenever a change to the layout or record framing means older builds can't read it.
+/// Version 2 added `RecordFormat::V2`
+const FORMAT_VERSION: u32 = 2;
+
+/// Checks the manifest in `db_path` against `codec`, returning its format version if there is
+/// one. Stores from before the manifest have none, and only their log headers to go on
+fn check_manifest(db_path: &Path, codec: Codec) -> Result<Option<u32>> {
+    let manifest = match read_manifest(db_path)? {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    if manifest.codec != codec {
+        return Err(KvsError::IncompatibleFormat(format!(
+            "store was written with the {:?} codec but opened with {:?}",
+            manifest.codec, codec
+        )));
+    }
+    Ok(Some(manifest.format_version))
+}
+
+/// Reads the manifest in `db_path`, if there is one, checking it is a format this build reads
+fn read_manifest(db_path: &Path) -> Result<Option<Manifest>> {
+    let path = db_path.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let manifest: Manifest = serde_json::from_slice(&fs::read(&path)?).map_err(|e| {
+        KvsError::IncompatibleFormat(format!("{} is unreadable: {}", path.display(), e))
+    })?;
+    if manifest.format_version > FORMAT_VERSION {
+        return Err(KvsError::IncompatibleFormat(format!(
+            "store is format version {} but this build reads up to version {}",
+            manifest.format_version, FORMAT_VERSION
+        )));
+    }
+    Ok(Some(manifest))
+}
+
+fn write_manifest(db_path: &Path, codec: Codec, format_version: u32) -> Result<()> {
+    let manifest = Manifest {
+        format_version,
+        codec,
+    };
+    fs::write(db_path.join(MANIFEST_FILE), serde_json::to_vec(&manifest)?)?;
+    Ok(())
+}
+
+/// Every record on disk is framed as a big-endian CRC32 of the payload, then the big-endian
+/// payload length, then the payload. The payload is a byte with the id of the compression
+/// applied (0 for none) in its low four bits and the `RecordFormat` tag in its high four,
+/// followed by the encoded record
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// A backup starts with these magic bytes, the backup format version and the codec id, followed
+/// by the live records exactly as they were framed in the log
+const BACKUP_MAGIC: &[u8; 4] = b"KVSB";
+const BACKUP_VERSION: u8 = 1;
+
+fn encode_record<K: Key, V: Value>(
+    config: &KvStoreConfig,
+    record: &KvRecord<K, V>,
+) -> Result<Vec<u8>> {
+    let encoded = match config.record_format {
+        RecordFormat::V1 => config.codec.encode(record)?,
+        RecordFormat::V2 => config.codec.encode(&record.stored())?,
+    };
+    let tag = config.record_format.tag() << 4;
+    let mut payload = Vec::with_capacity(encoded.len() + 1);
+    match config.compression {
+        Some(kind) if encoded.len() >= config.compression_threshold => {
+            payload.push(tag | kind.id());
+            payload.extend_from_slice(&kind.compress(&encoded));
+        }
+        _ => {
+            payload.push(tag);
+            payload.extend_from_slice(&encoded);
+        }
+    }
+    let mut framed = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    framed.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&payload);
+    Ok(framed)
+}
+
+fn decode_record<K: Key, V: Value>(
+    codec: Codec,
+    framed: &[u8],
+    offset: u64,
+) -> Result<KvRecord<K, V>> {
+    if framed.len() < RECORD_HEADER_SIZE + 1 {
+        return Err(KvsError::Corruption { offset });
+    }
+    let (header, payload) = framed.split_at(RECORD_HEADER_SIZE);
+    let crc = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if payload.len() != len || crc32fast::hash(payload) != crc {
+        return Err(KvsError::Corruption { offset });
+    }
+    let decompressed;
+    let encoded = match CompressionKind::from_id(payload[0] & 0x0f)? {
+        Some(kind) => {
+            decompressed = kind.decompress(&payload[1..])?;
+            &decompressed[..]
+        }
+        None => &payload[1..],
+    };
+    match payload[0] >> 4 {
+        0 => codec.decode(encoded),
+        2 => Ok(codec.decode::<StoredRecord<K, V>>(encoded)?.into_record()),
+        tag => Err(KvsError::IncompatibleFormat(format!(
+            "record at offset {} has format tag {}, newer than this build reads",
+            offset, tag
+        ))),
+    }
+}
+
+/// Log ids count up from 1 past `newest`, the newest id in use, so they sort in creation order
+/// whatever the clock does. The newest log is never removed, so an id is never handed out
+/// twice. Stores from before this named logs by creation time, and carry on counting from there
+fn new_file_id(newest: u64) -> u64 {
+    newest + 1
+}
+
+/// The logs live in this subdirectory of the path a store is opened with, so the directory can
+/// be shared with config and other engines' files
+const DATA_DIR: &str = "data";
+
+/// Stores used to keep their logs directly in the directory they were opened with. Moving them
+/// one at a time is safe to redo if interrupted, since the ids don't clash
+fn migrate_flat_layout(db_path: &Path, data_dir: &Path) -> Result<()> {
+    for file_id in log_file_ids(db_path)? {
+        fs::rename(log_path(db_path, file_id), log_path(data_dir, file_id))?;
+    }
+    Ok(())
+}
+
+/// Finds the logs of an existing store, in the data directory or in the flat layout that
+/// predates it, without creating or moving anything
+fn existing_logs(db_path: &Path) -> Result<(PathBuf, Vec<u64>)> {
+    let mut data_dir = db_path.join(DATA_DIR);
+    let mut file_ids = log_file_ids(&data_dir).unwrap_or_default();
+    if file_ids.is_empty() {
+        data_dir = db_path.to_path_buf();
+        file_ids = log_file_ids(&data_dir)?;
+    }
+    if file_ids.is_empty() {
+        return Err(KvsError::FileListEmpty);
+    }
+    Ok((data_dir, file_ids))
+}
+
+/// The codec named in a log's header
+fn log_codec(path: &Path) -> Result<Codec> {
+    let mut log_header = [0u8; LOG_HEADER_SIZE as usize];
+    File::open(path)?
+        .read_exact(&mut log_header)
+        .map_err(|_| KvsError::Corruption { offset: 0 })?;
+    if &log_header[..LOG_MAGIC.len()] != LOG_MAGIC {
+        return Err(KvsError::Corruption { offset: 0 });
+    }
+    Codec::from_id(log_header[LOG_MAGIC.len()])
+}
+
+fn create_log_file(path: &Path, codec: Codec) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create_new(true)
+        .open(path)?;
+    file.write_all(&log_header(codec))?;
+    Ok(file)
 }
 
-fn get_new_file_path(dir_path: &Path) -> PathBuf {
-    dir_path.join(format!(
-        "{}.kvs",
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards")
-            .as_nanos()
-            .to_string()
-    ))
+fn create_log(backend: &dyn LogBackend, file_id: u64, codec: Codec) -> Result<Arc<dyn LogStorage>> {
+    let log = backend.create(file_id)?;
+    log.append(&log_header(codec))?;
+    Ok(log)
 }
 
+/// The index as saved by a store. Entries are `(key, file_id, offset, size, expires_at)`, valid
+/// for as much of each log as `logs` says it covered. Each log is listed as `(file_id, len,
+/// crc)`, with a CRC32 of the last `SAVED_INDEX_TAIL_SIZE` bytes before `len`
+#[derive(Serialize, Deserialize)]
+struct SavedIndex<K> {
+    logs: Vec<(u64, u64, u32)>,
+    entries: Vec<(K, u64, u64, usize, Option<u64>)>,
+}
+
+/// A saved index starts with these magic bytes, its format version and the codec id, then a
+/// big-endian CRC32 of the encoded `SavedIndex` that makes up the rest
+const SAVED_INDEX_MAGIC: &[u8; 4] = b"KVSI";
+const SAVED_INDEX_VERSION: u8 = 2;
+const SAVED_INDEX_HEADER_SIZE: usize = SAVED_INDEX_MAGIC.len() + 6;
+
+fn encode_saved_index<K: Key>(codec: Codec, saved: &SavedIndex<K>) -> Result<Vec<u8>> {
+    let payload = codec.encode(saved)?;
+    let mut bytes = Vec::with_capacity(SAVED_INDEX_HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(SAVED_INDEX_MAGIC);
+    bytes.extend_from_slice(&[SAVED_INDEX_VERSION, codec.id()]);
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// How much of the end of each covered log a saved index checksums
+const SAVED_INDEX_TAIL_SIZE: u64 = 4 * 1024;
+
+/// CRC32 of the last `SAVED_INDEX_TAIL_SIZE` bytes before `len` in `log`. Logs are only ever
+/// appended to or replaced, so a matching length and tail is enough to tell the covered part
+/// hasn't been swapped or cut short, without reading the whole log on every open and save
+fn log_tail_checksum(log: &dyn LogStorage, len: u64) -> Result<u32> {
+    let start = len.saturating_sub(SAVED_INDEX_TAIL_SIZE);
+    let mut tail = vec![0; (len - start) as usize];
+    log.read_at(start, &mut tail)?;
+    Ok(crc32fast::hash(&tail))
+}
+
+/// The saved index in `bytes`, if it is intact and still describes `readers`: every log it
+/// covers must still be there with the same tail, and any log it doesn't know about must be
+/// newer than all of those. Otherwise the reason it can't be used
+fn decode_saved_index<K: Key>(
+    codec: Codec,
+    bytes: &[u8],
+    readers: &Readers,
+) -> std::result::Result<SavedIndex<K>, String> {
+    if bytes.len() < SAVED_INDEX_HEADER_SIZE
+        || &bytes[..SAVED_INDEX_MAGIC.len()] != SAVED_INDEX_MAGIC
+    {
+        return Err("not a saved index".to_owned());
+    }
+    let (header, payload) = bytes.split_at(SAVED_INDEX_HEADER_SIZE);
+    let header = &header[SAVED_INDEX_MAGIC.len()..];
+    if header[0] != SAVED_INDEX_VERSION || header[1] != codec.id() {
+        return Err("written in another format".to_owned());
+    }
+    if crc32fast::hash(payload) != u32::from_be_bytes([header[2], header[3], header[4], header[5]])
+    {
+        return Err("checksum mismatch".to_owned());
+    }
+    let saved: SavedIndex<K> = codec.decode(payload).map_err(|e| e.to_string())?;
+    for (file_id, len, crc) in &saved.logs {
+        let unchanged = match readers.get(file_id) {
+            Some(log) => {
+                log.len().map_err(|e| e.to_string())? >= *len
+                    && log_tail_checksum(&**log, *len).map_err(|e| e.to_string())? == *crc
+            }
+            None => false,
+        };
+        if !unchanged {
+            return Err(format!("log {} has been removed or changed", file_id));
+        }
+    }
+    let newest_saved = saved.logs.iter().map(|(file_id, ..)| *file_id).max();
+    if readers.keys().any(|file_id| {
+        !saved.logs.iter().any(|(saved_id, ..)| saved_id == file_id)
+            && Some(*file_id) < newest_saved
+    }) {
+        return Err("a log it doesn't cover predates it".to_owned());
+    }
+    Ok(saved)
+}
+
+/// Buffer in front of the log `ingest` writes, big enough that a load is a few large writes
+const INGEST_BUFFER_SIZE: usize = 1024 * 1024;
+
+type IndexSnapshot<K> = Vec<(K, ValueData)>;
+
+/// Read handles for every log, keyed by file id. The newest is the active one
+type Readers = BTreeMap<u64, Arc<dyn LogStorage>>;
+
+/// Point-in-time counters describing a `KvStore`, returned by `KvStore::stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KvStoreStats {
+    /// Live keys, not counting expired ones
+    pub keys: usize,
+    /// Bytes of the log taken up by the records those keys point at
+    pub live_bytes: u64,
+    /// Bytes taken up by overwritten values and tombstones, which the next compaction drops
+    pub uncompressed_bytes: u64,
+    /// Total size of the log files, including writes still in the buffer
+    pub file_size: u64,
+    /// Compactions run since the store was opened
+    pub compactions: u64,
+}
+
+/// What `KvStore::validate` found
+#[derive(Debug)]
+pub struct ValidationReport {
+    /// Logs checked, including the one that failed
+    pub files: usize,
+    /// Records that replayed cleanly
+    pub records: usize,
+    /// The first log that failed to replay and why. Nothing after it is checked
+    pub error: Option<(PathBuf, KvsError)>,
+}
+
+/// How many locks `KvStore` spreads keys over. Writes to keys sharing one wait for each other
+const KEY_LOCK_STRIPES: usize = 64;
+
+/// A single-key write's hold on the store, from `KvStore::lock_key`
+struct KeyGuard<'a> {
+    _key: MutexGuard<'a, ()>,
+    _gate: RwLockReadGuard<'a, ()>,
+}
+
+/// The writer along with exclusive use of the index, from `KvStore::lock_writer`
+struct ExclusiveWriter<'a> {
+    writer: MutexGuard<'a, BufWriterWithPosition<LogWriter>>,
+    _gate: RwLockWriteGuard<'a, ()>,
+}
+
+impl Deref for ExclusiveWriter<'_> {
+    type Target = BufWriterWithPosition<LogWriter>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.writer
+    }
+}
+
+impl DerefMut for ExclusiveWriter<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.writer
+    }
+}
+
+/// Combines the current value of a key, if any, with a merge operand into its new value
+pub type MergeOperator<V> = dyn Fn(Option<&V>, &V) -> V + Send + Sync;
+
 pub struct KvStore<K, V>
 where
     K: Key,
     V: Value,
 {
-    path: Arc<PathBuf>,
-    writer: Arc<Mutex<BufWriterWithPosition<File>>>,
+    // Where the logs are kept, usually the data directory
+    backend: Arc<dyn LogBackend>,
+    writer: Arc<Mutex<BufWriterWithPosition<LogWriter>>>,
+    // Single-key writes hold this shared from before their append until the index points at
+    // it, so they only contend with each other for the append itself. Anything that needs the
+    // index to match the log, like batches and compaction, holds it exclusively through
+    // `lock_writer`. Taken before the writer
+    commit_gate: Arc<RwLock<()>>,
+    // Striped locks serializing writes to the same key, so they reach the index in log order.
+    // Taken before the commit gate
+    key_locks: Arc<Vec<Mutex<()>>>,
     // All readers can read from the buffer even when performing writes or compaction
     // However, when compaction is complete and we want to block reading as we flip to the new
     // reader and index map
-    reader: Arc<RwLock<File>>,
-    index: Arc<DashMap<K, ValueData>>,
-    uncompressed_bytes: AtomicU64,
+    readers: Arc<RwLock<Readers>>,
+    index: Arc<Index<K>>,
+    // Only the active file can have data still in the BufWriter. Older ones are always flushed
+    active_file_id: Arc<AtomicU64>,
+    // Everything before this offset in the active file has left the BufWriter and can be read
+    flushed_position: Arc<AtomicU64>,
+    uncompressed_bytes: Arc<AtomicU64>,
+    compactions: Arc<AtomicU64>,
+    // Set by an append once `index_snapshot_interval` has passed, and cleared by whichever
+    // write takes it up and saves the index after letting go of its locks
+    index_save_due: Arc<AtomicBool>,
+    // Only advanced under the writer lock
+    last_version: Arc<AtomicU64>,
+    config: Arc<KvStoreConfig>,
+    merge_operator: Option<Arc<MergeOperator<V>>>,
+    // Set by `open_read_only`. The writer then wraps a read handle and must never be written to
+    read_only: bool,
+    watchers: Arc<Watchers<K, V>>,
     phantom: PhantomData<V>,
 }
 
-impl <K,V> Clone for KvStore<K, V>
+impl<K, V> Clone for KvStore<K, V>
 where
     K: Key,
     V: Value,
 {
     fn clone(&self) -> Self {
         Self {
-            path: self.path.clone(),
+            backend: self.backend.clone(),
             writer: self.writer.clone(),
-            reader: self.reader.clone(),
+            commit_gate: self.commit_gate.clone(),
+            key_locks: self.key_locks.clone(),
+            readers: self.readers.clone(),
             index: self.index.clone(),
-            uncompressed_bytes: AtomicU64::new(self.uncompressed_bytes.load(Ordering::SeqCst)),
-            phantom: self.phantom.clone()
+            active_file_id: self.active_file_id.clone(),
+            flushed_position: self.flushed_position.clone(),
+            uncompressed_bytes: self.uncompressed_bytes.clone(),
+            compactions: self.compactions.clone(),
+            index_save_due: self.index_save_due.clone(),
+            last_version: self.last_version.clone(),
+            config: self.config.clone(),
+            merge_operator: self.merge_operator.clone(),
+            read_only: self.read_only,
+            watchers: self.watchers.clone(),
+            phantom: self.phantom,
+        }
+    }
+}
+
+/// Iterator over a point-in-time snapshot of a `KvStore`, created by `KvStore::iter`
+pub struct KvStoreIter<K, V> {
+    // Handles to the logs as they were at snapshot time. Compaction removes rather than
+    // truncates old logs, so the snapshot offsets stay valid for as long as these are held
+    readers: Readers,
+    codec: Codec,
+    entries: std::vec::IntoIter<(K, ValueData)>,
+    phantom: PhantomData<V>,
+}
+
+impl<K, V> Iterator for KvStoreIter<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (key, value_data) in self.entries.by_ref() {
+            match KvStore::<K, V>::read_value(self.codec, &self.readers, &value_data) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
         }
+        None
     }
 }
 
@@ -108,60 +893,86 @@ where
     V: Value,
 {
     fn set(&self, key: K, val: V) -> Result<()> {
-        let serialized = rmp_serde::to_vec(&KvRecord::Set((key.clone(), val)))?;
-        let mut writer = self.writer.lock()?;
-        let value_data = ValueData {
-            offset: writer.position,
-            size: serialized.len(),
-        };
-        writer.buf_writer.write_all(&serialized)?;
-        writer.buf_writer.flush()?;
-        writer.position += serialized.len() as u64;
-        if let Some(previous_value) = self.index.insert(key, value_data) {
-            // if we were over 10k then run compaction
-            if self.uncompressed_bytes.fetch_add(previous_value.size as u64, Ordering::SeqCst) > 1000000 {
-                drop(writer);
-                self.compact_file()?;
-            }
-        }
-        Ok(())
+        self.write_set(KvRecord::Set((key, val)))
     }
     fn get(&self, key: K) -> Result<Option<V>> {
-        if let Some(entry) = self.index.get(&key) {
-            let mut buf = vec![0u8; entry.value().size];
-            self.reader.read()?.read_exact_at(&mut buf, entry.value().offset)?;
-            match rmp_serde::from_slice(&buf)? {
-                KvRecord::Set(kv) => {
-                    let _key: K = kv.0;
-                    Ok(Some(kv.1))
-                }
-                _ => Ok(None),
+        Ok(KvStore::get_versioned(self, key)?.0)
+    }
+    fn remove(&self, key: K) -> Result<()> {
+        let key_guard = self.lock_key(&key)?;
+        self.commit_remove(key_guard, key)
+    }
+    fn scan(&self, start: Bound<K>, end: Bound<K>) -> Result<Vec<(K, V)>> {
+        let (readers, mut entries) = self.snapshot(start.as_ref(), end.as_ref())?;
+        // A hash index hands the keys back in no order, so they have to be sorted before reading
+        if !self.index.is_ordered() {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, value_data) in entries {
+            if let Some(value) =
+                KvStore::<K, V>::read_value(self.config.codec, &readers, &value_data)?
+            {
+                pairs.push((key, value));
             }
-        } else {
-            Ok(None)
         }
+        Ok(pairs)
     }
-    fn remove(&self, key: K) -> Result<()> {
+    fn compact(&self) -> Result<()> {
+        self.compact_file()
+    }
+    fn flush(&self) -> Result<()> {
         let mut writer = self.writer.lock()?;
-        if let Some(previous_value) = self.index.remove(&key) {
-            let serialized = rmp_serde::to_vec(&KvRecord::<K, V>::Rm(key.clone()))?;
-            let value_data = ValueData {
-                offset: writer.position,
-                size: serialized.len(),
-            };
-            writer.buf_writer.write_all(&serialized)?;
-            writer.buf_writer.flush()?;
-            writer.position += serialized.len() as u64;
-            // if we were over 10k then run compaction
-            if self.uncompressed_bytes.fetch_add((previous_value.1.size + value_data.size) as u64, Ordering::SeqCst) > 1000000 {
-                drop(writer);
-                self.compact_file()?;
+        self.sync_writer(&mut writer)?;
+        if !self.config.fsync {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+    fn stats(&self) -> Result<KvStoreStats> {
+        KvStore::stats(self)
+    }
+    fn watch(&self, prefix: K) -> Result<Receiver<WatchEvent<K, V>>> {
+        Ok(self.watchers.watch(prefix))
+    }
+    fn iter(&self) -> Result<Pairs<K, V>> {
+        Ok(Box::new(KvStore::iter(self)?))
+    }
+    /// Looks every key up under one hold of the readers lock, flushing the writer at most once
+    /// for the lot instead of once per key
+    fn get_many(&self, keys: Vec<K>) -> Result<Vec<Option<V>>> {
+        loop {
+            let readers = self.readers.read()?;
+            let now = now_millis();
+            let entries: Vec<Option<ValueData>> = keys
+                .iter()
+                .map(|key| self.index.get(key).filter(|entry| !entry.is_expired(now)))
+                .collect();
+            if entries.iter().flatten().all(|entry| self.is_flushed(entry)) {
+                return entries
+                    .iter()
+                    .map(|entry| match entry {
+                        Some(value_data) => {
+                            KvStore::<K, V>::read_value(self.config.codec, &readers, value_data)
+                        }
+                        None => Ok(None),
+                    })
+                    .collect();
             }
-            Ok(())
-        } else {
-            Err(KvsError::NonExistantKey)
+            drop(readers);
+            self.flush_writer()?;
         }
     }
+    /// One `write_batch`, so the pairs land together under a single writer lock
+    fn set_many(&self, pairs: Vec<(K, V)>) -> Result<()> {
+        self.write_batch(pairs.into_iter().map(KvRecord::Set).collect())
+    }
+    fn get_versioned(&self, key: K) -> Result<(Option<V>, u64)> {
+        KvStore::get_versioned(self, key)
+    }
+    fn commit(&self, reads: Vec<(K, u64)>, writes: Vec<KvRecord<K, V>>) -> Result<bool> {
+        KvStore::commit(self, reads, writes)
+    }
 }
 
 impl From<rmp_serde::decode::Error> for KvsError {
@@ -187,127 +998,1377 @@ where
     K: Key,
     V: Value,
 {
-    fn compress_dir_files(db_path: &Path) -> Result<PathBuf> {
-        if !db_path.exists() {
-            fs::create_dir_all(&db_path)?;
-        }
-        let mut files_in_dir = fs::read_dir(&db_path)?;
-        let path = files_in_dir
-            .next()
-            .map(|f| f.unwrap().path())
-            .unwrap_or(get_new_file_path(db_path));
-        let mut final_file = fs::OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&path)?;
-        for file in files_in_dir {
-            let file = file.unwrap();
-            if file
-                .path()
-                .extension()
-                .map(|osstr| (*osstr).to_str().map(|str| str == "kvs").unwrap_or(false))
-                .unwrap_or(false)
-            {
-                let mut to_copy = fs::OpenOptions::new().read(true).open(file.path())?;
-                io::copy(&mut final_file, &mut to_copy)?;
+    /// Sets `key` to `value` for `ttl`, after which it reads as absent and is dropped by the next
+    /// compaction.
+    ///
+    /// The expiry is stored as an absolute wall clock time, so it survives restarts, but it is
+    /// judged against the local clock when read. A clock stepping backwards keeps keys alive for
+    /// longer and one stepping forwards expires them early.
+    pub fn set_with_ttl(&self, key: K, value: V, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis() + ttl.as_millis() as u64;
+        self.write_set(KvRecord::SetExpiring((key, value, expires_at)))
+    }
+
+    /// Encodes a record that is about to be written for the first time, enforcing the size
+    /// limits. Compaction re-encodes without them, so lowering a limit never strands old data
+    fn encode_new(&self, record: &KvRecord<K, V>) -> Result<Vec<u8>> {
+        if let Some(max_key_size) = self.config.max_key_size {
+            if self.config.codec.encode(record.key())?.len() > max_key_size {
+                return Err(KvsError::KeyTooLarge);
             }
         }
-        Ok(path)
+        if let Some(max_value_size) = self.config.max_value_size {
+            if let Some(value) = record.value() {
+                if self.config.codec.encode(value)?.len() > max_value_size {
+                    return Err(KvsError::ValueTooLarge);
+                }
+            }
+        }
+        encode_record(&self.config, record)
     }
 
-    fn deserialize_file(
-        file_path: &PathBuf,
-        mut f: impl FnMut(KvRecord<K, V>, ValueData) -> (),
+    /// Takes the writer and the index for a change to the store that isn't to a single key,
+    /// which a read-only store refuses
+    fn lock_writer(&self) -> Result<ExclusiveWriter<'_>> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        self.lock_exclusive()
+    }
+
+    /// Takes the writer, waiting for single-key writes in flight to reach the index
+    fn lock_exclusive(&self) -> Result<ExclusiveWriter<'_>> {
+        let gate = self.commit_gate.write()?;
+        Ok(ExclusiveWriter {
+            writer: self.writer.lock()?,
+            _gate: gate,
+        })
+    }
+
+    /// Takes the lock for a write to `key` alone, which a read-only store refuses. Writes to
+    /// other keys can go ahead meanwhile, except for the append itself
+    fn lock_key(&self, key: &K) -> Result<KeyGuard<'_>> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let stripe = hasher.finish() as usize % self.key_locks.len();
+        let key_lock = self.key_locks[stripe].lock()?;
+        Ok(KeyGuard {
+            _key: key_lock,
+            _gate: self.commit_gate.read()?,
+        })
+    }
+
+    fn write_set(&self, record: KvRecord<K, V>) -> Result<()> {
+        let record = record.stamped(now_millis());
+        let serialized = self.encode_new(&record)?;
+        let key_guard = self.lock_key(record.key())?;
+        self.commit_set(key_guard, &record, &serialized)
+    }
+
+    /// Appends `record`, already encoded as `serialized`, points the index at it and tells the
+    /// watchers, then runs compaction if that pushed the dead bytes over the threshold. Only
+    /// the append holds the writer
+    fn commit_set(
+        &self,
+        key_guard: KeyGuard<'_>,
+        record: &KvRecord<K, V>,
+        serialized: &[u8],
     ) -> Result<()> {
-        let file = fs::read(file_path)?;
-        let mut deserializer = rmp_serde::Deserializer::new(Cursor::new(&file));
-        let mut position: u64 = 0;
-        while position < file.len() as u64 {
-            let deserialized: KvRecord<K, V> = serde::Deserialize::deserialize(&mut deserializer)?;
-            let new_position = rmp_serde::decode::Deserializer::position(&deserializer);
-            let value_data = ValueData {
-                offset: position,
-                size: (new_position - position) as usize,
-            };
-            f(deserialized, value_data);
-            position = new_position;
+        let value_data = self.append(&mut *self.writer.lock()?, serialized, record.expires_at())?;
+        let key = record.key().clone();
+        self.watchers.notify(&key, record.value());
+        let compact = match self.index.insert(key, value_data) {
+            Some(previous_value) => self.add_uncompressed_bytes(previous_value.size as u64),
+            None => false,
+        };
+        // compaction and saving the index take the commit gate themselves
+        drop(key_guard);
+        if compact {
+            self.compact_file()?;
         }
+        self.save_index_if_due();
         Ok(())
     }
 
-    pub fn open(db_path: &Path) -> Result<KvStore<K, V>> {
-        let file_path = KvStore::<K, V>::compress_dir_files(db_path)?;
-        let index = Arc::new(DashMap::new());
-        KvStore::deserialize_file(&file_path, |deserialized: KvRecord<K, V>, value_data| {
-            match deserialized {
-                KvRecord::Set(kv) => {
-                    index.insert(kv.0, value_data);
+    /// Appends a tombstone for `key` and drops it from the index, then runs compaction if that
+    /// pushed the dead bytes over the threshold. Only the append holds the writer
+    fn commit_remove(&self, key_guard: KeyGuard<'_>, key: K) -> Result<()> {
+        if let Some(previous_value) = self.index.remove(&key) {
+            if previous_value.is_expired(now_millis()) {
+                // Already gone as far as readers are concerned, and it can't come back on
+                // reopen, so there is no need for a tombstone
+                self.add_uncompressed_bytes(previous_value.size as u64);
+                return Err(KvsError::NonExistantKey);
+            }
+            let serialized = encode_record(&self.config, &KvRecord::<K, V>::Rm(key.clone()))?;
+            let value_data = self.append(&mut *self.writer.lock()?, &serialized, None)?;
+            self.watchers.notify(&key, None);
+            let compact =
+                self.add_uncompressed_bytes((previous_value.size + value_data.size) as u64);
+            // compaction and saving the index take the commit gate themselves
+            drop(key_guard);
+            if compact {
+                self.compact_file()?;
+            }
+            self.save_index_if_due();
+            Ok(())
+        } else {
+            Err(KvsError::NonExistantKey)
+        }
+    }
+
+    /// Sets `key` to `value`, returning the value it replaced. The old value has to be read
+    /// back from the log, so this costs a read on top of a plain `set`
+    pub fn set_returning(&self, key: K, value: V) -> Result<Option<V>> {
+        let record = KvRecord::Set((key, value)).stamped(now_millis());
+        let serialized = self.encode_new(&record)?;
+        let key_guard = self.lock_key(record.key())?;
+        let previous = self.get_locked(&key_guard, record.key())?;
+        self.commit_set(key_guard, &record, &serialized)?;
+        Ok(previous)
+    }
+
+    /// Removes `key`, returning the value it had. Like `remove`, fails with `NonExistantKey` if
+    /// there was nothing to remove
+    pub fn remove_returning(&self, key: K) -> Result<V> {
+        let key_guard = self.lock_key(&key)?;
+        let previous = self
+            .get_locked(&key_guard, &key)?
+            .ok_or(KvsError::NonExistantKey)?;
+        self.commit_remove(key_guard, key)?;
+        Ok(previous)
+    }
+
+    /// Reads the current value of `key` while the caller holds its lock, so it can't change
+    /// underneath them
+    fn get_locked(&self, _key_guard: &KeyGuard<'_>, key: &K) -> Result<Option<V>> {
+        Ok(self
+            .get_record(key)?
+            .and_then(|(record, _)| record.into_live_value(now_millis())))
+    }
+
+    /// Folds `operand` into the current value of `key` with the merge operator given to
+    /// `open_with_merge_operator`, and stores the result as a plain set.
+    ///
+    /// Merges to a key run one at a time under its lock, so none are lost, but concurrent merges
+    /// are applied in whatever order they take the lock. For the result to be deterministic the
+    /// operator should be associative and commutative, like addition or set union.
+    pub fn merge(&self, key: K, operand: V) -> Result<()> {
+        let merge_operator = self
+            .merge_operator
+            .as_ref()
+            .ok_or(KvsError::NoMergeOperator)?;
+        let key_guard = self.lock_key(&key)?;
+        let current = self.get_locked(&key_guard, &key)?;
+        let record =
+            KvRecord::Set((key, merge_operator(current.as_ref(), &operand))).stamped(now_millis());
+        let serialized = self.encode_new(&record)?;
+        self.commit_set(key_guard, &record, &serialized)
+    }
+
+    /// Loads every pair in `pairs` into a fresh log, for filling a store far faster than a `set`
+    /// per pair. Nothing is flushed or synced until the last pair is written, after which the
+    /// whole load becomes visible at once. If anything fails the new log is thrown away and the
+    /// store is left as it was. Returns how many pairs were loaded.
+    ///
+    /// Writes wait for the load to finish, and it all lands in one log however far past
+    /// `max_file_size` that takes it.
+    pub fn ingest(&self, pairs: impl IntoIterator<Item = (K, V)>) -> Result<usize> {
+        let mut writer = self.lock_writer()?;
+        self.sync_writer(&mut writer)?;
+        let file_id = new_file_id(writer.file_id);
+        let log = create_log(&*self.backend, file_id, self.config.codec)?;
+        let mut loaded = Vec::new();
+        let end = match self.write_ingest(&log, pairs, &mut loaded) {
+            Ok(end) => end,
+            Err(e) => {
+                if let Err(remove_err) = self.backend.remove(file_id) {
+                    log::warn!("Error removing failed ingest log: {:?}", remove_err);
                 }
+                return Err(e);
+            }
+        };
+
+        // The log only joins the readers now, so nobody could have seen it half written
+        let mut readers = self.readers.write()?;
+        readers.insert(file_id, Arc::clone(&log));
+        let count = loaded.len();
+        let mut dead_bytes = 0;
+        for (key, value, offset, size) in loaded {
+            let value_data = ValueData {
+                file_id,
+                offset,
+                size,
+                expires_at: None,
+                version: self.next_version(),
+            };
+            self.watchers.notify(&key, Some(&value));
+            if let Some(previous_value) = self.index.insert(key, value_data) {
+                dead_bytes += previous_value.size as u64;
+            }
+        }
+        self.active_file_id.store(file_id, Ordering::SeqCst);
+        self.flushed_position.store(end, Ordering::SeqCst);
+        drop(readers);
+
+        writer.buf_writer = BufWriter::with_capacity(self.config.write_buffer_size, LogWriter(log));
+        writer.file_id = file_id;
+        writer.position = end;
+        writer.writes_since_sync = 0;
+        writer.last_sync = Instant::now();
+        if self.add_uncompressed_bytes(dead_bytes) {
+            // compaction takes the commit gate itself
+            drop(writer);
+            self.compact_file()?;
+        }
+        Ok(count)
+    }
+
+    /// Writes the records for `pairs` to `log` through one large buffer, noting where each
+    /// landed in `loaded`, then syncs it once. Returns the end of the log
+    fn write_ingest(
+        &self,
+        log: &Arc<dyn LogStorage>,
+        pairs: impl IntoIterator<Item = (K, V)>,
+        loaded: &mut Vec<(K, V, u64, usize)>,
+    ) -> Result<u64> {
+        let now = now_millis();
+        let mut out = BufWriter::with_capacity(INGEST_BUFFER_SIZE, LogWriter(Arc::clone(log)));
+        let mut offset = LOG_HEADER_SIZE;
+        for (key, value) in pairs {
+            let record = KvRecord::Set((key, value)).stamped(now);
+            let serialized = self.encode_new(&record)?;
+            out.write_all(&serialized)?;
+            if let KvRecord::SetWithMeta((key, value, _)) = record {
+                loaded.push((key, value, offset, serialized.len()));
+            }
+            offset += serialized.len() as u64;
+        }
+        out.flush()?;
+        log.sync()?;
+        Ok(offset)
+    }
+
+    /// Applies all of `ops` in order as a single append to the log. Either every record lands and
+    /// becomes visible to readers at once, or the batch fails and none of it is applied.
+    ///
+    /// Unlike `remove`, an `Rm` of a key that doesn't exist is not an error inside a batch.
+    pub fn write_batch(&self, ops: Vec<KvRecord<K, V>>) -> Result<()> {
+        let ops = stamp_all(ops);
+        let (serialized, sizes) = self.encode_batch(&ops)?;
+        let writer = self.lock_writer()?;
+        self.commit_batch(writer, ops, &serialized, sizes)
+    }
+
+    /// Applies `writes` like `write_batch`, but only if every key in `reads` is still at the
+    /// version it was read at, returning whether it did. Versions come from `get_versioned`, and
+    /// an absent key is at version 0.
+    ///
+    /// The check and the writes happen under the writer lock, so of two transactions that read
+    /// a key the other one writes, at most one commits. Removing a key puts it back to version
+    /// 0, so a transaction that read it as absent can't tell whether it was set and removed
+    /// again in the meantime.
+    pub fn commit(&self, reads: Vec<(K, u64)>, writes: Vec<KvRecord<K, V>>) -> Result<bool> {
+        let writes = stamp_all(writes);
+        let (serialized, sizes) = self.encode_batch(&writes)?;
+        let writer = self.lock_writer()?;
+        let now = now_millis();
+        let unchanged = reads.iter().all(|(key, version)| {
+            let current = self
+                .index
+                .get(key)
+                .filter(|entry| !entry.is_expired(now))
+                .map(|entry| entry.version)
+                .unwrap_or(0);
+            current == *version
+        });
+        if !unchanged {
+            return Ok(false);
+        }
+        self.commit_batch(writer, writes, &serialized, sizes)?;
+        Ok(true)
+    }
+
+    /// Removes every live key between `start` and `end`, returning how many there were. The
+    /// tombstones go out as one batch under a single writer lock, so no write lands in the
+    /// range part way through, and compaction reclaims them like any other removal
+    pub fn remove_range(&self, start: Bound<K>, end: Bound<K>) -> Result<usize> {
+        let writer = self.lock_writer()?;
+        let now = now_millis();
+        let ops: Vec<KvRecord<K, V>> = self
+            .index
+            .entries(start.as_ref(), end.as_ref(), |value_data| {
+                !value_data.is_expired(now)
+            })
+            .into_iter()
+            .map(|(key, _)| KvRecord::Rm(key))
+            .collect();
+        if ops.is_empty() {
+            return Ok(0);
+        }
+        let removed = ops.len();
+        let (serialized, sizes) = self.encode_batch(&ops)?;
+        self.commit_batch(writer, ops, &serialized, sizes)?;
+        Ok(removed)
+    }
+
+    /// Reads `key` along with its version, for use in `commit`
+    pub fn get_versioned(&self, key: K) -> Result<(Option<V>, u64)> {
+        match self.get_record(&key)? {
+            Some((record, value_data)) => {
+                let value = record.into_live_value(now_millis());
+                let version = if value.is_some() {
+                    value_data.version
+                } else {
+                    0
+                };
+                Ok((value, version))
+            }
+            None => Ok((None, 0)),
+        }
+    }
+
+    /// Reads `key` along with when it was last written, for last-write-wins merging. Keys last
+    /// written before the store kept timestamps report the UNIX epoch
+    pub fn get_meta(&self, key: K) -> Result<Option<(V, SystemTime)>> {
+        let (record, _) = match self.get_record(&key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let written_at = UNIX_EPOCH + Duration::from_millis(record.written_at());
+        Ok(record
+            .into_live_value(now_millis())
+            .map(|value| (value, written_at)))
+    }
+
+    /// The record the index has for `key`, unless it has expired
+    fn get_record(&self, key: &K) -> Result<Option<(KvRecord<K, V>, ValueData)>> {
+        loop {
+            // Lock the readers before the index so compaction can't swap files between the two
+            let readers = self.readers.read()?;
+            let value_data = match self.index.get(key) {
+                Some(value_data) => value_data,
+                None => return Ok(None),
+            };
+            if value_data.is_expired(now_millis()) {
+                return Ok(None);
+            }
+            if self.is_flushed(&value_data) {
+                let record =
+                    KvStore::<K, V>::read_record(self.config.codec, &readers, &value_data)?;
+                return Ok(Some((record, value_data)));
+            }
+            // The record is still in the BufWriter. Flushing needs the writer lock, which
+            // compaction takes before the readers lock, so let go of the readers first
+            drop(readers);
+            self.flush_writer()?;
+        }
+    }
+
+    /// Encodes the records of a batch back to back, returning them along with their sizes
+    fn encode_batch(&self, ops: &[KvRecord<K, V>]) -> Result<(Vec<u8>, Vec<usize>)> {
+        let mut serialized = Vec::new();
+        let mut sizes = Vec::with_capacity(ops.len());
+        for op in ops {
+            let record = self.encode_new(op)?;
+            sizes.push(record.len());
+            serialized.extend_from_slice(&record);
+        }
+        Ok((serialized, sizes))
+    }
+
+    fn commit_batch(
+        &self,
+        mut writer: ExclusiveWriter<'_>,
+        ops: Vec<KvRecord<K, V>>,
+        serialized: &[u8],
+        sizes: Vec<usize>,
+    ) -> Result<()> {
+        // Rotate up front so the whole batch lands in one file, and `start` stays meaningful
+        self.rotate_if_full(&mut writer)?;
+        let file_id = writer.file_id;
+        let start = writer.position;
+        if let Err(e) = self.append(&mut writer, serialized, None) {
+            self.rollback(&mut writer, start)?;
+            return Err(e);
+        }
+
+        // Readers take the readers lock before the index, so holding it while the index is
+        // updated keeps them from seeing half of the batch
+        let readers = self.readers.write()?;
+        let mut offset = start;
+        let mut dead_bytes = 0;
+        for (op, size) in ops.into_iter().zip(sizes) {
+            let value_data = ValueData {
+                file_id,
+                offset,
+                size,
+                expires_at: op.expires_at(),
+                version: self.next_version(),
+            };
+            offset += size as u64;
+            self.watchers.notify(op.key(), op.value());
+            let previous_value = match op {
+                KvRecord::Set((key, _))
+                | KvRecord::SetExpiring((key, _, _))
+                | KvRecord::SetWithMeta((key, _, _)) => self.index.insert(key, value_data),
                 KvRecord::Rm(key) => {
-                    index.insert(key, value_data);
+                    dead_bytes += size as u64;
+                    self.index.remove(&key)
                 }
+            };
+            if let Some(previous_value) = previous_value {
+                dead_bytes += previous_value.size as u64;
             }
+        }
+        drop(readers);
+
+        // compaction and saving the index take the commit gate themselves
+        drop(writer);
+        if self.add_uncompressed_bytes(dead_bytes) {
+            self.compact_file()?;
+        }
+        self.save_index_if_due();
+        Ok(())
+    }
+
+    /// Throws away anything written past `position` after a failed append, including whatever
+    /// is still sitting in the buffer, so a later write can't land after a partial record
+    fn rollback(&self, writer: &mut BufWriterWithPosition<LogWriter>, position: u64) -> Result<()> {
+        let log = LogWriter(Arc::clone(&writer.buf_writer.get_ref().0));
+        // `into_parts` hands back the buffer without flushing it, unlike dropping the BufWriter
+        let (log, _unwritten) = std::mem::replace(
+            &mut writer.buf_writer,
+            BufWriter::with_capacity(self.config.write_buffer_size, log),
+        )
+        .into_parts();
+        log.0.truncate(position)?;
+        writer.position = position;
+        self.flushed_position.fetch_min(position, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Gathers the store's counters. Like `len`, this walks the whole index
+    pub fn stats(&self) -> Result<KvStoreStats> {
+        let writer = self.writer.lock()?;
+        let mut file_size = writer.position;
+        for (file_id, reader) in self.readers.read()?.iter() {
+            if *file_id != writer.file_id {
+                file_size += reader.len()?;
+            }
+        }
+        drop(writer);
+        let now = now_millis();
+        let (keys, live_bytes) = self.index.fold((0, 0), |(keys, bytes), _, value_data| {
+            if value_data.is_expired(now) {
+                (keys, bytes)
+            } else {
+                (keys + 1, bytes + value_data.size as u64)
+            }
+        });
+        Ok(KvStoreStats {
+            keys,
+            live_bytes,
+            uncompressed_bytes: self.uncompressed_bytes.load(Ordering::SeqCst),
+            file_size,
+            compactions: self.compactions.load(Ordering::SeqCst),
+        })
+    }
+
+    /// Checks whether `key` has a live value without reading it from disk
+    pub fn contains_key(&self, key: &K) -> bool {
+        let now = now_millis();
+        self.index
+            .get(key)
+            .map(|entry| !entry.is_expired(now))
+            .unwrap_or(false)
+    }
+
+    /// Number of live keys in the store. Expired keys have to be filtered out, so this walks
+    /// the whole index
+    pub fn len(&self) -> usize {
+        let now = now_millis();
+        self.index.fold(0, |count, _, value_data| {
+            count + !value_data.is_expired(now) as usize
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns every live key, in key order with an `IndexKind::Ordered` index and in no
+    /// particular order otherwise
+    pub fn keys(&self) -> Vec<K> {
+        let now = now_millis();
+        self.index
+            .entries(Bound::Unbounded, Bound::Unbounded, |value_data| {
+                !value_data.is_expired(now)
+            })
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Returns a lazy iterator over every live pair, in key order with an `IndexKind::Ordered`
+    /// index and in no particular order otherwise. The set of keys and
+    /// their locations are captured up front and values are read as the iterator advances, so
+    /// writes made after this call are not observed
+    pub fn iter(&self) -> Result<KvStoreIter<K, V>> {
+        let (readers, entries) = self.snapshot(Bound::Unbounded, Bound::Unbounded)?;
+        let readers = readers.clone();
+        Ok(KvStoreIter {
+            readers,
+            codec: self.config.codec,
+            entries: entries.into_iter(),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Writes every live pair to `writer` as one `[key, value]` JSON array per line. The pairs
+    /// come from an `iter` snapshot, so writes made while exporting are not included
+    pub fn export_json(&self, mut writer: impl Write) -> Result<()> {
+        for pair in self.iter()? {
+            serde_json::to_writer(&mut writer, &pair?)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Loads pairs written by `export_json`, returning how many were set. They go through
+    /// `write_batch` a chunk at a time, so a bad line stops the import after the last full chunk
+    pub fn import_json(&self, reader: impl Read) -> Result<usize> {
+        const CHUNK_SIZE: usize = 1024;
+        let mut imported = 0;
+        let mut batch = Vec::with_capacity(CHUNK_SIZE);
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            batch.push(KvRecord::Set(serde_json::from_str(&line)?));
+            if batch.len() == CHUNK_SIZE {
+                imported += batch.len();
+                self.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            imported += batch.len();
+            self.write_batch(batch)?;
+        }
+        Ok(imported)
+    }
+
+    /// Copies out the live index entries with keys between `start` and `end`, returning them
+    /// along with the readers lock they are valid under. The writer is flushed first if any of
+    /// them still point at buffered data
+    fn snapshot(
+        &self,
+        start: Bound<&K>,
+        end: Bound<&K>,
+    ) -> Result<(RwLockReadGuard<'_, Readers>, IndexSnapshot<K>)> {
+        loop {
+            let readers = self.readers.read()?;
+            let now = now_millis();
+            let entries = self
+                .index
+                .entries(start, end, |value_data| !value_data.is_expired(now));
+            if entries
+                .iter()
+                .all(|(_, value_data)| self.is_flushed(value_data))
+            {
+                return Ok((readers, entries));
+            }
+            drop(readers);
+            self.flush_writer()?;
+        }
+    }
+
+    fn next_version(&self) -> u64 {
+        self.last_version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn is_flushed(&self, value_data: &ValueData) -> bool {
+        value_data.file_id != self.active_file_id.load(Ordering::SeqCst)
+            || value_data.offset + value_data.size as u64
+                <= self.flushed_position.load(Ordering::SeqCst)
+    }
+
+    /// Appends an already framed record to the active log, syncing according to the configured
+    /// `SyncPolicy`, and returns where it landed
+    fn append(
+        &self,
+        writer: &mut BufWriterWithPosition<LogWriter>,
+        serialized: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<ValueData> {
+        self.rotate_if_full(writer)?;
+        let value_data = ValueData {
+            file_id: writer.file_id,
+            offset: writer.position,
+            size: serialized.len(),
+            expires_at,
+            version: self.next_version(),
+        };
+        writer.buf_writer.write_all(serialized)?;
+        writer.position += serialized.len() as u64;
+        writer.writes_since_sync += 1;
+        let sync_due = match self.config.sync_policy {
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => writer.writes_since_sync >= n,
+            SyncPolicy::Interval(interval) => writer.last_sync.elapsed() >= interval,
+            SyncPolicy::OnDropOnly => false,
+        };
+        if sync_due {
+            self.sync_writer(writer)?;
+        }
+        if self.config.index_snapshots
+            && self
+                .config
+                .index_snapshot_interval
+                .is_some_and(|interval| writer.last_index_save.elapsed() >= interval)
+        {
+            self.index_save_due.store(true, Ordering::SeqCst);
+        }
+        Ok(value_data)
+    }
+
+    /// Starts a new active log once the current one has reached `max_file_size`. The old one is
+    /// left read-only until compaction folds it into a new file
+    fn rotate_if_full(&self, writer: &mut BufWriterWithPosition<LogWriter>) -> Result<()> {
+        // A file always gets at least one record, however small the limit
+        if writer.position < self.config.max_file_size || writer.position <= LOG_HEADER_SIZE {
+            return Ok(());
+        }
+        self.sync_writer(writer)?;
+        let file_id = new_file_id(writer.file_id);
+        let log = create_log(&*self.backend, file_id, self.config.codec)?;
+
+        // Flushed data in the old file stays flushed, so only the switch itself needs to be
+        // atomic for readers
+        let mut readers = self.readers.write()?;
+        readers.insert(file_id, Arc::clone(&log));
+        self.active_file_id.store(file_id, Ordering::SeqCst);
+        self.flushed_position
+            .store(LOG_HEADER_SIZE, Ordering::SeqCst);
+        drop(readers);
+
+        writer.buf_writer = BufWriter::with_capacity(self.config.write_buffer_size, LogWriter(log));
+        writer.file_id = file_id;
+        writer.position = LOG_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn flush_buffer(&self, writer: &mut BufWriterWithPosition<LogWriter>) -> Result<()> {
+        writer.buf_writer.flush()?;
+        self.flushed_position
+            .store(writer.position, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn sync_writer(&self, writer: &mut BufWriterWithPosition<LogWriter>) -> Result<()> {
+        self.flush_buffer(writer)?;
+        if self.config.fsync {
+            writer.sync()?;
+        }
+        writer.writes_since_sync = 0;
+        writer.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Makes buffered writes visible to readers without counting as a sync for the policy
+    fn flush_writer(&self) -> Result<()> {
+        let mut writer = self.writer.lock()?;
+        self.flush_buffer(&mut writer)
+    }
+
+    fn read_value(codec: Codec, readers: &Readers, value_data: &ValueData) -> Result<Option<V>> {
+        Ok(KvStore::<K, V>::read_record(codec, readers, value_data)?.into_live_value(now_millis()))
+    }
+
+    fn read_record(
+        codec: Codec,
+        readers: &Readers,
+        value_data: &ValueData,
+    ) -> Result<KvRecord<K, V>> {
+        let reader = readers.get(&value_data.file_id).ok_or_else(|| {
+            KvsError::IOError(format!("log file {} is missing", value_data.file_id))
         })?;
-        let write_buf = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(&file_path)?;
+        let mut buf = vec![0u8; value_data.size];
+        reader.read_at(value_data.offset, &mut buf)?;
+        decode_record(codec, &buf, value_data.offset)
+    }
+
+    /// Replays every record in `log` from `start` through `f`, returning where the last one
+    /// ended. With `torn_tail`, a final record cut short by a crash mid-write ends the replay
+    /// instead of failing it. A record that is all there but fails its checksum is always an
+    /// error
+    fn deserialize_log(
+        log: &Arc<dyn LogStorage>,
+        file_id: u64,
+        codec: Codec,
+        start: u64,
+        torn_tail: bool,
+        mut f: impl FnMut(KvRecord<K, V>, ValueData) -> Result<()>,
+    ) -> Result<u64> {
+        let len = log.len()?;
+        let mut log_header = [0u8; LOG_HEADER_SIZE as usize];
+        if len < LOG_HEADER_SIZE {
+            return Err(KvsError::Corruption { offset: 0 });
+        }
+        log.read_at(0, &mut log_header)?;
+        if &log_header[..LOG_MAGIC.len()] != LOG_MAGIC {
+            return Err(KvsError::Corruption { offset: 0 });
+        }
+        if log_header[LOG_MAGIC.len()] != codec.id() {
+            return Err(KvsError::WrongCodec);
+        }
+        let mut reader = BufReader::new(LogReader::new(Arc::clone(log), start)?);
+        let mut framed = Vec::new();
+        let mut offset = start;
+        while offset < len {
+            if offset + RECORD_HEADER_SIZE as u64 > len {
+                return if torn_tail {
+                    Ok(offset)
+                } else {
+                    Err(KvsError::Corruption { offset })
+                };
+            }
+            let mut header = [0u8; RECORD_HEADER_SIZE];
+            reader.read_exact(&mut header)?;
+            let payload_len =
+                u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as u64;
+            // Check the length against the file before trusting it with an allocation
+            let size = RECORD_HEADER_SIZE as u64 + payload_len;
+            if offset + size > len {
+                return if torn_tail {
+                    Ok(offset)
+                } else {
+                    Err(KvsError::Corruption { offset })
+                };
+            }
+            framed.clear();
+            framed.extend_from_slice(&header);
+            framed.resize(size as usize, 0);
+            reader.read_exact(&mut framed[RECORD_HEADER_SIZE..])?;
+            let record = decode_record(codec, &framed, offset)?;
+            let value_data = ValueData {
+                file_id,
+                offset,
+                size: size as usize,
+                expires_at: record.expires_at(),
+                version: 0,
+            };
+            f(record, value_data)?;
+            offset += size;
+        }
+        Ok(offset)
+    }
+
+    /// Records `bytes` of newly dead log data, returning true once the total crosses the
+    /// configured compaction threshold
+    fn add_uncompressed_bytes(&self, bytes: u64) -> bool {
+        let total = self.uncompressed_bytes.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        total >= self.config.compaction_threshold
+    }
+
+    pub fn open(db_path: &Path) -> Result<KvStore<K, V>> {
+        KvStore::open_with_config(db_path, KvStoreConfig::default())
+    }
+
+    pub fn open_with_config(db_path: &Path, config: KvStoreConfig) -> Result<KvStore<K, V>> {
+        let data_dir = db_path.join(DATA_DIR);
+        fs::create_dir_all(&data_dir)?;
+        let manifest_version = check_manifest(db_path, config.codec)?;
+        let format_version = config.record_format.format_version();
+        migrate_flat_layout(db_path, &data_dir)?;
+        remove_staged_logs(&data_dir)?;
+        let codec = config.codec;
+        let backend = FileBackend::new(data_dir).mmap_reads(config.mmap_reads);
+        let store = KvStore::open_backend(Arc::new(backend), config)?;
+        // Only once the logs have loaded, so an older store opened with the wrong codec doesn't
+        // get a manifest vouching for it. Raised before any record needing the newer version is
+        // written, and never lowered, since older records stay readable
+        if manifest_version.is_none_or(|version| version < format_version) {
+            write_manifest(db_path, codec, format_version)?;
+        }
+        Ok(store)
+    }
+
+    /// `open`, with the index sized for `expected_keys` so a large store doesn't keep growing
+    /// it while replaying
+    pub fn open_with_capacity(db_path: &Path, expected_keys: usize) -> Result<KvStore<K, V>> {
+        KvStore::open_with_config(
+            db_path,
+            KvStoreConfig {
+                expected_keys: Some(expected_keys),
+                ..KvStoreConfig::default()
+            },
+        )
+    }
+
+    /// Opens an existing store without changing anything on disk: no directories or files are
+    /// created, a flat layout is read where it is, and writes fail with `KvsError::ReadOnly`.
+    /// Nothing is locked, so any number of processes can read the same store
+    pub fn open_read_only(db_path: &Path) -> Result<KvStore<K, V>> {
+        KvStore::open_read_only_with_config(db_path, KvStoreConfig::default())
+    }
+
+    pub fn open_read_only_with_config(
+        db_path: &Path,
+        config: KvStoreConfig,
+    ) -> Result<KvStore<K, V>> {
+        check_manifest(db_path, config.codec)?;
+        let (data_dir, file_ids) = existing_logs(db_path)?;
+        let backend = Arc::new(FileBackend::read_only(data_dir).mmap_reads(config.mmap_reads));
+        KvStore::load(backend, file_ids, config, true)
+    }
+
+    /// Opens a store that keeps its logs in memory instead of on disk. It behaves like any other,
+    /// including compaction and file rotation, but is gone once the last handle is dropped
+    pub fn open_in_memory() -> Result<KvStore<K, V>> {
+        KvStore::open_in_memory_with_config(KvStoreConfig::default())
+    }
+
+    pub fn open_in_memory_with_config(config: KvStoreConfig) -> Result<KvStore<K, V>> {
+        KvStore::open_with_storage(MemoryBackend::default(), config)
+    }
+
+    /// Opens a store over the logs in `backend`. Unlike `open`, nothing but the logs is read or
+    /// written: there is no manifest to check the codec against, and no older layout to migrate
+    pub fn open_with_storage(
+        backend: impl LogBackend + 'static,
+        config: KvStoreConfig,
+    ) -> Result<KvStore<K, V>> {
+        KvStore::open_backend(Arc::new(backend), config)
+    }
+
+    /// Loads the logs in `backend`, starting a first one if there are none
+    fn open_backend(backend: Arc<dyn LogBackend>, config: KvStoreConfig) -> Result<KvStore<K, V>> {
+        let mut file_ids = backend.log_ids()?;
+        if file_ids.is_empty() {
+            let file_id = new_file_id(0);
+            create_log(&*backend, file_id, config.codec)?;
+            file_ids.push(file_id);
+        }
+        KvStore::load(backend, file_ids, config, false)
+    }
+
+    /// Replays every log of the store at `db_path`, checking that each record's checksum matches
+    /// and that it decodes, without changing anything on disk. The codec comes from the
+    /// manifest, or for stores that predate it from the first log's header
+    pub fn validate(db_path: &Path) -> Result<ValidationReport> {
+        let (data_dir, file_ids) = existing_logs(db_path)?;
+        let codec = match read_manifest(db_path)? {
+            Some(manifest) => manifest.codec,
+            None => log_codec(&log_path(&data_dir, file_ids[0]))?,
+        };
+        let mut report = ValidationReport {
+            files: 0,
+            records: 0,
+            error: None,
+        };
+        let backend = FileBackend::read_only(data_dir.clone());
+        for file_id in file_ids {
+            let path = log_path(&data_dir, file_id);
+            report.files += 1;
+            let replayed = backend.open(file_id).and_then(|log| {
+                KvStore::<K, V>::deserialize_log(
+                    &log,
+                    file_id,
+                    codec,
+                    LOG_HEADER_SIZE,
+                    false,
+                    |_, _| {
+                        report.records += 1;
+                        Ok(())
+                    },
+                )
+            });
+            if let Err(e) = replayed {
+                report.error = Some((path, e));
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Replays `file_ids` from `backend` into a new index. The writer appends to the newest
+    fn load(
+        backend: Arc<dyn LogBackend>,
+        file_ids: Vec<u64>,
+        config: KvStoreConfig,
+        read_only: bool,
+    ) -> Result<KvStore<K, V>> {
+        let active_file_id = file_ids[file_ids.len() - 1];
+        let mut readers = Readers::new();
+        for file_id in file_ids {
+            let log = backend.open(file_id)?;
+            // A crash between creating the active log and writing its header leaves it empty
+            if file_id == active_file_id && !read_only && log.is_empty()? {
+                log.append(&log_header(config.codec))?;
+            }
+            readers.insert(file_id, log);
+        }
+
+        // Start from the saved index if there is a usable one, so only what was written after
+        // it needs replaying
+        let saved = if config.index_snapshots {
+            KvStore::<K, V>::saved_index(&*backend, config.codec, &readers).unwrap_or_else(
+                |reason| {
+                    log::warn!("Saved index is stale, replaying every log: {}", reason);
+                    None
+                },
+            )
+        } else {
+            None
+        };
+        let capacity = config
+            .expected_keys
+            .or_else(|| saved.as_ref().map(|saved| saved.entries.len()))
+            .unwrap_or(0);
+        let index = Arc::new(Index::with_capacity(config.index, capacity));
+        let mut last_version = 0;
+        let mut covered = HashMap::new();
+        // Bytes no longer referenced by the index, counted up as replay overwrites and removes
+        // keys so compaction is due as soon after a restart as it was before it
+        let mut dead_bytes = 0;
+        if let Some(saved) = saved {
+            // Whatever the saved index covered but doesn't point at is dead
+            dead_bytes = saved
+                .logs
+                .iter()
+                .map(|(_, len, _)| len.saturating_sub(LOG_HEADER_SIZE))
+                .sum::<u64>();
+            for (key, file_id, offset, size, expires_at) in saved.entries {
+                dead_bytes = dead_bytes.saturating_sub(size as u64);
+                last_version += 1;
+                let value_data = ValueData {
+                    file_id,
+                    offset,
+                    size,
+                    expires_at,
+                    version: last_version,
+                };
+                index.insert(key, value_data);
+            }
+            covered.extend(
+                saved
+                    .logs
+                    .into_iter()
+                    .map(|(file_id, len, _)| (file_id, len)),
+            );
+        }
+
+        // Replaying oldest first means later records win, across files as well as within them
+        for (&file_id, log) in &readers {
+            let start = covered.get(&file_id).copied().unwrap_or(LOG_HEADER_SIZE);
+            // Older logs were synced before the next was started, so only the active one can
+            // have been cut off mid-write
+            let replayed = KvStore::deserialize_log(
+                log,
+                file_id,
+                config.codec,
+                start,
+                file_id == active_file_id,
+                |deserialized: KvRecord<K, V>, mut value_data| {
+                    last_version += 1;
+                    value_data.version = last_version;
+                    let previous = match deserialized {
+                        KvRecord::Set(kv) => index.insert(kv.0, value_data),
+                        KvRecord::SetExpiring(kve) => index.insert(kve.0, value_data),
+                        KvRecord::SetWithMeta(kvm) => index.insert(kvm.0, value_data),
+                        KvRecord::Rm(key) => {
+                            dead_bytes += value_data.size as u64;
+                            index.remove(&key)
+                        }
+                    };
+                    if let Some(previous) = previous {
+                        dead_bytes += previous.size as u64;
+                    }
+                    Ok(())
+                },
+            )?;
+            let len = log.len()?;
+            if replayed < len {
+                log::warn!(
+                    "Dropping {} bytes of a partly written record at offset {} of log {}",
+                    len - replayed,
+                    replayed,
+                    file_id
+                );
+                // A read-only store leaves the file alone and just doesn't replay the tail
+                if !read_only {
+                    log.truncate(replayed)?;
+                }
+            }
+        }
+        let active_log = Arc::clone(&readers[&active_file_id]);
+        let position = active_log.len()?;
         Ok(KvStore {
-            path: Arc::new(db_path.to_path_buf()),
+            backend,
             index,
-            reader: Arc::new(RwLock::new(OpenOptions::new().read(true).open(&file_path)?)),
+            readers: Arc::new(RwLock::new(readers)),
             writer: Arc::new(Mutex::new(BufWriterWithPosition {
-                path: file_path,
-                position: (write_buf.metadata()?.len()),
-                buf_writer: BufWriter::new(write_buf),
+                file_id: active_file_id,
+                position,
+                buf_writer: BufWriter::with_capacity(
+                    config.write_buffer_size,
+                    LogWriter(active_log),
+                ),
+                writes_since_sync: 0,
+                last_sync: Instant::now(),
+                last_index_save: Instant::now(),
             })),
-            uncompressed_bytes: AtomicU64::new(0),
+            commit_gate: Arc::new(RwLock::new(())),
+            key_locks: Arc::new((0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect()),
+            active_file_id: Arc::new(AtomicU64::new(active_file_id)),
+            flushed_position: Arc::new(AtomicU64::new(position)),
+            uncompressed_bytes: Arc::new(AtomicU64::new(dead_bytes)),
+            compactions: Arc::new(AtomicU64::new(0)),
+            index_save_due: Arc::new(AtomicBool::new(false)),
+            last_version: Arc::new(AtomicU64::new(last_version)),
+            config: Arc::new(config),
+            merge_operator: None,
+            read_only,
+            watchers: Arc::new(Watchers::new()),
             phantom: PhantomData,
         })
     }
 
-    fn compact_file(&self) -> Result<()> {
-        let mut value_map = HashMap::new();
-        let new_path = get_new_file_path(&self.path);
-        let mut new_file = fs::File::create(&new_path)?;
-        let mut writer = self.writer.lock()?;
-        KvStore::deserialize_file(&writer.path,
-            |deserialized: KvRecord<K, V>, _| match deserialized {
-                KvRecord::Set(kv) => {
-                    value_map.insert(kv.0, kv.1);
+    /// The index the backend saved, if it has one, or why it no longer matches the logs
+    fn saved_index(
+        backend: &dyn LogBackend,
+        codec: Codec,
+        readers: &Readers,
+    ) -> std::result::Result<Option<SavedIndex<K>>, String> {
+        match backend.load_snapshot().map_err(|e| e.to_string())? {
+            Some(bytes) => decode_saved_index(codec, &bytes, readers).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Saves the index for the next open. The caller must hold the writer with no single-key
+    /// write part way to the index, as the last handle or through `lock_exclusive`, so the
+    /// index is in step with the logs
+    fn save_index(&self, writer: &mut BufWriterWithPosition<LogWriter>) -> Result<()> {
+        self.flush_buffer(writer)?;
+        writer.last_index_save = Instant::now();
+        let readers = self.readers.read()?;
+        let logs = readers
+            .iter()
+            .map(|(file_id, log)| {
+                let len = log.len()?;
+                Ok((*file_id, len, log_tail_checksum(&**log, len)?))
+            })
+            .collect::<Result<_>>()?;
+        let entries = self
+            .index
+            .entries(Bound::Unbounded, Bound::Unbounded, |_| true)
+            .into_iter()
+            .map(|(key, value_data)| {
+                (
+                    key,
+                    value_data.file_id,
+                    value_data.offset,
+                    value_data.size,
+                    value_data.expires_at,
+                )
+            })
+            .collect();
+        drop(readers);
+        let bytes = encode_saved_index(self.config.codec, &SavedIndex { logs, entries })?;
+        self.backend.save_snapshot(&bytes)
+    }
+
+    /// Saves the index if an append found `index_snapshot_interval` had passed. Called once a
+    /// write has let go of its locks, since saving waits for every write in flight. The write
+    /// itself has already landed, so a failed save is only logged
+    fn save_index_if_due(&self) {
+        if !self.index_save_due.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        let saved = self
+            .lock_exclusive()
+            .and_then(|mut writer| self.save_index(&mut writer));
+        if let Err(e) = saved {
+            log::warn!("Error saving index: {:?}", e);
+        }
+    }
+
+    /// Writes every live pair to a single file at `out`, which `restore` can rebuild a store
+    /// from. Writes are held off while it runs, so the backup is a consistent point in time
+    pub fn backup(&self, out: &Path) -> Result<()> {
+        let mut writer = self.lock_exclusive()?;
+        self.flush_buffer(&mut writer)?;
+        let readers = self.readers.read()?;
+        let mut backup = BufWriter::new(File::create(out)?);
+        backup.write_all(BACKUP_MAGIC)?;
+        backup.write_all(&[BACKUP_VERSION, self.config.codec.id()])?;
+        let mut buf = Vec::new();
+        for (_, value_data) in self
+            .index
+            .entries(Bound::Unbounded, Bound::Unbounded, |_| true)
+        {
+            let reader = readers.get(&value_data.file_id).ok_or_else(|| {
+                KvsError::IOError(format!("log file {} is missing", value_data.file_id))
+            })?;
+            buf.resize(value_data.size, 0);
+            reader.read_at(value_data.offset, &mut buf)?;
+            backup.write_all(&buf)?;
+        }
+        backup.flush()?;
+        backup.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Builds a new store in `into` from a file written by `backup`. The store uses the codec
+    /// the backup was taken with and is opened with the default config otherwise
+    pub fn restore(from: &Path, into: &Path) -> Result<KvStore<K, V>> {
+        let mut backup = BufReader::new(File::open(from)?);
+        let mut header = [0u8; BACKUP_MAGIC.len() + 2];
+        backup
+            .read_exact(&mut header)
+            .map_err(|_| KvsError::Corruption { offset: 0 })?;
+        if &header[..BACKUP_MAGIC.len()] != BACKUP_MAGIC {
+            return Err(KvsError::Corruption { offset: 0 });
+        }
+        let version = header[BACKUP_MAGIC.len()];
+        if version != BACKUP_VERSION {
+            return Err(KvsError::SerializationError(format!(
+                "unsupported backup version {}",
+                version
+            )));
+        }
+        let codec = Codec::from_id(header[BACKUP_MAGIC.len() + 1])?;
+
+        let data_dir = into.join(DATA_DIR);
+        fs::create_dir_all(&data_dir)?;
+        if !log_file_ids(into)?.is_empty() || !log_file_ids(&data_dir)?.is_empty() {
+            return Err(KvsError::IOError(format!(
+                "{} already holds a store",
+                into.display()
+            )));
+        }
+        // The records are already framed, so they go straight into a log file. Opening the store
+        // replays them, which checks every checksum on the way
+        let mut log = create_log_file(&log_path(&data_dir, new_file_id(0)), codec)?;
+        std::io::copy(&mut backup, &mut log)?;
+        log.sync_all()?;
+        KvStore::open_with_config(
+            into,
+            KvStoreConfig {
+                codec,
+                ..KvStoreConfig::default()
+            },
+        )
+    }
+
+    /// Opens the store with `merge_operator` registered for use by `merge`
+    pub fn open_with_merge_operator<F>(
+        db_path: &Path,
+        config: KvStoreConfig,
+        merge_operator: F,
+    ) -> Result<KvStore<K, V>>
+    where
+        F: Fn(Option<&V>, &V) -> V + Send + Sync + 'static,
+    {
+        let mut store = KvStore::open_with_config(db_path, config)?;
+        store.merge_operator = Some(Arc::new(merge_operator));
+        Ok(store)
+    }
+
+    /// Rewrites the logs into a single new file that only contains the records currently
+    /// referenced by the index, then swaps the writer, readers and index over to it
+    pub fn compact_file(&self) -> Result<()> {
+        // Holding the writer and the commit gate for the whole compaction keeps `set` and
+        // `remove` from appending to the old files or touching the index until the swap is
+        // complete
+        let mut writer = self.lock_writer()?;
+        self.flush_buffer(&mut writer)?;
+        let old_logs = self.readers.read()?.clone();
+        let new_file_id = new_file_id(writer.file_id);
+        // The new log is built aside and only published once it is complete and synced. A
+        // crash before then leaves just the old logs, and after it the new log holds the same
+        // values as them and is replayed last, so either way the store reopens as it was
+        let new_log = self.backend.create_staged(new_file_id)?;
+        let written = self
+            .write_compacted(&old_logs, new_file_id, &new_log)
+            .and_then(|written| {
+                self.backend.publish(new_file_id)?;
+                Ok(written)
+            });
+        let (new_file, mut new_index, next_offset) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                // The publish may have failed after its rename. A published log left in place
+                // would be replayed after everything written to the old logs from here on
+                if self.backend.discard_staged(new_file_id).is_err() {
+                    if let Err(remove_error) = self.backend.remove(new_file_id) {
+                        log::warn!("Error discarding unfinished log: {:?}", remove_error);
+                    }
                 }
-                KvRecord::Rm(k) => {
-                    // I dont think this should ever happen, but just to be sure
-                    value_map.remove(&k);
+                return Err(e);
+            }
+        };
+
+        // Readers take the readers lock before looking up an offset, so swapping the files and
+        // the offsets under the write lock means no `get` can pair an old offset with a new file
+        let mut readers = self.readers.write()?;
+        *readers = Readers::from([(new_file_id, new_log)]);
+        self.index
+            .retain(|key, value_data| match new_index.remove(key) {
+                Some(new_value_data) => {
+                    *value_data = new_value_data;
+                    true
                 }
-            },
-        )?;
-        let mut next_offset = 0;
-        let mut new_index = HashMap::new();
-        for (key, val) in value_map {
-            let serialized = rmp_serde::to_vec(&KvRecord::Set((key.clone(), val)))?;
-            let value_data = ValueData {
-                offset: next_offset,
-                size: serialized.len(),
-            };
-            new_index.insert(key, value_data);
-            new_file.write_all(&serialized)?;
-            new_file.flush()?;
-            next_offset += serialized.len() as u64;
-        }
-        let old_path = writer.path.clone();
-        writer.buf_writer = BufWriter::new(new_file);
+                None => false,
+            });
+        self.active_file_id.store(new_file_id, Ordering::SeqCst);
+        self.flushed_position.store(next_offset, Ordering::SeqCst);
+        drop(readers);
+
+        writer.buf_writer = BufWriter::with_capacity(
+            self.config.write_buffer_size,
+            new_file.into_inner().map_err(|e| e.into_error())?,
+        );
+        writer.file_id = new_file_id;
         writer.position = next_offset;
-        writer.path = new_path.clone();
+        writer.writes_since_sync = 0;
+        writer.last_sync = Instant::now();
         self.uncompressed_bytes.store(0, Ordering::SeqCst);
-        let mut reader = self.reader.write()?;
-        *reader = OpenOptions::new().read(true).open(&new_path)?;
-        new_index.clear();
-        for (key, value) in new_index {
-            self.index.insert(key, value);
+        self.compactions.fetch_add(1, Ordering::SeqCst);
+        for file_id in old_logs.keys() {
+            self.backend.remove(*file_id)?;
+        }
+        // The saved index pointed into the logs just removed, so without a new one a crash
+        // would leave the next open replaying everything
+        if self.config.index_snapshots {
+            if let Err(e) = self.save_index(&mut writer) {
+                log::warn!("Error saving index after compaction: {:?}", e);
+            }
         }
-        fs::remove_file(&old_path)?;
         Ok(())
     }
+
+    /// Copies the live records of `old_logs` into the staged log `new_log` and syncs it.
+    /// Returns the writer over the new log, the index entries pointing into it and its length
+    #[allow(clippy::type_complexity)]
+    fn write_compacted(
+        &self,
+        old_logs: &Readers,
+        new_file_id: u64,
+        new_log: &Arc<dyn LogStorage>,
+    ) -> Result<(BufWriter<LogWriter>, HashMap<K, ValueData>, u64)> {
+        new_log.append(&log_header(self.config.codec))?;
+        let mut new_file = BufWriter::new(LogWriter(Arc::clone(new_log)));
+        let mut new_index = HashMap::new();
+        let mut next_offset = LOG_HEADER_SIZE;
+        let now = now_millis();
+        for (file_id, log) in old_logs {
+            KvStore::deserialize_log(
+                log,
+                *file_id,
+                self.config.codec,
+                LOG_HEADER_SIZE,
+                false,
+                |deserialized: KvRecord<K, V>, value_data| {
+                    if let KvRecord::Rm(_) = deserialized {
+                        return Ok(());
+                    }
+                    // Expired records are left behind, so retain below drops their keys
+                    let live_version = self
+                        .index
+                        .get(deserialized.key())
+                        .filter(|entry| {
+                            !value_data.is_expired(now)
+                                && entry.file_id == value_data.file_id
+                                && entry.offset == value_data.offset
+                        })
+                        .map(|entry| entry.version);
+                    if let Some(version) = live_version {
+                        let serialized = encode_record(&self.config, &deserialized)?;
+                        new_file.write_all(&serialized)?;
+                        new_index.insert(
+                            deserialized.key().clone(),
+                            ValueData {
+                                file_id: new_file_id,
+                                offset: next_offset,
+                                size: serialized.len(),
+                                expires_at: value_data.expires_at,
+                                version,
+                            },
+                        );
+                        next_offset += serialized.len() as u64;
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+        new_file.flush()?;
+        new_log.sync()?;
+        Ok((new_file, new_index, next_offset))
+    }
+}
+
+impl<K, V> Drop for KvStore<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    fn drop(&mut self) {
+        // Clones share the writer, so only the last handle does the (optional) compaction. Every
+        // handle flushes though, since that is cheap and harmless while others are still writing
+        if self.config.compact_on_drop
+            && !self.read_only
+            && Arc::strong_count(&self.writer) == 1
+            && self.uncompressed_bytes.load(Ordering::SeqCst) > 0
+        {
+            if let Err(e) = self.compact_file() {
+                log::warn!("Error compacting store when dropped: {:?}", e);
+            }
+        }
+        match self.writer.lock() {
+            Ok(mut writer) => {
+                if let Err(e) = self.sync_writer(&mut writer) {
+                    log::warn!("Error flushing store when dropped: {:?}", e);
+                }
+                if self.config.index_snapshots
+                    && !self.read_only
+                    && Arc::strong_count(&self.writer) == 1
+                {
+                    if let Err(e) = self.save_index(&mut writer) {
+                        log::warn!("Error saving index when dropped: {:?}", e);
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("Writer lock poisoned when dropping store: {:?}", e);
+            }
+        }
+    }
+}
+
+impl<K, V> KvStore<K, V>
+where
+    K: Key,
+    V: Value + PartialEq,
+{
+    /// Sets `key` to `new` only if its current value is `expected`, with `None` meaning the key
+    /// is absent, and returns whether the swap happened. The comparison and the write both
+    /// happen under the key's lock, so racing swaps on the same key can't both succeed
+    pub fn compare_and_swap(&self, key: K, expected: Option<V>, new: V) -> Result<bool> {
+        let key_guard = self.lock_key(&key)?;
+        if self.get_locked(&key_guard, &key)? != expected {
+            return Ok(false);
+        }
+        let record = KvRecord::Set((key, new)).stamped(now_millis());
+        let serialized = self.encode_new(&record)?;
+        self.commit_set(key_guard, &record, &serialized)?;
+        Ok(true)
+    }
 }
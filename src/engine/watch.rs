@@ -0,0 +1,71 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+use super::store::Key;
+
+/// A change to a watched key, delivered by `KvsEngine::watch`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent<K, V> {
+    Set(K, V),
+    Removed(K),
+}
+
+impl<K, V> WatchEvent<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            WatchEvent::Set(key, _) | WatchEvent::Removed(key) => key,
+        }
+    }
+}
+
+// A watched prefix and where its events go
+type Watcher<K, V> = (K, Sender<WatchEvent<K, V>>);
+
+/// The subscribers an engine notifies as writes land. Ones whose receiver has been dropped are
+/// forgotten at the next matching write
+pub(crate) struct Watchers<K, V> {
+    watchers: Mutex<Vec<Watcher<K, V>>>,
+}
+
+impl<K: Key, V: Clone> Watchers<K, V> {
+    pub(crate) fn new() -> Watchers<K, V> {
+        Watchers {
+            watchers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn watch(&self, prefix: K) -> Receiver<WatchEvent<K, V>> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock().push((prefix, sender));
+        receiver
+    }
+
+    /// Tells everyone watching a prefix of `key` that it was set to `value`, or removed if that
+    /// is `None`. Engines call this while still holding whatever orders their writes, so events
+    /// arrive in the order the writes landed
+    pub(crate) fn notify(&self, key: &K, value: Option<&V>) {
+        let mut watchers = self.lock();
+        if watchers.is_empty() {
+            return;
+        }
+        watchers.retain(|(prefix, sender)| {
+            if !key.has_prefix(prefix) {
+                return true;
+            }
+            let event = match value {
+                Some(value) => WatchEvent::Set(key.clone(), value.clone()),
+                None => WatchEvent::Removed(key.clone()),
+            };
+            sender.send(event).is_ok()
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Watcher<K, V>>> {
+        // Nothing is left half done if a watcher panics, so a poisoned lock is still usable
+        self.watchers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 pub type Result<T> = std::result::Result<T, KvsError>;
 
@@ -6,13 +7,95 @@ pub type Result<T> = std::result::Result<T, KvsError>;
 pub enum KvsError {
     FileListEmpty,
     WrongEngine,
+    CorruptConfig(String),
+    WrongCodec,
+    IncompatibleFormat(String),
     SerializationError(String),
     IOError(String),
     NonExistantKey,
+    Corruption { offset: u64 },
     ThreadPoolBuildError(String),
+    Compression(String),
+    NoMergeOperator,
+    QueueFull,
+    TooManyConnections,
+    PoolStopped,
+    ConnectionFailed(String),
+    ReadOnly,
+    KeyTooLarge,
+    ValueTooLarge,
+    PermissionDenied,
+    Unauthorized,
+    Unsupported,
+    Conflict,
     Other,
 }
 
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::FileListEmpty => write!(f, "no log files found"),
+            KvsError::WrongEngine => write!(f, "data directory belongs to a different engine"),
+            KvsError::CorruptConfig(msg) => write!(f, "corrupt config: {}", msg),
+            KvsError::WrongCodec => write!(f, "log was written with a different codec"),
+            KvsError::IncompatibleFormat(msg) => write!(f, "incompatible store format: {}", msg),
+            KvsError::SerializationError(msg) => write!(f, "serialization error: {}", msg),
+            KvsError::IOError(msg) => write!(f, "I/O error: {}", msg),
+            KvsError::NonExistantKey => write!(f, "Key not found"),
+            KvsError::Corruption { offset } => write!(f, "corrupt record at offset {}", offset),
+            KvsError::ThreadPoolBuildError(msg) => {
+                write!(f, "failed to build thread pool: {}", msg)
+            }
+            KvsError::Compression(msg) => write!(f, "compression error: {}", msg),
+            KvsError::NoMergeOperator => write!(f, "no merge operator registered"),
+            KvsError::QueueFull => write!(f, "thread pool queue is full"),
+            KvsError::TooManyConnections => write!(f, "server is at its connection limit"),
+            KvsError::PoolStopped => write!(f, "thread pool has been shut down"),
+            KvsError::ConnectionFailed(msg) => write!(f, "could not connect: {}", msg),
+            KvsError::ReadOnly => write!(f, "store was opened read-only"),
+            KvsError::KeyTooLarge => write!(f, "key is over the size limit"),
+            KvsError::ValueTooLarge => write!(f, "value is over the size limit"),
+            KvsError::PermissionDenied => write!(f, "not permitted on this server"),
+            KvsError::Unauthorized => write!(f, "missing or wrong auth token"),
+            KvsError::Unsupported => write!(f, "not supported by this engine"),
+            KvsError::Conflict => write!(f, "a key read by the transaction has since changed"),
+            KvsError::Other => write!(f, "unknown error"),
+        }
+    }
+}
+
+impl KvsError {
+    /// The protocol error code this error is reported under
+    pub fn code(&self) -> protocol::ErrorCode {
+        use protocol::ErrorCode;
+        match self {
+            KvsError::NonExistantKey => ErrorCode::KeyNotFound,
+            KvsError::Corruption { .. } | KvsError::Compression(_) => ErrorCode::Corruption,
+            KvsError::IOError(_) => ErrorCode::Io,
+            KvsError::SerializationError(_) => ErrorCode::Serialization,
+            KvsError::FileListEmpty
+            | KvsError::WrongEngine
+            | KvsError::CorruptConfig(_)
+            | KvsError::WrongCodec
+            | KvsError::IncompatibleFormat(_) => ErrorCode::Config,
+            KvsError::NoMergeOperator | KvsError::ReadOnly | KvsError::Unsupported => {
+                ErrorCode::Unsupported
+            }
+            KvsError::Conflict => ErrorCode::Conflict,
+            KvsError::QueueFull | KvsError::TooManyConnections => ErrorCode::Busy,
+            KvsError::ConnectionFailed(_) | KvsError::PoolStopped => ErrorCode::Unavailable,
+            KvsError::KeyTooLarge | KvsError::ValueTooLarge => ErrorCode::TooLarge,
+            KvsError::PermissionDenied => ErrorCode::PermissionDenied,
+            KvsError::Unauthorized => ErrorCode::Unauthorized,
+            KvsError::ThreadPoolBuildError(_) | KvsError::Other => ErrorCode::Internal,
+        }
+    }
+}
+
+// The underlying errors are flattened to strings so the error can cross the wire, which means
+// there is no source to hand back
+impl std::error::Error for KvsError {}
+
 impl From<serde_json::Error> for KvsError {
     fn from(serde_err: serde_json::Error) -> Self {
         KvsError::SerializationError(serde_err.to_string())
@@ -32,21 +115,298 @@ impl From<rayon::ThreadPoolBuildError> for KvsError {
 }
 
 pub mod protocol {
+    use crate::engine::store::{KvRecord, KvStoreStats};
     use crate::Result;
+    use serde::de::DeserializeOwned;
     use serde::{Deserialize, Serialize};
+    use std::fmt::{self, Write as _};
+    use std::io::{self, Read, Write};
+
+    /// The error carried in responses. It is the crate's own error type, re-exported so protocol
+    /// users only need the one import
+    pub use crate::KvsError as KvError;
 
     #[derive(Serialize, Deserialize, Debug)]
     pub enum KvRequest<K, V> {
         Set((K, V)),
         Rm(K),
         Get(K),
+        /// Looks up every key in one round trip. The answers come back in `values`, in the same
+        /// order as the keys
+        GetMany(Vec<K>),
+        /// Every pair whose key starts with the given one, in key order. The pairs come back in
+        /// `pairs`
+        ScanPrefix(K),
+        /// Compacts the store. An admin request, refused unless the server allows them
+        Compact,
+        /// The engine's counters. They come back in `stats`
+        Stats,
+        /// Subscribes to changes of every key starting with the given one. After the usual
+        /// response the connection carries nothing but `WatchEvent` frames, until either side
+        /// closes it
+        Watch(K),
+        /// Reads a key along with its version. The version comes back in `version`
+        GetVersioned(K),
+        /// Applies the writes only if every key read is still at the given version, failing with
+        /// `Conflict` otherwise
+        Commit {
+            reads: Vec<(K, u64)>,
+            writes: Vec<KvRecord<K, V>>,
+        },
+        /// Gives the server's auth token. A server started with one refuses every other request
+        /// with `Unauthorized` until the connection has sent it
+        Auth(String),
+        /// Checks the server is up. Answered without touching the engine and without needing
+        /// to authenticate, so health checks can poll it cheaply
+        Ping,
+    }
+
+    impl<K, V> KvRequest<K, V> {
+        /// Whether the request leaves the store as it was, so sending it twice is harmless
+        pub fn is_read_only(&self) -> bool {
+            matches!(
+                self,
+                KvRequest::Get(_)
+                    | KvRequest::GetMany(_)
+                    | KvRequest::ScanPrefix(_)
+                    | KvRequest::Stats
+                    | KvRequest::GetVersioned(_)
+                    | KvRequest::Ping
+            )
+        }
     }
 
+    /// `K` is only needed for `ScanPrefix` responses, so it defaults to `String` to keep the
+    /// common `KvResponse<V>` spelling working
     #[derive(Serialize, Deserialize, Debug)]
-    pub struct KvResponse<V> {
+    pub struct KvResponse<V, K = String> {
         pub value: Result<Option<V>>,
+        /// Filled in whenever `value` is an error, so clients can branch on the code without
+        /// decoding `KvError` itself
+        #[serde(default)]
+        pub error: Option<ResponseError>,
+        /// The answers to a `GetMany`. A plain `default` would make serde require `V: Default`
+        #[serde(default = "Option::default")]
+        pub values: Option<Vec<Option<V>>>,
+        /// The answer to a `ScanPrefix`
+        #[serde(default = "Option::default")]
+        pub pairs: Option<Vec<(K, V)>>,
+        /// The answer to a `Stats`
+        #[serde(default)]
+        pub stats: Option<KvStoreStats>,
+        /// The version of the key a `GetVersioned` read
+        #[serde(default)]
+        pub version: Option<u64>,
+    }
+
+    impl<V, K> KvResponse<V, K> {
+        pub fn new(value: Result<Option<V>>) -> KvResponse<V, K> {
+            let error = value.as_ref().err().map(|e| ResponseError {
+                code: e.code(),
+                message: e.to_string(),
+            });
+            KvResponse {
+                value,
+                error,
+                values: None,
+                pairs: None,
+                stats: None,
+                version: None,
+            }
+        }
+
+        /// Response to a `GetMany`
+        pub fn many(values: Result<Vec<Option<V>>>) -> KvResponse<V, K> {
+            match values {
+                Ok(values) => KvResponse {
+                    values: Some(values),
+                    ..KvResponse::new(Ok(None))
+                },
+                Err(e) => KvResponse::new(Err(e)),
+            }
+        }
+
+        /// Response to a `ScanPrefix`
+        pub fn pairs(pairs: Result<Vec<(K, V)>>) -> KvResponse<V, K> {
+            match pairs {
+                Ok(pairs) => KvResponse {
+                    pairs: Some(pairs),
+                    ..KvResponse::new(Ok(None))
+                },
+                Err(e) => KvResponse::new(Err(e)),
+            }
+        }
+
+        /// Response to a `GetVersioned`
+        pub fn versioned(read: Result<(Option<V>, u64)>) -> KvResponse<V, K> {
+            match read {
+                Ok((value, version)) => KvResponse {
+                    version: Some(version),
+                    ..KvResponse::new(Ok(value))
+                },
+                Err(e) => KvResponse::new(Err(e)),
+            }
+        }
+
+        /// Response to a `Stats`
+        pub fn stats(stats: Result<KvStoreStats>) -> KvResponse<V, K> {
+            match stats {
+                Ok(stats) => KvResponse {
+                    stats: Some(stats),
+                    ..KvResponse::new(Ok(None))
+                },
+                Err(e) => KvResponse::new(Err(e)),
+            }
+        }
+    }
+
+    /// Formats the wrapped value's `Debug` output cut down to its first `TRUNCATED_LEN` bytes
+    /// and the full length, so logging a request or response with a huge value stays cheap
+    pub struct Truncated<'a, T>(pub &'a T);
+
+    /// How much of a value's `Debug` output `Truncated` keeps
+    pub const TRUNCATED_LEN: usize = 64;
+
+    impl<T: fmt::Debug> fmt::Debug for Truncated<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut head = Head {
+                kept: String::new(),
+                len: 0,
+            };
+            write!(head, "{:?}", self.0)?;
+            if head.len <= TRUNCATED_LEN {
+                f.write_str(&head.kept)
+            } else {
+                write!(f, "{}... ({} bytes)", head.kept, head.len)
+            }
+        }
+    }
+
+    // Keeps the first `TRUNCATED_LEN` bytes written to it and counts the rest
+    struct Head {
+        kept: String,
+        len: usize,
+    }
+
+    impl fmt::Write for Head {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            // Once anything has been dropped, everything after it is too
+            if self.kept.len() == self.len {
+                for c in s.chars() {
+                    if self.kept.len() + c.len_utf8() > TRUNCATED_LEN {
+                        break;
+                    }
+                    self.kept.push(c);
+                }
+            }
+            self.len += s.len();
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct ResponseError {
+        pub code: ErrorCode,
+        pub message: String,
+    }
+
+    /// Stable, machine-readable error categories. These go over the wire as snake_case strings,
+    /// and unlike `KvError` variants they won't be renamed or gain payloads
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ErrorCode {
+        KeyNotFound,
+        /// Stored data failed its checksum or couldn't be decoded
+        Corruption,
+        Io,
+        /// A request or value couldn't be encoded or decoded
+        Serialization,
+        /// The data directory doesn't match the engine or codec it was opened with
+        Config,
+        /// The request needs something the server wasn't set up with
+        Unsupported,
+        /// The server is too busy to take the request right now
+        Busy,
+        Unavailable,
+        /// A key, value or whole request is over the server's size limit
+        TooLarge,
+        /// The server doesn't allow this request
+        PermissionDenied,
+        /// The server needs an auth token, and the connection hasn't given the right one
+        Unauthorized,
+        /// A transaction lost to a conflicting write and should be retried
+        Conflict,
+        Internal,
+    }
+
+    /// Longest frame `read_frame` accepts, and the server's default request limit
+    pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+    /// Writes `message` as a single frame: its JSON encoding prefixed by the length in bytes as
+    /// a big-endian u32. Frames are self-delimiting, so any number can share a connection
+    pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+        // One write for the whole frame, so Nagle's algorithm doesn't hold the payload back
+        // waiting on an ACK for the length
+        writer.write_all(&encode_frame(message)?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The bytes `write_frame` would write for `message`, for writers that aren't `Write`
+    pub fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+        let payload = serde_json::to_vec(message)?;
+        let len = u32::try_from(payload.len())
+            .map_err(|_| KvError::SerializationError("frame too large".to_owned()))?;
+        let mut frame = Vec::with_capacity(4 + payload.len());
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&payload);
+        Ok(frame)
+    }
+
+    /// Reads one frame written by `write_frame`, up to `DEFAULT_MAX_FRAME_SIZE` long. Returns
+    /// `None` if the stream ends cleanly before the next frame starts
+    pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+        read_frame_limited(reader, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Like `read_frame`, but fails with `ValueTooLarge` instead of allocating for a frame
+    /// longer than `max_len`. The frame is left unread, so the stream can't be used after that
+    pub fn read_frame_limited<R: Read, T: DeserializeOwned>(
+        reader: &mut R,
+        max_len: usize,
+    ) -> Result<Option<T>> {
+        let mut len = [0u8; 4];
+        let mut filled = 0;
+        while filled < len.len() {
+            match reader.read(&mut len[filled..]) {
+                // Only an end before any of the length is a clean one
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ended part way through a frame length",
+                    )
+                    .into())
+                }
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > max_len {
+            return Err(KvError::ValueTooLarge);
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(serde_json::from_slice(&payload)?))
     }
 }
 
+#[cfg(feature = "tokio-client")]
+pub mod async_client;
+pub mod client;
 pub mod engine;
+pub mod server;
 pub mod thread_pool;
+pub mod transport;
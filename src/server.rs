@@ -0,0 +1,477 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{self, Read};
+use std::ops::Bound;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::engine::store::{Key, Value};
+use crate::engine::watch::WatchEvent;
+use crate::engine::KvsEngine;
+use crate::protocol::{
+    read_frame_limited, write_frame, KvRequest, KvResponse, Truncated, DEFAULT_MAX_FRAME_SIZE,
+};
+use crate::thread_pool::ThreadPool;
+use crate::transport::{Listener, Stream};
+use crate::{KvsError, Result};
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Bounds how long the accept loop can spend telling a refused client why
+const REFUSAL_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+// How often a watching connection checks whether the client is gone or the server is stopping
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// How often a connection waiting for its next request checks whether the server is stopping
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Log lines for a request are tagged `[connection.request]`, so interleaved lines from
+// concurrent connections can be told apart
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Settings for `serve_with_config`
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Longest request frame the server will read. Anything longer gets a `ValueTooLarge`
+    /// error and the connection is closed, since the rest of the frame is never read
+    pub max_frame_size: usize,
+    /// Whether admin requests such as `Compact` are served. Off by default, since any client
+    /// could otherwise start an expensive operation
+    pub allow_admin: bool,
+    /// How long each request has to arrive in full, counted from when the server starts
+    /// waiting for it. The connection is closed once it runs out, so a client that stalls or
+    /// trickles a request in a byte at a time can't hold a worker forever. `None` waits
+    /// indefinitely
+    pub read_timeout: Option<Duration>,
+    /// Token each connection must send in an `Auth` request before anything else is served.
+    /// `None` serves every connection straight away
+    pub auth_token: Option<AuthToken>,
+    /// Most connections served at once. Connections past it are sent `TooManyConnections` and
+    /// closed straight away. `None` accepts any number
+    pub max_connections: Option<usize>,
+    /// How long shutdown waits for requests in flight. Idle connections close as soon as the
+    /// server starts stopping, so this only has to cover requests already being served
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            allow_admin: false,
+            read_timeout: Some(Duration::from_secs(30)),
+            auth_token: None,
+            max_connections: None,
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A shared secret clients authenticate with. It is left out of `Debug` output, so configs
+/// holding one can be logged
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken(String);
+
+impl AuthToken {
+    pub fn new(token: impl Into<String>) -> AuthToken {
+        AuthToken(token.into())
+    }
+
+    /// Compares every byte whatever the first mismatch, so the time taken gives away nothing
+    /// about how much of `given` is right
+    fn matches(&self, given: &str) -> bool {
+        let (expected, given) = (self.0.as_bytes(), given.as_bytes());
+        expected.len() == given.len()
+            && expected
+                .iter()
+                .zip(given)
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
+
+impl FromStr for AuthToken {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(AuthToken::new(s))
+    }
+}
+
+/// Answers requests on `stream` until the client closes its end or takes longer than the read
+/// timeout to send a request. A `Watch` request keeps the connection until the client closes it
+pub fn handle_connection<K, V, E>(
+    stream: impl Into<Stream>,
+    store: E,
+    config: &ServerConfig,
+) -> Result<()>
+where
+    K: Key,
+    V: Value,
+    E: KvsEngine<K, V>,
+{
+    let stopping = AtomicBool::new(false);
+    match serve_connection(
+        next_connection_id(),
+        stream.into(),
+        store,
+        config,
+        &stopping,
+    )? {
+        Some(subscription) => stream_events(subscription, &stopping),
+        None => Ok(()),
+    }
+}
+
+/// A connection given over to a `Watch`, which only carries its events from here on
+struct Subscription<K, V> {
+    stream: Stream,
+    events: Receiver<WatchEvent<K, V>>,
+}
+
+/// `handle_connection`, also ending once `stopping` is set and the connection is between
+/// requests. A `Watch` ends the requests, handing the connection back to be fed its events
+fn serve_connection<K, V, E>(
+    connection_id: u64,
+    mut stream: Stream,
+    store: E,
+    config: &ServerConfig,
+    stopping: &AtomicBool,
+) -> Result<Option<Subscription<K, V>>>
+where
+    K: Key,
+    V: Value,
+    E: KvsEngine<K, V>,
+{
+    let mut authenticated = config.auth_token.is_none();
+    for request_id in 1u64.. {
+        let id = format!("{}.{}", connection_id, request_id);
+        let mut reader = DeadlineReader {
+            stream: &mut stream,
+            deadline: config.read_timeout.map(|timeout| Instant::now() + timeout),
+            stopping,
+            started: false,
+        };
+        let request = match read_frame_limited(&mut reader, config.max_frame_size) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(KvsError::ValueTooLarge) => {
+                let response: KvResponse<V, K> = KvResponse::new(Err(KvsError::ValueTooLarge));
+                write_frame(&mut stream, &response)?;
+                return Err(KvsError::ValueTooLarge);
+            }
+            Err(e) => return Err(e),
+        };
+        if let KvRequest::Auth(_) = request {
+            debug!("[{}] Got auth request", id);
+        } else {
+            debug!("[{}] Got from stream: {:?}", id, Truncated(&request));
+        }
+        let response = match request {
+            KvRequest::Auth(token) => {
+                authenticated = match &config.auth_token {
+                    Some(expected) => expected.matches(&token),
+                    None => true,
+                };
+                if authenticated {
+                    KvResponse::new(Ok(None))
+                } else {
+                    warn!("[{}] Rejected auth token", id);
+                    KvResponse::new(Err(KvsError::Unauthorized))
+                }
+            }
+            KvRequest::Ping => KvResponse::new(Ok(None)),
+            _ if !authenticated => KvResponse::new(Err(KvsError::Unauthorized)),
+            KvRequest::Set(kv) => KvResponse::new(store.set(kv.0, kv.1).map(|_| None)),
+            KvRequest::Get(k) => KvResponse::new(store.get(k)),
+            KvRequest::Rm(k) => KvResponse::new(store.remove(k).map(|_| None)),
+            KvRequest::GetMany(keys) => KvResponse::many(store.get_many(keys)),
+            KvRequest::ScanPrefix(prefix) => KvResponse::pairs(
+                store
+                    .scan(Bound::Included(prefix.clone()), prefix.prefix_end())
+                    .map(|pairs| {
+                        pairs
+                            .into_iter()
+                            .take_while(|(key, _)| key.has_prefix(&prefix))
+                            .collect()
+                    }),
+            ),
+            KvRequest::Compact if config.allow_admin => {
+                KvResponse::new(store.compact().map(|_| None))
+            }
+            KvRequest::Compact => KvResponse::new(Err(KvsError::PermissionDenied)),
+            KvRequest::Stats => KvResponse::stats(store.stats()),
+            KvRequest::GetVersioned(k) => KvResponse::versioned(store.get_versioned(k)),
+            KvRequest::Commit { reads, writes } => {
+                KvResponse::new(store.commit(reads, writes).and_then(|committed| {
+                    if committed {
+                        Ok(None)
+                    } else {
+                        Err(KvsError::Conflict)
+                    }
+                }))
+            }
+            KvRequest::Watch(prefix) => match store.watch(prefix) {
+                Ok(events) => {
+                    let response: KvResponse<V, K> = KvResponse::new(Ok(None));
+                    write_frame(&mut stream, &response)?;
+                    return Ok(Some(Subscription { stream, events }));
+                }
+                Err(e) => KvResponse::new(Err(e)),
+            },
+        };
+        debug!("[{}] Response from store: {:?}", id, Truncated(&response));
+        write_frame(&mut stream, &response)?;
+    }
+    Ok(None)
+}
+
+/// Reads one request from `stream` by `deadline`, so the deadline holds across however many
+/// reads a frame takes. Reads wait at most `IDLE_POLL_INTERVAL` at a time, and until the first
+/// byte arrives a set `stopping` ends the stream as if the client had closed it
+struct DeadlineReader<'a> {
+    stream: &'a mut Stream,
+    deadline: Option<Instant>,
+    stopping: &'a AtomicBool,
+    started: bool,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.started && self.stopping.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            let mut wait = IDLE_POLL_INTERVAL;
+            if let Some(deadline) = self.deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "request didn't arrive in time",
+                    ));
+                }
+                wait = wait.min(remaining);
+            }
+            self.stream.set_read_timeout(Some(wait))?;
+            match self.stream.read(buf) {
+                Ok(read) => {
+                    self.started |= read > 0;
+                    return Ok(read);
+                }
+                // Which of the two a timed out read gives depends on the platform
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Forwards a subscription's events to the watching client until it hangs up or `stopping` is
+/// set
+fn stream_events<K, V>(subscription: Subscription<K, V>, stopping: &AtomicBool) -> Result<()>
+where
+    K: Key,
+    V: Value,
+{
+    let Subscription { mut stream, events } = subscription;
+    while !stopping.load(Ordering::SeqCst) {
+        match events.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => write_frame(&mut stream, &event)?,
+            Err(RecvTimeoutError::Timeout) => {
+                if client_closed(&mut stream)? {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Whether the client has closed its end, checked without blocking. A watching client has no
+/// more requests to send, so anything it does send is read and thrown away
+fn client_closed(stream: &mut Stream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let closed = match stream.read(&mut [0u8; 256]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+        Err(_) => true,
+    };
+    stream.set_nonblocking(false)?;
+    Ok(closed)
+}
+
+/// Streams a subscription's events from a thread of its own, so watches, which can last as
+/// long as the client likes, don't hold on to pool workers other connections need. The
+/// connection keeps its slot, so watches still count against `max_connections`
+fn watch_on_own_thread<K: Key, V: Value>(
+    connection_id: u64,
+    subscription: Subscription<K, V>,
+    slot: ActiveConnection,
+    stopping: Arc<AtomicBool>,
+) {
+    let watching = thread::Builder::new()
+        .name(format!("kvs-watch-{}", connection_id))
+        .spawn(move || {
+            let _slot = slot;
+            if let Err(e) = stream_events(subscription, &stopping) {
+                info!("[{}] Error streaming events: {}", connection_id, e);
+            }
+        });
+    if let Err(e) = watching {
+        warn!(
+            "[{}] Couldn't start a thread for a watch: {}",
+            connection_id, e
+        );
+    }
+}
+
+/// Counts a connection as active until dropped, which happens when its job finishes or if the
+/// pool refuses the job
+struct ActiveConnection(Arc<AtomicUsize>);
+
+impl ActiveConnection {
+    /// Takes a slot unless `max` are already taken
+    fn acquire(active: &Arc<AtomicUsize>, max: Option<usize>) -> Option<ActiveConnection> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                max.is_none_or(|max| n < max).then(|| n + 1)
+            })
+            .ok()
+            .map(|_| ActiveConnection(Arc::clone(active)))
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Tells a client over the connection limit why it is being closed. The error arrives as the
+/// response to its first request
+fn refuse_connection<V: Value>(mut stream: Stream) -> Result<()> {
+    stream.set_write_timeout(Some(REFUSAL_WRITE_TIMEOUT))?;
+    let response: KvResponse<V> = KvResponse::new(Err(KvsError::TooManyConnections));
+    write_frame(&mut stream, &response)
+}
+
+/// Accepts connections on `listener`, a TCP or UNIX domain socket, and handles each one on
+/// `thread_pool` until `shutdown` is set. The key and value types are whatever the engine
+/// stores, so the client has to send requests with the same ones
+pub fn serve<K, V, E, P>(
+    listener: impl Into<Listener>,
+    store: E,
+    thread_pool: P,
+    shutdown: &AtomicBool,
+) -> Result<()>
+where
+    K: Key,
+    V: Value,
+    E: KvsEngine<K, V>,
+    P: ThreadPool,
+{
+    serve_with_config(
+        listener,
+        store,
+        thread_pool,
+        shutdown,
+        ServerConfig::default(),
+    )
+}
+
+/// `serve` with settings other than the defaults
+pub fn serve_with_config<K, V, E, P>(
+    listener: impl Into<Listener>,
+    store: E,
+    thread_pool: P,
+    shutdown: &AtomicBool,
+    config: ServerConfig,
+) -> Result<()>
+where
+    K: Key,
+    V: Value,
+    E: KvsEngine<K, V>,
+    P: ThreadPool,
+{
+    let listener = listener.into();
+    let config = Arc::new(config);
+    // Tells connections to finish, since draining the pool waits for them
+    let stopping = Arc::new(AtomicBool::new(false));
+    let active = Arc::new(AtomicUsize::new(0));
+    // Poll rather than block in accept, so shutdown is noticed promptly
+    listener.set_nonblocking(true)?;
+    while !shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((s, peer)) => {
+                s.set_nonblocking(false)?;
+                let connection_id = next_connection_id();
+                debug!("[{}] Accepted connection from {}", connection_id, peer);
+                let slot = match ActiveConnection::acquire(&active, config.max_connections) {
+                    Some(slot) => slot,
+                    None => {
+                        warn!("[{}] Refusing connection, at the limit", connection_id);
+                        if let Err(e) = refuse_connection::<V>(s) {
+                            debug!("[{}] Error refusing connection: {}", connection_id, e);
+                        }
+                        continue;
+                    }
+                };
+                let store = store.clone();
+                let config = Arc::clone(&config);
+                let stopping = Arc::clone(&stopping);
+                // A job the pool refuses is dropped along with the stream, closing it
+                let spawned = thread_pool.spawn(move || {
+                    match serve_connection(connection_id, s, store, &config, &stopping) {
+                        Ok(Some(subscription)) => {
+                            watch_on_own_thread(connection_id, subscription, slot, stopping)
+                        }
+                        Ok(None) => {}
+                        Err(e) => info!("[{}] Error handling connection: {}", connection_id, e),
+                    }
+                });
+                if let Err(e) = spawned {
+                    warn!("[{}] Rejecting connection: {}", connection_id, e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(e) => {
+                warn!("Errored in stream: {}", e);
+            }
+        }
+    }
+    info!("Shutting down");
+    // Idle connections close on their next poll, and the rest once their request is answered
+    stopping.store(true, Ordering::SeqCst);
+    if !thread_pool.drain(config.shutdown_timeout) {
+        warn!(
+            "Gave up waiting for {} connections to finish",
+            active.load(Ordering::SeqCst)
+        );
+    }
+    drop(thread_pool);
+    drop(store);
+    Ok(())
+}
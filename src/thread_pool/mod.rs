@@ -1,12 +1,41 @@
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::time::Duration;
+
 use crate::Result;
 
 pub trait ThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized;
-    fn spawn<F>(&self, job: F)
+    /// Queues `job` to run on the pool. Fails if the pool can't take it, e.g. once it has been
+    /// shut down, in which case the job is dropped without running
+    fn spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static;
+
+    /// Runs `job` on the pool and delivers its return value through the returned receiver. If
+    /// the job panics the sender is dropped, so waiting on the receiver gives an error rather
+    /// than hanging
+    fn spawn_handle<F, T>(&self, job: F) -> Result<Receiver<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = sync_channel(1);
+        self.spawn(move || {
+            // The caller may not care about the result and have dropped the receiver
+            let _ = sender.send(job());
+        })?;
+        Ok(receiver)
+    }
+
+    /// Stops taking jobs and waits up to `timeout` for the ones already queued or running,
+    /// returning whether they all finished. Jobs still going after that are left to run on
+    /// their own. Pools whose drop doesn't wait for their jobs have nothing to drain, which is
+    /// what the default assumes
+    fn drain(&self, _timeout: Duration) -> bool {
+        true
+    }
 }
 
 pub mod naive;
@@ -1,24 +1,86 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
 
 use super::Result;
 use super::ThreadPool;
-pub struct NaiveThreadPool {}
+use crate::KvsError;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    running: u32,
+}
+
+/// Starts a fresh thread whenever there is work and fewer than `threads` are running. Each
+/// thread drains the queue and exits once it is empty, so nothing is kept around between bursts
+pub struct NaiveThreadPool {
+    threads: u32,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl NaiveThreadPool {
+    fn run(state: Arc<Mutex<PoolState>>) {
+        loop {
+            let job = {
+                // Jobs never run under the lock, so it can't actually be poisoned
+                let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                match state.queue.pop_front() {
+                    Some(job) => job,
+                    None => {
+                        state.running -= 1;
+                        return;
+                    }
+                }
+            };
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                log::error!("Naive thread pool job panicked {:?}", e);
+            }
+        }
+    }
+}
+
 impl ThreadPool for NaiveThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
-        println!(
-            "Naive thread pool will just spin up unlimited threads regardless of param {}",
-            threads
-        );
-        Ok(NaiveThreadPool {})
+        if threads == 0 {
+            return Err(KvsError::ThreadPoolBuildError(
+                "thread count must be at least 1".to_owned(),
+            ));
+        }
+        Ok(NaiveThreadPool {
+            threads,
+            state: Arc::new(Mutex::new(PoolState {
+                queue: VecDeque::new(),
+                running: 0,
+            })),
+        })
     }
 
-    fn spawn<F>(&self, job: F)
+    fn spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.queue.push_back(Box::new(job));
+        if state.running < self.threads {
+            let shared_state = Arc::clone(&self.state);
+            if let Err(e) = thread::Builder::new().spawn(move || NaiveThreadPool::run(shared_state))
+            {
+                // Threads already running will get to the job, but with none it would sit in
+                // the queue forever
+                if state.running == 0 {
+                    state.queue.pop_back();
+                    return Err(e.into());
+                }
+                return Ok(());
+            }
+            state.running += 1;
+        }
+        Ok(())
     }
 }
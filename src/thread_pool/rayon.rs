@@ -12,14 +12,17 @@ impl ThreadPool for RayonThreadPool {
         Ok(RayonThreadPool {
             pool: rayon::ThreadPoolBuilder::new()
                 .num_threads(threads as usize)
+                // Without a handler a panicking job aborts the whole process
+                .panic_handler(|e| log::error!("Rayon thread pool job panicked {:?}", e))
                 .build()?,
         })
     }
 
-    fn spawn<F>(&self, job: F)
+    fn spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.install(job);
+        self.pool.spawn(job);
+        Ok(())
     }
 }
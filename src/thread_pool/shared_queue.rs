@@ -1,104 +1,313 @@
 use std::{
     panic::{self, AssertUnwindSafe},
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, sync_channel, Receiver, SendError, Sender, SyncSender, TrySendError},
+        Arc, Mutex, PoisonError, RwLock,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use super::Result;
 use super::ThreadPool;
+use crate::KvsError;
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 enum ThreadPoolMessage {
     Run(Job),
     Shutdown,
 }
-struct Worker {
+
+enum JobSender {
+    Unbounded(Sender<ThreadPoolMessage>),
+    Bounded(SyncSender<ThreadPoolMessage>),
+}
+
+impl JobSender {
+    /// Blocks while a bounded queue is full
+    fn send(
+        &self,
+        message: ThreadPoolMessage,
+    ) -> std::result::Result<(), SendError<ThreadPoolMessage>> {
+        match self {
+            JobSender::Unbounded(sender) => sender.send(message),
+            JobSender::Bounded(sender) => sender.send(message),
+        }
+    }
+
+    fn try_send(
+        &self,
+        message: ThreadPoolMessage,
+    ) -> std::result::Result<(), TrySendError<ThreadPoolMessage>> {
+        match self {
+            JobSender::Unbounded(sender) => sender
+                .send(message)
+                .map_err(|SendError(message)| TrySendError::Disconnected(message)),
+            JobSender::Bounded(sender) => sender.try_send(message),
+        }
+    }
+}
+
+/// What `SharedQueueThreadPool::shutdown` does with jobs that are queued but not yet started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingJobs {
+    /// Run them before the workers stop. They count against the shutdown timeout
+    Run,
+    /// Drop them without running them
+    Discard,
+}
+
+struct PoolShared {
+    receiver: Mutex<Receiver<ThreadPoolMessage>>,
+    // One slot per worker id, so a replacement worker takes over the slot of the one it replaces
+    handles: Mutex<Vec<Option<JoinHandle<()>>>>,
+    // Workers send their id here when they stop cleanly
+    exited: Sender<u32>,
+    discard_pending: AtomicBool,
+}
+
+/// Lives on a worker's stack and starts a replacement if the worker unwinds. Jobs run inside
+/// `catch_unwind`, but a panic can still get out, e.g. from dropping a panic payload
+struct Sentinel {
     id: u32,
-    join_handle: Option<JoinHandle<()>>,
+    shared: Arc<PoolShared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            log::error!("Worker {} died, starting a replacement", self.id);
+            spawn_worker(self.id, self.shared.clone());
+        } else {
+            // The pool may already have given up waiting and dropped the receiver
+            let _ = self.shared.exited.send(self.id);
+        }
+    }
+}
+
+fn spawn_worker(id: u32, shared: Arc<PoolShared>) {
+    // Hold the slot while spawning so a worker that dies straight away can't have its
+    // replacement's handle overwritten by its own
+    let mut slots = shared
+        .handles
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let sentinel = Sentinel {
+        id,
+        shared: shared.clone(),
+    };
+    let join_handle = thread::spawn(move || {
+        run_worker(id, &sentinel.shared);
+        drop(sentinel);
+    });
+    slots[id as usize] = Some(join_handle);
 }
-impl Worker {
-    fn new(id: u32, receiver: Arc<Mutex<Receiver<ThreadPoolMessage>>>) -> Self {
-        let join_handle = thread::spawn(move || loop {
-            match receiver.lock() {
-                Ok(receiver) => match receiver.recv() {
-                    Ok(message) => match message {
-                        ThreadPoolMessage::Run(job) => {
-                            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
-                                println!("Worker {} panicked running job {:?}", id, e);
-                            }
-                        }
-                        ThreadPoolMessage::Shutdown => {
-                            println!("Worker {} received message to shutdown", id);
-                            return;
-                        }
-                    },
-                    Err(e) => {
-                        println!("Worker {} received error reading from channel: {:?}", id, e);
-                    }
-                },
-                Err(e) => {
-                    println!("Worker {} failed to lock receiver: {:?}", id, e);
+
+fn run_worker(id: u32, shared: &PoolShared) {
+    loop {
+        // The lock is only held while waiting for a message, never while a job runs. Jobs run
+        // outside it, so a poisoned lock still guards a perfectly usable receiver
+        let message = shared
+            .receiver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv();
+        match message {
+            Ok(ThreadPoolMessage::Run(job)) => {
+                if shared.discard_pending.load(Ordering::SeqCst) {
+                    continue;
+                }
+                if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    log::error!("Worker {} panicked running job {:?}", id, e);
                 }
             }
-        });
-        Worker {
-            id,
-            join_handle: Some(join_handle),
+            Ok(ThreadPoolMessage::Shutdown) => {
+                log::debug!("Worker {} received message to shutdown", id);
+                return;
+            }
+            Err(e) => {
+                log::debug!("Worker {} channel closed: {:?}", id, e);
+                return;
+            }
         }
     }
 }
 
 pub struct SharedQueueThreadPool {
-    workers: Vec<Worker>,
-    sender: Sender<ThreadPoolMessage>,
+    shared: Arc<PoolShared>,
+    sender: JobSender,
+    // Behind a mutex only so the pool can be shared between threads
+    exited: Mutex<Receiver<u32>>,
+    // Set once by the first shutdown, after which spawns are refused
+    stopped: RwLock<bool>,
 }
+
+impl SharedQueueThreadPool {
+    /// Like `new`, but at most `queue_cap` jobs can wait for a worker. Once the queue is full
+    /// `spawn` blocks until a worker takes a job, and `try_spawn` fails with `QueueFull`, which
+    /// pushes back on whatever is producing the jobs instead of letting the queue grow forever
+    pub fn new_bounded(threads: u32, queue_cap: usize) -> Result<Self> {
+        let (sender, receiver) = sync_channel(queue_cap);
+        Ok(SharedQueueThreadPool::start(
+            threads,
+            JobSender::Bounded(sender),
+            receiver,
+        ))
+    }
+
+    /// Queues `job` without blocking, failing with `KvsError::QueueFull` if a bounded queue has
+    /// no room
+    pub fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let stopped = self.stopped.read().unwrap_or_else(PoisonError::into_inner);
+        if *stopped {
+            return Err(KvsError::PoolStopped);
+        }
+        match self.sender.try_send(ThreadPoolMessage::Run(Box::new(job))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(KvsError::QueueFull),
+            // The pool holds the receiver, so this can't happen while it is alive
+            Err(TrySendError::Disconnected(_)) => Err(KvsError::PoolStopped),
+        }
+    }
+
+    fn start(
+        threads: u32,
+        sender: JobSender,
+        receiver: Receiver<ThreadPoolMessage>,
+    ) -> SharedQueueThreadPool {
+        let (exited_sender, exited) = channel();
+        let shared = Arc::new(PoolShared {
+            receiver: Mutex::new(receiver),
+            handles: Mutex::new((0..threads).map(|_| None).collect()),
+            exited: exited_sender,
+            discard_pending: AtomicBool::new(false),
+        });
+        for i in 0..threads {
+            spawn_worker(i, Arc::clone(&shared));
+        }
+        SharedQueueThreadPool {
+            shared,
+            sender,
+            exited: Mutex::new(exited),
+            stopped: RwLock::new(false),
+        }
+    }
+
+    /// Stops the pool, giving running jobs (and queued ones, depending on `pending`) up to
+    /// `timeout` to finish. Workers still busy after that are detached rather than joined.
+    ///
+    /// Jobs spawned after this fail with `KvsError::PoolStopped`. Returns whether every worker
+    /// stopped within the timeout, or true if the pool was already shut down.
+    pub fn shutdown(&self, timeout: Duration, pending: PendingJobs) -> bool {
+        self.stop(Some(timeout), pending)
+    }
+
+    fn stop(&self, timeout: Option<Duration>, pending: PendingJobs) -> bool {
+        // Spawns hold the read lock while they send, so every job they queue goes ahead of the
+        // shutdown messages rather than being stranded behind them
+        {
+            let mut stopped = self.stopped.write().unwrap_or_else(PoisonError::into_inner);
+            if *stopped {
+                return true;
+            }
+            *stopped = true;
+        }
+        if pending == PendingJobs::Discard {
+            self.shared.discard_pending.store(true, Ordering::SeqCst);
+        }
+        let threads = self
+            .shared
+            .handles
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len();
+        // Queued behind any pending jobs, so each worker only sees one once those are gone
+        for _ in 0..threads {
+            if let Err(e) = self.sender.send(ThreadPoolMessage::Shutdown) {
+                log::warn!("Failed to send while shutting down: {:?}", e);
+            }
+        }
+
+        let exited_receiver = self.exited.lock().unwrap_or_else(PoisonError::into_inner);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut exited = Vec::with_capacity(threads);
+        while exited.len() < threads {
+            let id = match deadline {
+                Some(deadline) => exited_receiver
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .ok(),
+                None => exited_receiver.recv().ok(),
+            };
+            match id {
+                Some(id) => exited.push(id),
+                None => break,
+            }
+        }
+
+        let mut slots = self
+            .shared
+            .handles
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for id in &exited {
+            if let Some(handle) = slots[*id as usize].take() {
+                if let Err(e) = handle.join() {
+                    log::warn!("Failed to join while shutting down: {:?}", e);
+                }
+            }
+        }
+        if exited.len() < threads {
+            log::warn!(
+                "Detaching {} workers still busy at shutdown",
+                threads - exited.len()
+            );
+        }
+        exited.len() == threads
+    }
+}
+
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self>
     where
         Self: Sized,
     {
         let (sender, receiver) = channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(threads as usize);
-        for i in 0..threads {
-            workers.push(Worker::new(i, Arc::clone(&receiver)));
-        }
-        Ok(SharedQueueThreadPool { workers, sender })
+        Ok(SharedQueueThreadPool::start(
+            threads,
+            JobSender::Unbounded(sender),
+            receiver,
+        ))
     }
 
-    fn spawn<F>(&self, job: F)
+    fn spawn<F>(&self, job: F) -> Result<()>
     where
         F: FnOnce() + Send + 'static,
     {
-        match self.sender.send(ThreadPoolMessage::Run(Box::new(job))) {
-            Err(e) => {
-                println!("Error sending job to worker channel: {:?}", e);
-            }
-            Ok(_) => {}
+        let stopped = self.stopped.read().unwrap_or_else(PoisonError::into_inner);
+        if *stopped {
+            return Err(KvsError::PoolStopped);
         }
+        // The pool holds the receiver, so this can't fail while it is alive
+        self.sender
+            .send(ThreadPoolMessage::Run(Box::new(job)))
+            .map_err(|_| KvsError::PoolStopped)
+    }
+
+    fn drain(&self, timeout: Duration) -> bool {
+        self.shutdown(timeout, PendingJobs::Run)
     }
 }
 
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
         if thread::panicking() {
-            println!("dropped while unwinding panic");
+            log::warn!("Thread pool dropped while unwinding a panic, not waiting for workers");
             return;
         }
-        for _ in 0..self.workers.len() {
-            if let Err(e) = self.sender.send(ThreadPoolMessage::Shutdown) {
-                println!("Failed to send while shutting down: {:?}", e);
-            }
-        }
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.join_handle.take() {
-                if let Err(e) = thread.join() {
-                    println!("Failed to join while shutting down: {:?}", e);
-                }
-            }
-        }
+        self.stop(None, PendingJobs::Run);
     }
 }
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::{KvsError, Result};
+
+/// Marks an address as the path of a UNIX domain socket rather than a TCP address
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where a server listens and clients connect. Written as a TCP address like `127.0.0.1:4000`,
+/// or as `unix:/path/to.sock` for a UNIX domain socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ServerAddr::Tcp(addr)
+    }
+}
+
+impl FromStr for ServerAddr {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) if !path.is_empty() => Ok(ServerAddr::Unix(PathBuf::from(path))),
+            Some(_) => Err(KvsError::ConnectionFailed(format!(
+                "{} is not a usable socket path",
+                s
+            ))),
+            None => s.parse().map(ServerAddr::Tcp).map_err(|e| {
+                KvsError::ConnectionFailed(format!("{} is not a valid address: {}", s, e))
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// A connection between a client and a server, over either kind of socket
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to `addr`. `timeout` only bounds TCP connects, since a local socket answers or
+    /// refuses straight away
+    pub fn connect(addr: &ServerAddr, timeout: Duration) -> io::Result<Stream> {
+        match addr {
+            ServerAddr::Tcp(addr) => TcpStream::connect_timeout(addr, timeout).map(Stream::Tcp),
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Accepts connections for a server, on either kind of socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is set when `bind` created the socket file, which is then removed on drop
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Listens on `addr`. A socket file left behind by a server that is no longer running is
+    /// replaced, but one that still accepts connections is an error
+    pub fn bind(addr: &ServerAddr) -> io::Result<Listener> {
+        match addr {
+            ServerAddr::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            #[cfg(unix)]
+            ServerAddr::Unix(path) => {
+                let listener = match UnixListener::bind(path) {
+                    Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                        if UnixStream::connect(path).is_ok() {
+                            return Err(e);
+                        }
+                        std::fs::remove_file(path)?;
+                        UnixListener::bind(path)?
+                    }
+                    bound => bound?,
+                };
+                Ok(Listener::Unix(listener, Some(path.clone())))
+            }
+        }
+    }
+
+    /// Accepts a connection, along with a description of the peer for logging
+    pub fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, peer)| (Stream::Tcp(stream), peer.to_string())),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.accept().map(|(stream, peer)| {
+                let peer = match peer.as_pathname() {
+                    Some(path) => path.display().to_string(),
+                    None => "unnamed unix socket".to_owned(),
+                };
+                (Stream::Unix(stream), peer)
+            }),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener, None)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            if let Err(e) = std::fs::remove_file(path.as_path()) {
+                log::warn!("Error removing socket {}: {:?}", path.display(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use kvs::async_client::AsyncKvsClient;
+use kvs::engine::store::KvStore;
+use kvs::server;
+use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::ThreadPool;
+use kvs::KvsError;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tempfile::TempDir;
+
+fn start_server(temp_dir: &TempDir) -> (SocketAddr, Arc<AtomicBool>, JoinHandle<kvs::Result<()>>) {
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(8)?, &shutdown)
+        })
+    };
+    (addr, shutdown, server)
+}
+
+// Many tasks each on their own connection set, read back and remove their keys at once
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_tasks() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (addr, shutdown, server) = start_server(&temp_dir);
+
+    let tasks: Vec<_> = (0..32)
+        .map(|task_id| {
+            tokio::spawn(async move {
+                let mut client = AsyncKvsClient::<String, String>::connect(addr).await?;
+                for i in 0..20 {
+                    let key = format!("key{}-{}", task_id, i);
+                    client.set(key.clone(), format!("value{}", i)).await?;
+                    assert_eq!(client.get(key).await?, Some(format!("value{}", i)));
+                }
+                client.remove(format!("key{}-0", task_id)).await?;
+                assert_eq!(client.get(format!("key{}-0", task_id)).await?, None);
+                kvs::Result::Ok(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+
+    let mut client = AsyncKvsClient::<String, String>::connect(addr).await?;
+    assert_eq!(
+        client.get("key31-19".to_owned()).await?,
+        Some("value19".to_owned())
+    );
+    assert!(matches!(
+        client.remove("missing".to_owned()).await,
+        Err(KvsError::NonExistantKey)
+    ));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()
+}
+
+#[tokio::test]
+async fn connect_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    assert!(matches!(
+        AsyncKvsClient::<String, String>::connect(addr).await,
+        Err(KvsError::ConnectionFailed(_))
+    ));
+}
@@ -1,11 +1,18 @@
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::engine::sled::SledKvsEngine;
+use kvs::engine::store::KvStore;
+use kvs::engine::KvsEngine;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
+use walkdir::WalkDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
@@ -173,6 +180,41 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+fn server_stderr_with_flag(flag: &str, addr: &str) -> String {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([flag, "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    fs::read_to_string(&stderr_path).expect("unable to read from stderr file")
+}
+
+#[test]
+fn cli_log_verbosity() {
+    let content = server_stderr_with_flag("-vvv", "127.0.0.1:4013");
+    assert!(content
+        .lines()
+        .any(|line| line.contains("DEBUG - [") && line.contains("Got from stream")));
+
+    let content = server_stderr_with_flag("-q", "127.0.0.1:4014");
+    assert!(content.contains(env!("CARGO_PKG_VERSION")));
+    assert!(!content.contains("configuration"));
+    assert!(!content.contains("Got from stream"));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -214,6 +256,30 @@ fn cli_wrong_engine() {
     }
 }
 
+// A client pointed at a port nobody is listening on gives up after its retries
+#[test]
+fn client_cli_unreachable_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let start = Instant::now();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:4009",
+            "--timeout",
+            "500",
+            "--retries",
+            "2",
+            "get",
+            "key",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("could not connect"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -337,3 +403,449 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn cli_pool_round_trip(pool: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--pool", pool, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn cli_naive_pool() {
+    cli_pool_round_trip("naive", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_shared_queue_pool() {
+    cli_pool_round_trip("shared-queue", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_rayon_pool() {
+    cli_pool_round_trip("rayon", "127.0.0.1:4008");
+}
+
+// SIGTERM stops the server cleanly, with everything it acknowledged on disk
+#[cfg(unix)]
+#[test]
+fn cli_sigterm_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "server ignored SIGTERM"
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+
+    let store: KvStore<String, String> = KvStore::open(&temp_dir.path().join("db/store")).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// `kvs validate` passes a healthy store and points at the first bad record of a damaged one,
+// leaving it as it was
+#[test]
+fn cli_validate() {
+    let temp_dir = TempDir::new().unwrap();
+    let store_dir = temp_dir.path().join("db").join("store");
+    let store: KvStore<String, String> = KvStore::open(&store_dir).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let log_path = fs::read_dir(store_dir.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().map(|ext| ext == "kvs").unwrap_or(false))
+        .unwrap();
+    let second_offset = fs::metadata(&log_path).unwrap().len();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["validate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("ok: 2 records in 1 log files"));
+
+    let mut log = fs::read(&log_path).unwrap();
+    *log.last_mut().unwrap() ^= 0xff;
+    fs::write(&log_path, &log).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["validate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(format!("offset {}", second_offset)));
+    assert_eq!(fs::read(&log_path).unwrap(), log);
+}
+
+// `kvs compact` shrinks a store full of overwrites down to its live values
+#[test]
+fn cli_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let store_dir = temp_dir.path().join("db").join("store");
+    let log_len = || -> u64 {
+        fs::read_dir(store_dir.join("data"))
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    let store: KvStore<String, String> = KvStore::open(&store_dir).unwrap();
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store
+                .set(format!("key{}", key_id), format!("value{}", iter))
+                .unwrap();
+        }
+    }
+    drop(store);
+    let before = log_len();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("reclaimed"));
+    assert!(log_len() < before / 10);
+
+    let store: KvStore<String, String> = KvStore::open(&store_dir).unwrap();
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).unwrap(),
+            Some("value99".to_owned())
+        );
+    }
+}
+
+// `--data-dir` moves everything the server writes, and `kvs` finds it through `KVS_DATA_DIR`
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("custom");
+    let addr = "127.0.0.1:4016";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--engine", "kvs", "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+
+    assert!(data_dir.join("config.info").is_file());
+    assert!(data_dir.join("store").join("data").is_dir());
+    assert!(!temp_dir.path().join("db").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["validate"])
+        .env("KVS_DATA_DIR", &data_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("ok: 1 records in 1 log files"));
+}
+
+// A server listening on a UNIX domain socket serves the client over it like over TCP
+#[cfg(unix)]
+#[test]
+fn cli_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = format!("unix:{}", temp_dir.path().join("kvs.sock").display());
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr, "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr, "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+// Two servers starting on one data directory with different engines can't both record theirs:
+// exactly one keeps running and config.info names its engine
+#[test]
+fn cli_concurrent_engine_choice() {
+    let temp_dir = TempDir::new().unwrap();
+    let spawn = |engine: &str, addr: &str| {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--engine", engine])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut kvs = spawn("kvs", "127.0.0.1:4017");
+    let mut sled = spawn("sled", "127.0.0.1:4018");
+    thread::sleep(Duration::from_secs(1));
+
+    let kvs_exited = kvs.try_wait().unwrap();
+    let sled_exited = sled.try_wait().unwrap();
+    let config = fs::read_to_string(temp_dir.path().join("db").join("config.info")).unwrap();
+    match (kvs_exited, sled_exited) {
+        (None, Some(status)) => {
+            assert!(!status.success());
+            assert_eq!(config, "\"Kvs\"");
+        }
+        (Some(status), None) => {
+            assert!(!status.success());
+            assert_eq!(config, "\"Sled\"");
+        }
+        other => panic!("expected exactly one server to exit, got {:?}", other),
+    }
+    for mut child in [kvs, sled] {
+        let _ = child.kill();
+        child.wait().unwrap();
+    }
+}
+
+// `kvs-client ping` reports the round trip to a running server, and fails fast once it is gone
+#[test]
+fn cli_ping() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "ping"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("pong in"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+
+    let start = Instant::now();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", addr, "--timeout", "500", "--retries", "0", "ping"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("could not connect"));
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+// A mangled config.info stops the server with an error naming the file, unless --force-engine
+// says to overwrite it
+#[test]
+fn cli_corrupt_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_info = temp_dir.path().join("db").join("config.info");
+    fs::create_dir_all(config_info.parent().unwrap()).unwrap();
+    fs::write(&config_info, "\"Kv").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4020"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("corrupt config"))
+        .stderr(contains("config.info"));
+    assert_eq!(fs::read_to_string(&config_info).unwrap(), "\"Kv");
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--force-engine",
+            "--addr",
+            "127.0.0.1:4020",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    assert_eq!(fs::read_to_string(&config_info).unwrap(), "\"Kvs\"");
+}
+
+// --migrate-engine moves a kvs store's keys into sled and records the switch in config.info
+#[test]
+fn cli_migrate_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let db_dir = temp_dir.path().join("db");
+    let store: KvStore<String, String> = KvStore::open(&db_dir.join("store")).unwrap();
+    for key_id in 0..10 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .unwrap();
+    }
+    drop(store);
+    fs::write(db_dir.join("config.info"), "\"Kvs\"").unwrap();
+
+    let addr = "127.0.0.1:4021";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--migrate-engine", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for key_id in 0..10 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["--addr", addr, "get", &format!("key{}", key_id)])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", key_id));
+    }
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    assert_eq!(
+        fs::read_to_string(db_dir.join("config.info")).unwrap(),
+        "\"Sled\""
+    );
+}
+
+// Every file under `dir` with its contents
+fn dir_contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut contents: Vec<_> = WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.is_file())
+        .map(|path| {
+            let bytes = fs::read(&path).unwrap();
+            (path, bytes)
+        })
+        .collect();
+    contents.sort();
+    contents
+}
+
+// `kvs migrate` copies a kvs store into sled with progress along the way, leaving the source
+// untouched, and won't write over a destination that has anything in it
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let src = temp_dir.path().join("store");
+    let dst = temp_dir.path().join("sled");
+    let store: KvStore<String, String> = KvStore::open(&src).unwrap();
+    store
+        .ingest((0..25_000).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id))))
+        .unwrap();
+    for key_id in 0..100 {
+        store.remove(format!("key{}", key_id)).unwrap();
+    }
+    drop(store);
+    let before = dir_contents(&src);
+
+    let migrate = || {
+        let mut cmd = Command::cargo_bin("kvs").unwrap();
+        cmd.args(["migrate", "--from", "kvs", "--to", "sled", "--src"])
+            .arg(&src)
+            .arg("--dst")
+            .arg(&dst)
+            .current_dir(&temp_dir);
+        cmd
+    };
+    migrate()
+        .assert()
+        .success()
+        .stdout(contains("migrated 24900 pairs from Kvs to Sled"))
+        .stderr(contains("copied 10000 pairs"))
+        .stderr(contains("copied 20000 pairs"));
+    assert_eq!(dir_contents(&src), before);
+
+    let sled: SledKvsEngine<String, String> = SledKvsEngine::new(&dst).unwrap();
+    assert_eq!(sled.stats().unwrap().keys, 24_900);
+    for key_id in [0, 99, 100, 12_345, 24_999] {
+        let expected = (key_id >= 100).then(|| format!("value{}", key_id));
+        assert_eq!(sled.get(format!("key{}", key_id)).unwrap(), expected);
+    }
+    drop(sled);
+
+    migrate()
+        .assert()
+        .failure()
+        .stderr(contains("already holds data"));
+}
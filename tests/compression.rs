@@ -0,0 +1,90 @@
+use kvs::engine::compression::CompressionKind;
+use kvs::engine::store::{KvStore, KvStoreConfig};
+use kvs::engine::KvsEngine;
+use kvs::Result;
+use rand::{thread_rng, Rng, RngCore};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+#[test]
+fn lz_round_trip() -> Result<()> {
+    let kind = CompressionKind::Lz;
+    let mut random = vec![0u8; 10000];
+    thread_rng().fill_bytes(&mut random);
+    let repeated = b"hello world ".repeat(1000);
+    let mut mixed = Vec::new();
+    for _ in 0..100 {
+        let len = thread_rng().gen_range(0..50);
+        mixed.extend_from_slice(&random[..len]);
+        mixed.extend_from_slice(&repeated[..len * 3]);
+    }
+    let runs = vec![7u8; 100000];
+
+    for input in [
+        &b""[..],
+        b"abc",
+        b"abcdabcd",
+        &random,
+        &repeated,
+        &mixed,
+        &runs,
+    ] {
+        let compressed = kind.compress(input);
+        assert_eq!(kind.decompress(&compressed)?, input);
+    }
+    assert!(kind.compress(&repeated).len() < repeated.len() / 10);
+    assert!(kind.compress(&runs).len() < 32);
+    Ok(())
+}
+
+#[test]
+fn lz_rejects_garbage() {
+    let kind = CompressionKind::Lz;
+    let compressed = kind.compress(&b"hello world ".repeat(100));
+    assert!(kind
+        .decompress(&compressed[..compressed.len() - 1])
+        .is_err());
+    assert!(kind.decompress(&[5, 1, 3, 4]).is_err());
+    assert!(kind
+        .decompress(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01])
+        .is_err());
+    assert!(kind
+        .decompress(&[4, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f])
+        .is_err());
+}
+
+fn dir_size(dir: &std::path::Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+// A highly compressible 1 MiB value should take a fraction of that on disk, and the log must
+// stay readable after reopening without compression
+#[test]
+fn compressed_values_on_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compression: Some(CompressionKind::Lz),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let large = "{\"field\": \"some json value\"}, ".repeat(1024 * 1024 / 32);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert!(dir_size(temp_dir.path()) < 64 * 1024);
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    store.set("uncompressed".to_owned(), large.clone())?;
+    store.compact_file()?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("uncompressed".to_owned())?, Some(large));
+    Ok(())
+}
@@ -0,0 +1,492 @@
+use kvs::engine::memory::InMemoryKvsEngine;
+use kvs::engine::sled::SledKvsEngine;
+use kvs::engine::storage::{FileBackend, LogBackend, LogStorage, MemoryBackend};
+use kvs::engine::store::{KvRecord, KvStore, KvStoreConfig, Value};
+use kvs::engine::watch::WatchEvent;
+use kvs::engine::KvsEngine;
+use kvs::{KvsError, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Only goes through the trait, the way the server and benches see an engine
+fn compact_through_trait<E: KvsEngine<String, String>>(engine: &E) -> Result<()> {
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            engine.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in 50..100 {
+        engine.remove(format!("key{}", key_id))?;
+    }
+
+    engine.compact()?;
+    for key_id in 0..50 {
+        assert_eq!(
+            engine.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    assert_eq!(engine.get("key50".to_owned())?, None);
+    assert_eq!(engine.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 50);
+
+    // Still writable afterwards
+    engine.set("key0".to_owned(), "after".to_owned())?;
+    assert_eq!(engine.get("key0".to_owned())?, Some("after".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_compact_through_trait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compact_through_trait(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_compact_through_trait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compact_through_trait(&SledKvsEngine::new(temp_dir.path())?)
+}
+
+#[test]
+fn memory_compact_through_trait() -> Result<()> {
+    compact_through_trait(&InMemoryKvsEngine::new())
+}
+
+// get_many and set_many, overridden or not, agree with looping over get and set
+fn many_through_trait<E: KvsEngine<String, String>>(batched: &E, looped: &E) -> Result<()> {
+    let mut pairs: Vec<(String, String)> = (0..200)
+        .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+        .collect();
+    // A key given twice keeps its last value
+    pairs.push(("key7".to_owned(), "again".to_owned()));
+    batched.set_many(pairs.clone())?;
+    for (key, value) in pairs {
+        looped.set(key, value)?;
+    }
+    batched.remove("key3".to_owned())?;
+    looped.remove("key3".to_owned())?;
+
+    let keys: Vec<String> = (0..250)
+        .rev()
+        .map(|key_id| format!("key{}", key_id))
+        .chain(["key7".to_owned(), "key7".to_owned()])
+        .collect();
+    let expected: Vec<Option<String>> = keys
+        .iter()
+        .map(|key| looped.get(key.clone()))
+        .collect::<Result<_>>()?;
+    assert_eq!(expected[249 - 7], Some("again".to_owned()));
+    assert_eq!(expected[249 - 3], None);
+    assert_eq!(batched.get_many(keys.clone())?, expected);
+    assert_eq!(looped.get_many(keys)?, expected);
+    assert_eq!(
+        batched.scan(Bound::Unbounded, Bound::Unbounded)?,
+        looped.scan(Bound::Unbounded, Bound::Unbounded)?
+    );
+    assert_eq!(batched.get_many(Vec::new())?, Vec::new());
+    batched.set_many(Vec::new())?;
+    Ok(())
+}
+
+#[test]
+fn kvs_many_through_trait() -> Result<()> {
+    let batched = TempDir::new().expect("unable to create temporary working directory");
+    let looped = TempDir::new().expect("unable to create temporary working directory");
+    many_through_trait(
+        &KvStore::open(batched.path())?,
+        &KvStore::open(looped.path())?,
+    )
+}
+
+#[test]
+fn sled_many_through_trait() -> Result<()> {
+    let batched = TempDir::new().expect("unable to create temporary working directory");
+    let looped = TempDir::new().expect("unable to create temporary working directory");
+    many_through_trait(
+        &SledKvsEngine::new(batched.path())?,
+        &SledKvsEngine::new(looped.path())?,
+    )
+}
+
+#[test]
+fn memory_many_through_trait() -> Result<()> {
+    many_through_trait(&InMemoryKvsEngine::new(), &InMemoryKvsEngine::new())
+}
+
+// The same basics the disk engines are held to
+#[test]
+fn memory_set_get_remove() -> Result<()> {
+    let store: InMemoryKvsEngine = InMemoryKvsEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::NonExistantKey)
+    ));
+
+    // Clones share the map
+    let clone = store.clone();
+    clone.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(
+        store.scan(Bound::Excluded("key2".to_owned()), Bound::Unbounded)?,
+        vec![("key4".to_owned(), "value4".to_owned())]
+    );
+    Ok(())
+}
+
+// Watchers only hear about their own prefix, in write order, and dropped ones are let go
+fn watch_through_trait<E: KvsEngine<String, String>>(engine: &E) -> Result<()> {
+    let events = engine.watch("user:".to_owned())?;
+    drop(engine.watch(String::new())?);
+    engine.set("user:1".to_owned(), "alice".to_owned())?;
+    engine.set("other".to_owned(), "value".to_owned())?;
+    engine.remove("user:1".to_owned())?;
+    engine.set("user:2".to_owned(), "bob".to_owned())?;
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        WatchEvent::Set("user:1".to_owned(), "alice".to_owned())
+    );
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        WatchEvent::Removed("user:1".to_owned())
+    );
+    assert_eq!(
+        events.recv_timeout(timeout).unwrap(),
+        WatchEvent::Set("user:2".to_owned(), "bob".to_owned())
+    );
+    assert!(events.recv_timeout(Duration::from_millis(100)).is_err());
+    Ok(())
+}
+
+#[test]
+fn kvs_watch_through_trait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_through_trait(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_watch_through_trait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_through_trait(&SledKvsEngine::new(temp_dir.path())?)
+}
+
+#[test]
+fn memory_watch_through_trait() -> Result<()> {
+    watch_through_trait(&InMemoryKvsEngine::new())
+}
+
+// Small files, so the engine rotates and compacts across several logs in each backend
+fn storage_config() -> KvStoreConfig {
+    KvStoreConfig {
+        max_file_size: 1024,
+        ..KvStoreConfig::default()
+    }
+}
+
+#[test]
+fn kvs_file_storage_through_trait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open =
+        || KvStore::open_with_storage(FileBackend::new(temp_dir.path().into()), storage_config());
+    let store = open()?;
+    compact_through_trait(&store)?;
+    watch_through_trait(&store)?;
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("user:2".to_owned())?, Some("bob".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn kvs_memory_storage_through_trait() -> Result<()> {
+    let store = KvStore::open_with_storage(MemoryBackend::default(), storage_config())?;
+    compact_through_trait(&store)?;
+    watch_through_trait(&store)
+}
+
+// Stands in for a backend from outside the crate, passing everything on to memory
+#[derive(Default)]
+struct CountingBackend {
+    inner: MemoryBackend,
+    created: Arc<AtomicUsize>,
+}
+
+impl LogBackend for CountingBackend {
+    fn log_ids(&self) -> Result<Vec<u64>> {
+        self.inner.log_ids()
+    }
+
+    fn create(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        self.created.fetch_add(1, Ordering::SeqCst);
+        self.inner.create(file_id)
+    }
+
+    fn open(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        self.inner.open(file_id)
+    }
+
+    fn remove(&self, file_id: u64) -> Result<()> {
+        self.inner.remove(file_id)
+    }
+}
+
+#[test]
+fn kvs_custom_storage_through_trait() -> Result<()> {
+    let backend = CountingBackend::default();
+    let created = Arc::clone(&backend.created);
+    let store = KvStore::open_with_storage(backend, storage_config())?;
+    compact_through_trait(&store)?;
+    // The first log, at least one rotation and the compacted log
+    assert!(created.load(Ordering::SeqCst) >= 3);
+    Ok(())
+}
+
+// A file backend that stops doing anything once compaction publishes its new log, as if the
+// process had died there. `after_rename` decides which side of the rename that is
+struct CrashingBackend {
+    inner: FileBackend,
+    after_rename: bool,
+    crashed: AtomicBool,
+}
+
+impl CrashingBackend {
+    fn check(&self) -> Result<()> {
+        if self.crashed.load(Ordering::SeqCst) {
+            return Err(KvsError::IOError("crashed".to_owned()));
+        }
+        Ok(())
+    }
+}
+
+impl LogBackend for CrashingBackend {
+    fn log_ids(&self) -> Result<Vec<u64>> {
+        self.inner.log_ids()
+    }
+
+    fn create(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        self.check()?;
+        self.inner.create(file_id)
+    }
+
+    fn open(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        self.inner.open(file_id)
+    }
+
+    fn remove(&self, file_id: u64) -> Result<()> {
+        self.check()?;
+        self.inner.remove(file_id)
+    }
+
+    fn create_staged(&self, file_id: u64) -> Result<Arc<dyn LogStorage>> {
+        self.check()?;
+        self.inner.create_staged(file_id)
+    }
+
+    fn publish(&self, file_id: u64) -> Result<()> {
+        if self.after_rename {
+            self.inner.publish(file_id)?;
+        }
+        self.crashed.store(true, Ordering::SeqCst);
+        self.check()
+    }
+
+    fn discard_staged(&self, file_id: u64) -> Result<()> {
+        self.check()?;
+        self.inner.discard_staged(file_id)
+    }
+}
+
+// Dying on either side of the rename that publishes a compacted log leaves a store that opens
+// with every value it had, and keeps taking writes afterwards
+fn crash_during_compaction(after_rename: bool) -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    std::fs::create_dir(&data_dir)?;
+    let backend = CrashingBackend {
+        inner: FileBackend::new(data_dir.clone()),
+        after_rename,
+        crashed: AtomicBool::new(false),
+    };
+    let store = KvStore::open_with_storage(backend, KvStoreConfig::default())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.compact().is_err());
+    // Skip the drop, which would flush and save the index like a clean shutdown
+    std::mem::forget(store);
+
+    let files = || -> Vec<String> {
+        std::fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    };
+    assert_eq!(
+        files().iter().any(|name| name.ends_with(".tmp")),
+        !after_rename
+    );
+
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert!(!files().iter().any(|name| name.ends_with(".tmp")));
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value9".to_owned())
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.set("key1".to_owned(), "after".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn crash_before_compaction_rename() -> Result<()> {
+    crash_during_compaction(false)
+}
+
+#[test]
+fn crash_after_compaction_rename() -> Result<()> {
+    crash_during_compaction(true)
+}
+
+// Everything live in a kvs store turns up in sled after a migration, and nothing removed does
+#[test]
+fn migrate_kvs_to_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(&temp_dir.path().join("store"))?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..1000).step_by(3) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let sled = SledKvsEngine::new(&temp_dir.path().join("sled"))?;
+
+    assert_eq!(kvs::engine::migrate(&store, &sled, |_| {})?, 666);
+    assert_eq!(
+        sled.scan(Bound::Unbounded, Bound::Unbounded)?,
+        store.scan(Bound::Unbounded, Bound::Unbounded)?
+    );
+    for key_id in 0..1000 {
+        let expected = (key_id % 3 != 0).then(|| format!("value{}", key_id));
+        assert_eq!(sled.get(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// A value that refuses to serialize, to make a batch fail part way through
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Fallible(String);
+
+impl fmt::Display for Fallible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Fallible {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.0 == "fail" {
+            return Err(serde::ser::Error::custom("refusing to serialize"));
+        }
+        serializer.serialize_newtype_struct("Fallible", &self.0)
+    }
+}
+
+impl Value for Fallible {}
+
+// A sled batch lands whole or not at all, and beats setting the same pairs one flush at a time
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(temp_dir.path())?;
+    engine.set("key0".to_owned(), Fallible("before".to_owned()))?;
+
+    let mut ops: Vec<_> = (0..100)
+        .map(|i| KvRecord::Set((format!("key{}", i), Fallible(format!("value{}", i)))))
+        .collect();
+    ops[50] = KvRecord::Set(("key50".to_owned(), Fallible("fail".to_owned())));
+    assert!(engine.write_batch(ops).is_err());
+    assert_eq!(engine.stats()?.keys, 1);
+    assert_eq!(
+        engine.get("key0".to_owned())?,
+        Some(Fallible("before".to_owned()))
+    );
+    assert!(matches!(
+        engine.write_batch(vec![KvRecord::SetExpiring((
+            "key1".to_owned(),
+            Fallible("value1".to_owned()),
+            0
+        ))]),
+        Err(KvsError::Unsupported)
+    ));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    let mut ops: Vec<_> = (1..100)
+        .map(|i| KvRecord::Set((format!("key{}", i), Fallible(format!("value{}", i)))))
+        .collect();
+    ops.push(KvRecord::Rm("key0".to_owned()));
+    ops.push(KvRecord::Rm("missing".to_owned()));
+    engine.write_batch(ops)?;
+    assert_eq!(engine.stats()?.keys, 99);
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(
+        engine.get("key99".to_owned())?,
+        Some(Fallible("value99".to_owned()))
+    );
+    drop(engine);
+
+    // Every set flushes, so the loop pays for hundreds of flushes where the batch pays for one
+    let pairs: Vec<(String, String)> = (0..200)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    let looped_dir = TempDir::new().expect("unable to create temporary working directory");
+    let looped: SledKvsEngine<String, String> = SledKvsEngine::new(looped_dir.path())?;
+    let start = Instant::now();
+    for (key, value) in pairs.clone() {
+        looped.set(key, value)?;
+    }
+    let looped_time = start.elapsed();
+    let batched_dir = TempDir::new().expect("unable to create temporary working directory");
+    let batched: SledKvsEngine<String, String> = SledKvsEngine::new(batched_dir.path())?;
+    let start = Instant::now();
+    batched.set_many(pairs)?;
+    let batched_time = start.elapsed();
+    assert!(
+        batched_time < looped_time,
+        "batch took {:?}, looping took {:?}",
+        batched_time,
+        looped_time
+    );
+    Ok(())
+}
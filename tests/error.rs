@@ -0,0 +1,30 @@
+use kvs::KvsError;
+use std::error::Error;
+
+#[test]
+fn display_messages() {
+    assert_eq!(KvsError::NonExistantKey.to_string(), "Key not found");
+    assert_eq!(
+        KvsError::Corruption { offset: 42 }.to_string(),
+        "corrupt record at offset 42"
+    );
+    assert_eq!(
+        KvsError::IOError("disk on fire".to_owned()).to_string(),
+        "I/O error: disk on fire"
+    );
+}
+
+#[test]
+fn boxed_error_downcast() {
+    fn fails() -> Result<(), Box<dyn Error>> {
+        Err(KvsError::NonExistantKey)?;
+        Ok(())
+    }
+
+    let err = fails().unwrap_err();
+    assert_eq!(err.to_string(), "Key not found");
+    assert!(matches!(
+        err.downcast_ref::<KvsError>(),
+        Some(KvsError::NonExistantKey)
+    ));
+}
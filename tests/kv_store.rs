@@ -1,7 +1,18 @@
-use kvs::engine::{store::KvStore, KvsEngine};
-use kvs::Result;
+use kvs::engine::index::IndexKind;
+use kvs::engine::store::{
+    Codec, Key, KvRecord, KvStore, KvStoreConfig, KvStoreStats, RecordFormat, SyncPolicy, Value,
+};
+use kvs::engine::KvsEngine;
+use kvs::{KvsError, Result};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::PathBuf;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -82,6 +93,339 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// A removed key must not be reloaded into the index from its tombstone on reopen
+#[test]
+fn removed_key_absent_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(), vec!["key2".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    // Setting it again after the reopen brings it back
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Replay of a large log should rebuild byte-accurate offsets for every record
+#[test]
+fn reopen_large_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: u64::MAX,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for iter in 0..4 {
+        for key_id in 0..25000 {
+            let value = format!("{}", key_id).repeat(iter + 1);
+            store.set(format!("key{}", key_id), value)?;
+        }
+    }
+    for key_id in (0..25000).step_by(10) {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 22500);
+    for key_id in 0..25000 {
+        let expected = if key_id % 10 == 0 {
+            None
+        } else {
+            Some(format!("{}", key_id).repeat(4))
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+fn log_files(dir: &std::path::Path) -> Vec<PathBuf> {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.extension().map(|ext| ext == "kvs").unwrap_or(false))
+        .collect()
+}
+
+// A flipped byte in the log should surface as a corruption error at the damaged record
+#[test]
+fn corrupted_record_detected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let second_offset = fs::metadata(&log_path)?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let log_len = fs::metadata(&log_path)?.len();
+    let mut log = OpenOptions::new().read(true).write(true).open(&log_path)?;
+    let mut last_byte = [0u8; 1];
+    log.seek(SeekFrom::Start(log_len - 1))?;
+    log.read_exact(&mut last_byte)?;
+    log.seek(SeekFrom::Start(log_len - 1))?;
+    log.write_all(&[last_byte[0] ^ 0xff])?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.get("key2".to_owned()) {
+        Err(KvsError::Corruption { offset }) => assert_eq!(offset, second_offset),
+        other => panic!("expected corruption error, got {:?}", other),
+    }
+
+    drop(store);
+    let replay_config = KvStoreConfig {
+        index_snapshots: false,
+        ..KvStoreConfig::default()
+    };
+    match KvStore::<String, String>::open_with_config(temp_dir.path(), replay_config) {
+        Err(KvsError::Corruption { offset }) => assert_eq!(offset, second_offset),
+        Err(e) => panic!("expected corruption error, got {:?}", e),
+        Ok(_) => panic!("expected corruption error opening store"),
+    }
+
+    // The index saved at close doesn't replay the log, so the damage shows up on the read
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    match store.get("key2".to_owned()) {
+        Err(KvsError::Corruption { offset }) => assert_eq!(offset, second_offset),
+        other => panic!("expected corruption error, got {:?}", other),
+    }
+    Ok(())
+}
+
+fn log_len(dir: &std::path::Path) -> u64 {
+    log_files(dir)
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// EveryN only pushes writes out to the file once n of them have built up
+#[test]
+fn sync_policy_every_n() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        sync_policy: SyncPolicy::EveryN(3),
+        fsync: true,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let empty_len = log_len(temp_dir.path());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(log_len(temp_dir.path()), empty_len);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let synced_len = log_len(temp_dir.path());
+    assert!(synced_len > empty_len);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(log_len(temp_dir.path()), synced_len);
+    Ok(())
+}
+
+// Interval syncs on the first write after the interval has passed
+#[test]
+fn sync_policy_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        sync_policy: SyncPolicy::Interval(Duration::from_millis(200)),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let empty_len = log_len(temp_dir.path());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(log_len(temp_dir.path()), empty_len);
+    thread::sleep(Duration::from_millis(250));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(log_len(temp_dir.path()) > empty_len);
+    Ok(())
+}
+
+// Buffered writes are readable straight away and reach disk when the store is dropped
+#[test]
+fn sync_policy_on_drop_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        sync_policy: SyncPolicy::OnDropOnly,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let empty_len = log_len(temp_dir.path());
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(log_len(temp_dir.path()), empty_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.iter()?.count(), 99);
+    assert!(log_len(temp_dir.path()) > empty_len);
+
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    Ok(())
+}
+
+fn codec_round_trip(codec: Codec) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        codec,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact_file()?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn codec_message_pack_round_trip() -> Result<()> {
+    codec_round_trip(Codec::MessagePack)
+}
+
+#[test]
+fn codec_json_round_trip() -> Result<()> {
+    codec_round_trip(Codec::Json)
+}
+
+// JSON logs can be read by eye, and reopening them as MessagePack fails cleanly, from the
+// manifest or failing that from the log header
+#[test]
+fn codec_mismatch_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        codec: Codec::Json,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = fs::read(log_files(temp_dir.path()).pop().unwrap())?;
+    assert!(
+        String::from_utf8_lossy(&log).contains(r#"{"key":"key1","value":"value1","written_at":"#)
+    );
+
+    match KvStore::<String, String>::open(temp_dir.path()) {
+        Err(KvsError::IncompatibleFormat(_)) => {}
+        Err(e) => panic!("expected format error, got {:?}", e),
+        Ok(_) => panic!("expected format error opening store"),
+    }
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+    match KvStore::<String, String>::open(temp_dir.path()) {
+        Err(KvsError::WrongCodec) => {}
+        Err(e) => panic!("expected codec error, got {:?}", e),
+        Ok(_) => panic!("expected codec error opening store"),
+    }
+    // A failed open doesn't leave a manifest behind for the wrong codec
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+    Ok(())
+}
+
+// Keys set with a TTL read normally until they expire, then disappear everywhere
+#[test]
+fn ttl_expires_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("forever".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+    assert!(store.contains_key(&"short".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert!(!store.contains_key(&"short".to_owned()));
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.iter()?.count(), 2);
+    assert!(store.remove("short".to_owned()).is_err());
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+
+    // The expiry survives a reopen and compaction keeps the unexpired TTL
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, Some("value4".to_owned()));
+    store.compact_file()?;
+    assert_eq!(store.get("short".to_owned())?, Some("value4".to_owned()));
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("short".to_owned())?, None);
+
+    // A plain set clears the TTL
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value5".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set("long".to_owned(), "value6".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("long".to_owned())?, Some("value6".to_owned()));
+
+    Ok(())
+}
+
+// Compaction drops expired records from the log
+#[test]
+fn ttl_compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            format!("value{}", key_id),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    let size_before = log_len(temp_dir.path());
+    thread::sleep(Duration::from_millis(200));
+    store.compact_file()?;
+    assert!(log_len(temp_dir.path()) < size_before / 10);
+    assert_eq!(store.keys(), vec!["kept".to_owned()]);
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys(), vec!["kept".to_owned()]);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -128,6 +472,260 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Overwrite every key several times, compact, and check the log shrank without losing data
+#[test]
+fn compact_file_keeps_live_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    for iter in 0..5 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let size_before = dir_size();
+    store.compact_file()?;
+    let size_after = dir_size();
+    assert!(size_after < size_before);
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-4", key_id))
+        );
+    }
+
+    // Writes after compaction land in the new file
+    store.set("key0".to_owned(), "value0-5".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0-5".to_owned()));
+    for key_id in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-4", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Repeatedly overwriting one key should keep triggering compaction so the log stays small
+#[test]
+fn compaction_threshold_bounds_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compaction_threshold: 4 * 1024,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+    };
+
+    for iter in 0..10000 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+        assert!(dir_size() < 8 * 1024);
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("value9999".to_owned()));
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value9999".to_owned()));
+
+    Ok(())
+}
+
+// Dropping the store with no other calls should leave the data on disk
+#[test]
+fn drop_persists_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    clone.set("key2".to_owned(), "value2".to_owned())?;
+    drop(clone);
+
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// With compact_on_drop the last handle rewrites the log on close
+#[test]
+fn drop_compacts_when_configured() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        compact_on_drop: true,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    let clone = store.clone();
+    drop(clone);
+    drop(store);
+
+    let files = log_files(temp_dir.path());
+    assert_eq!(files.len(), 1);
+    // The header and a single timestamped record
+    assert!(fs::metadata(&files[0])?.len() < 64);
+
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Scans return pairs in key order and respect inclusive and exclusive bounds
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key5".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(key.replace("key", "value"), value);
+                key
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let all = store.scan(Bound::Unbounded, Bound::Unbounded)?;
+    assert_eq!(
+        keys(all),
+        vec!["key0", "key1", "key2", "key3", "key4", "key6", "key7", "key8", "key9"]
+    );
+    let inclusive = store.scan(
+        Bound::Included("key2".to_owned()),
+        Bound::Included("key6".to_owned()),
+    )?;
+    assert_eq!(keys(inclusive), vec!["key2", "key3", "key4", "key6"]);
+    let exclusive = store.scan(
+        Bound::Excluded("key2".to_owned()),
+        Bound::Excluded("key6".to_owned()),
+    )?;
+    assert_eq!(keys(exclusive), vec!["key3", "key4"]);
+
+    assert!(store
+        .scan(
+            Bound::Included("key6".to_owned()),
+            Bound::Excluded("key6".to_owned())
+        )?
+        .is_empty());
+    assert!(store
+        .scan(
+            Bound::Included("key8".to_owned()),
+            Bound::Included("key2".to_owned())
+        )?
+        .is_empty());
+    assert!(store
+        .scan(Bound::Excluded("key9".to_owned()), Bound::Unbounded)?
+        .is_empty());
+
+    // Open from disk again and check the removed key stays out of the scan
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let reopened = store.scan(
+        Bound::Included("key4".to_owned()),
+        Bound::Included("key6".to_owned()),
+    )?;
+    assert_eq!(keys(reopened), vec!["key4", "key6"]);
+
+    Ok(())
+}
+
+// The iterator and keys should yield exactly the live set, unaffected by later writes
+#[test]
+fn iterate_live_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..1000).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let iter = store.iter()?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.compact_file()?;
+
+    let mut pairs = iter.collect::<Result<Vec<_>>>()?;
+    pairs.sort();
+    let mut expected: Vec<(String, String)> = (1..1000)
+        .step_by(2)
+        .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+        .collect();
+    expected.sort();
+    assert_eq!(pairs, expected);
+
+    let mut keys = store.keys();
+    keys.sort();
+    let mut expected_keys: Vec<String> = expected.into_iter().map(|(key, _)| key).collect();
+    expected_keys.push("key0".to_owned());
+    expected_keys.sort();
+    assert_eq!(keys, expected_keys);
+
+    Ok(())
+}
+
+#[test]
+fn contains_key_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(store.contains_key(&"key1".to_owned()));
+    assert!(store.contains_key(&"key2".to_owned()));
+    assert!(!store.contains_key(&"key3".to_owned()));
+    assert_eq!(store.len(), 2);
+
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key(&"key1".to_owned()));
+    assert_eq!(store.len(), 1);
+
+    // Open from disk again and check the removed key stays gone
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key(&"key1".to_owned()));
+    assert!(store.contains_key(&"key2".to_owned()));
+    assert_eq!(store.len(), 1);
+    assert!(!store.is_empty());
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -160,22 +758,79 @@ fn concurrent_set() -> Result<()> {
 }
 
 #[test]
-fn concurrent_get() -> Result<()> {
+fn compare_and_swap() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
-    }
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value0".to_owned()),
+        "value1".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.compare_and_swap("key1".to_owned(), None, "value1".to_owned())?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, "value2".to_owned())?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Threads incrementing a counter through CAS retry loops must not lose any updates
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
 
     let mut handles = Vec::new();
-    for thread_id in 0..100 {
+    for _ in 0..16 {
         let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                loop {
+                    let current = store.get("counter".to_owned()).unwrap();
+                    let next = current.as_ref().unwrap().parse::<u64>().unwrap() + 1;
+                    if store
+                        .compare_and_swap("counter".to_owned(), current, next.to_string())
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("1600".to_owned()));
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("1600".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
                     store.get(format!("key{}", key_id)).unwrap(),
                     Some(format!("value{}", key_id))
                 );
@@ -210,3 +865,1402 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn write_batch_applies_all() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+
+    let mut ops: Vec<_> = (0..100)
+        .map(|i| KvRecord::Set((format!("key{}", i), format!("value{}", i))))
+        .collect();
+    ops.push(KvRecord::Rm("removed".to_owned()));
+    ops.push(KvRecord::Rm("never-set".to_owned()));
+    store.write_batch(ops)?;
+
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("removed".to_owned())?, None);
+
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("removed".to_owned())?, None);
+    Ok(())
+}
+
+// A value that refuses to serialize, to make a batch fail part way through
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Fallible(String);
+
+impl fmt::Display for Fallible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Fallible {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if self.0 == "fail" {
+            return Err(serde::ser::Error::custom("refusing to serialize"));
+        }
+        serializer.serialize_newtype_struct("Fallible", &self.0)
+    }
+}
+
+impl Value for Fallible {}
+
+#[test]
+fn write_batch_failure_applies_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), Fallible("before".to_owned()))?;
+    let len_before = log_len(temp_dir.path());
+
+    let mut ops: Vec<_> = (0..100)
+        .map(|i| KvRecord::Set((format!("key{}", i), Fallible(format!("value{}", i)))))
+        .collect();
+    ops[50] = KvRecord::Set(("key50".to_owned(), Fallible("fail".to_owned())));
+    assert!(store.write_batch(ops).is_err());
+
+    assert_eq!(log_len(temp_dir.path()), len_before);
+    assert_eq!(store.len(), 1);
+    assert_eq!(
+        store.get("key0".to_owned())?,
+        Some(Fallible("before".to_owned()))
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    drop(store);
+    let store: KvStore<String, Fallible> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(
+        store.get("key0".to_owned())?,
+        Some(Fallible("before".to_owned()))
+    );
+    Ok(())
+}
+
+#[test]
+fn merge_requires_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    match store.merge("counter".to_owned(), "1".to_owned()) {
+        Err(KvsError::NoMergeOperator) => {}
+        other => panic!("expected NoMergeOperator, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let add = |current: Option<&String>, operand: &String| {
+        let current: u64 = current.map(|value| value.parse().unwrap()).unwrap_or(0);
+        (current + operand.parse::<u64>().unwrap()).to_string()
+    };
+    let store = KvStore::open_with_merge_operator(temp_dir.path(), KvStoreConfig::default(), add)?;
+
+    let mut handles = Vec::new();
+    for _ in 0..16 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                store.merge("counter".to_owned(), "2".to_owned()).unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("3200".to_owned()));
+
+    // Merged values are plain sets, so they read back without the operator
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("3200".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stats_track_writes_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = store.stats()?;
+    assert_eq!(
+        empty,
+        KvStoreStats {
+            file_size: empty.file_size,
+            ..KvStoreStats::default()
+        }
+    );
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let written = store.stats()?;
+    assert_eq!(written.keys, 10);
+    assert_eq!(written.uncompressed_bytes, 0);
+    assert_eq!(written.file_size, empty.file_size + written.live_bytes);
+
+    store.set("key0".to_owned(), "other".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let churned = store.stats()?;
+    assert_eq!(churned.keys, 9);
+    assert!(churned.uncompressed_bytes > 0);
+    assert_eq!(
+        churned.file_size,
+        empty.file_size + churned.live_bytes + churned.uncompressed_bytes
+    );
+
+    store.compact()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.keys, 9);
+    assert_eq!(compacted.uncompressed_bytes, 0);
+    assert_eq!(compacted.compactions, 1);
+    assert_eq!(compacted.live_bytes, churned.live_bytes);
+    assert_eq!(compacted.file_size, empty.file_size + compacted.live_bytes);
+    assert_eq!(compacted.file_size, log_len(temp_dir.path()));
+    Ok(())
+}
+
+// Writes move on to a new log once the active one is full, and keys stay readable from every
+// generation, across a reopen and after compaction folds them back into one file
+#[test]
+fn rotation_across_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    for key_id in 50..60 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(log_files(temp_dir.path()).len() > 2);
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        for key_id in 0..200 {
+            let expected = match key_id {
+                0..=49 => Some(format!("new{}", key_id)),
+                50..=59 => None,
+                _ => Some(format!("value{}", key_id)),
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.len(), 190);
+        Ok(())
+    };
+    check(&store)?;
+    assert_eq!(store.stats()?.file_size, log_len(temp_dir.path()));
+
+    drop(store);
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    check(&store)?;
+
+    store.compact_file()?;
+    assert_eq!(log_files(temp_dir.path()).len(), 1);
+    check(&store)?;
+    Ok(())
+}
+
+// A restored store holds exactly what the store held when the backup was taken
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let backup_path = temp_dir.path().join("store.backup");
+    let store = KvStore::<String, String>::open(&store_dir)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.backup(&backup_path)?;
+
+    store.set("key2".to_owned(), "after backup".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set("new".to_owned(), "after backup".to_owned())?;
+
+    let restored =
+        KvStore::<String, String>::restore(&backup_path, &temp_dir.path().join("restored"))?;
+    assert_eq!(restored.len(), 99);
+    assert_eq!(
+        restored.get("key0".to_owned())?,
+        Some("overwritten".to_owned())
+    );
+    assert_eq!(restored.get("key1".to_owned())?, None);
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(restored.get("new".to_owned())?, None);
+
+    // Restoring over an existing store is refused rather than mixing the two
+    assert!(KvStore::<String, String>::restore(&backup_path, &store_dir).is_err());
+    Ok(())
+}
+
+// Exporting one store and importing into another copies every live pair
+#[test]
+fn json_export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(&temp_dir.path().join("source"))?;
+    for key_id in 0..3000 {
+        store.set(format!("key{}", key_id), format!("value \"{}\"\n", key_id))?;
+    }
+    store.remove("key7".to_owned())?;
+
+    let mut exported = Vec::new();
+    store.export_json(&mut exported)?;
+    assert_eq!(exported.iter().filter(|byte| **byte == b'\n').count(), 2999);
+
+    let imported = KvStore::<String, String>::open(&temp_dir.path().join("dest"))?;
+    assert_eq!(imported.import_json(exported.as_slice())?, 2999);
+    let mut expected = store.iter()?.collect::<Result<Vec<_>>>()?;
+    let mut actual = imported.iter()?.collect::<Result<Vec<_>>>()?;
+    expected.sort();
+    actual.sort();
+    assert_eq!(actual, expected);
+    assert_eq!(imported.get("key7".to_owned())?, None);
+    Ok(())
+}
+
+// A directory seeded with two separate logs opens with the keys of both, and the newer log
+// wins for keys they share
+#[test]
+fn open_merges_seeded_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let seeded = temp_dir.path().join("seeded");
+    fs::create_dir(&seeded)?;
+    for (generation, keys) in [(0, 0..60), (1, 40..100)] {
+        let source = temp_dir.path().join(format!("source{}", generation));
+        let store = KvStore::<String, String>::open(&source)?;
+        for key_id in keys {
+            store.set(
+                format!("key{}", key_id),
+                format!("{}-{}", generation, key_id),
+            )?;
+        }
+        drop(store);
+        // Every store numbers its logs from 1, so each source's log gets its own id here
+        for path in log_files(&source) {
+            fs::copy(&path, seeded.join(format!("{:06}.kvs", generation + 1)))?;
+        }
+    }
+    assert_eq!(log_files(&seeded).len(), 2);
+
+    let store = KvStore::<String, String>::open(&seeded)?;
+    assert_eq!(store.len(), 100);
+    for key_id in 0..100 {
+        let generation = if key_id < 40 { 0 } else { 1 };
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-{}", generation, key_id))
+        );
+    }
+    Ok(())
+}
+
+// Files that aren't `<id>.kvs` logs are left alone, and writes always go to the newest log
+#[test]
+fn open_ignores_stray_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let older = &log_files(temp_dir.path())[0];
+    let data_dir = older.parent().unwrap();
+    let newer = data_dir.join("9999999999999999999.kvs");
+    fs::copy(older, &newer)?;
+    fs::write(data_dir.join("notes.txt"), "not a log")?;
+    fs::write(data_dir.join("backup.kvs"), "not a log either")?;
+    fs::create_dir(data_dir.join("nested"))?;
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let (older_len, newer_len) = (fs::metadata(older)?.len(), fs::metadata(&newer)?.len());
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(fs::metadata(older)?.len(), older_len);
+    assert!(fs::metadata(&newer)?.len() > newer_len);
+    assert_eq!(fs::read_to_string(data_dir.join("notes.txt"))?, "not a log");
+    Ok(())
+}
+
+// A log that can't be read fails the open with an error instead of a panic
+#[test]
+fn open_reports_unreadable_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("1.kvs"))?;
+    assert!(KvStore::<String, String>::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// The server's config.info can share the data directory without being mistaken for a log
+#[test]
+fn open_ignores_config_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config_info = temp_dir.path().join("config.info");
+    fs::write(&config_info, "\"Kvs\"")?;
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact_file()?;
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::read_to_string(&config_info)?, "\"Kvs\"");
+    Ok(())
+}
+
+// Logs go in a data subdirectory, and stores written before that move their logs over on open
+#[test]
+fn data_subdirectory_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let logs = log_files(temp_dir.path());
+    assert_eq!(logs.len(), 1);
+    assert_eq!(
+        logs[0].parent(),
+        Some(temp_dir.path().join("data").as_path())
+    );
+
+    // Put the log back where older versions kept it
+    let flat = temp_dir.path().join(logs[0].file_name().unwrap());
+    fs::rename(&logs[0], &flat)?;
+    fs::remove_dir_all(temp_dir.path().join("data"))?;
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!flat.exists());
+    assert_eq!(log_files(&temp_dir.path().join("data")).len(), 1);
+    Ok(())
+}
+
+// A read-only store answers reads, refuses every kind of write and leaves the files untouched
+#[test]
+fn read_only_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStore::<String, String>::open_read_only(temp_dir.path()),
+        Err(KvsError::FileListEmpty)
+    ));
+
+    let writer = KvStore::<String, String>::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    writer.remove("key2".to_owned())?;
+    let len = log_len(temp_dir.path());
+
+    let store = KvStore::<String, String>::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.write_batch(vec![KvRecord::Rm("key1".to_owned())]),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compact_file(), Err(KvsError::ReadOnly)));
+    drop(store);
+    assert_eq!(log_len(temp_dir.path()), len);
+    assert_eq!(writer.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// flush makes writes durable even when the sync policy would have held on to them, so they
+// survive the process dying without the store being dropped
+#[test]
+fn flush_survives_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        sync_policy: SyncPolicy::OnDropOnly,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    KvsEngine::flush(&store)?;
+    store.set("unflushed".to_owned(), "value".to_owned())?;
+    // Skip Drop, which would flush the rest, as a crash would
+    std::mem::forget(store);
+
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("unflushed".to_owned())?, None);
+    Ok(())
+}
+
+// Reads racing with compaction and rotation always see a whole value that was really written
+// for that key. The readers lock keeps a swap from happening under an in-flight read
+#[test]
+fn reads_during_repeated_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size: 4 * 1024,
+        sync_policy: SyncPolicy::EveryN(7),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("key{}-v0", key_id))?;
+    }
+
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || -> Result<usize> {
+                let mut reads = 0;
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id))?.unwrap();
+                        let (owner, version) = value.split_once("-v").unwrap();
+                        assert_eq!(owner, format!("key{}", key_id));
+                        assert!(version.parse::<u32>().unwrap() < 50);
+                        reads += 1;
+                    }
+                }
+                Ok(reads)
+            })
+        })
+        .collect();
+
+    for version in 1..50 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("key{}-v{}", key_id, version),
+            )?;
+        }
+        store.compact_file()?;
+    }
+    done.store(true, std::sync::atomic::Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().unwrap()? > 0);
+    }
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("key{}-v49", key_id))
+        );
+    }
+
+    // An iterator keeps its own handles, so it can finish after the files it started on are gone
+    let iter = store.iter()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "newer".to_owned())?;
+    }
+    store.compact_file()?;
+    let pairs = iter.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 100);
+    assert!(pairs
+        .iter()
+        .all(|(key, value)| *value == format!("{}-v49", key)));
+    Ok(())
+}
+
+// Keys and values over the limits are refused before anything reaches the log
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_key_size: Some(16),
+        max_value_size: Some(64),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    store.set("key".to_owned(), "x".repeat(32))?;
+    let len = log_len(temp_dir.path());
+
+    assert!(matches!(
+        store.set("key".to_owned(), "x".repeat(100)),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert!(matches!(
+        store.set("k".repeat(100), "value".to_owned()),
+        Err(KvsError::KeyTooLarge)
+    ));
+    assert!(matches!(
+        store.write_batch(vec![
+            KvRecord::Set(("other".to_owned(), "value".to_owned())),
+            KvRecord::Set(("key".to_owned(), "x".repeat(100))),
+        ]),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert!(matches!(
+        store.compare_and_swap("key".to_owned(), Some("x".repeat(32)), "x".repeat(100)),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert_eq!(log_len(temp_dir.path()), len);
+    assert_eq!(store.get("key".to_owned())?, Some("x".repeat(32)));
+    assert_eq!(store.get("other".to_owned())?, None);
+    Ok(())
+}
+
+// Two transactions race to move from the same balance, and only one of them can commit
+#[test]
+fn conflicting_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, u64>::open(temp_dir.path())?;
+    store.set("balance".to_owned(), 100)?;
+    store.set("other".to_owned(), 0)?;
+
+    let barrier = Arc::new(Barrier::new(2));
+    let handles: Vec<_> = (0..2)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<bool> {
+                let (balance, version) = store.get_versioned("balance".to_owned())?;
+                let balance = balance.unwrap();
+                // Both have read before either commits
+                barrier.wait();
+                store.commit(
+                    vec![("balance".to_owned(), version)],
+                    vec![
+                        KvRecord::Set(("balance".to_owned(), balance - 60)),
+                        KvRecord::Set((format!("spent{}", i), 60)),
+                    ],
+                )
+            })
+        })
+        .collect();
+    let committed: Vec<bool> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<_>>()?;
+    assert_eq!(committed.iter().filter(|c| **c).count(), 1);
+    assert_eq!(store.get("balance".to_owned())?, Some(40));
+    let spent = (0..2)
+        .filter_map(|i| store.get(format!("spent{}", i)).unwrap())
+        .count();
+    assert_eq!(spent, 1);
+
+    // Versions move with writes but not with compaction, and an unread key doesn't matter
+    let (_, version) = store.get_versioned("balance".to_owned())?;
+    store.compact_file()?;
+    assert_eq!(store.get_versioned("balance".to_owned())?.1, version);
+    store.set("other".to_owned(), 1)?;
+    assert!(store.commit(
+        vec![("balance".to_owned(), version)],
+        vec![KvRecord::Rm("balance".to_owned())],
+    )?);
+    assert_eq!(store.get_versioned("balance".to_owned())?, (None, 0));
+    Ok(())
+}
+
+// The ordered index gives the same answers, and hands keys back sorted without being asked
+#[test]
+fn ordered_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || KvStoreConfig {
+        index: IndexKind::Ordered,
+        compaction_threshold: 256,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config())?;
+    for key_id in [5, 3, 9, 1, 7] {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for iter in 0..20 {
+        store.set("key3".to_owned(), format!("value{}", iter))?;
+    }
+    store.remove("key9".to_owned())?;
+    store.set_with_ttl("key0".to_owned(), "gone".to_owned(), Duration::ZERO)?;
+
+    assert_eq!(store.keys(), vec!["key1", "key3", "key5", "key7"]);
+    assert_eq!(store.len(), 4);
+    assert_eq!(
+        store.scan(
+            Bound::Excluded("key1".to_owned()),
+            Bound::Included("key5".to_owned())
+        )?,
+        vec![
+            ("key3".to_owned(), "value19".to_owned()),
+            ("key5".to_owned(), "value5".to_owned()),
+        ]
+    );
+    let keys: Vec<String> = store.iter()?.map(|pair| pair.unwrap().0).collect();
+    assert_eq!(keys, vec!["key1", "key3", "key5", "key7"]);
+    assert!(store.stats()?.compactions > 0);
+    drop(store);
+
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config())?;
+    assert_eq!(store.keys(), vec!["key1", "key3", "key5", "key7"]);
+    assert_eq!(store.get("key3".to_owned())?, Some("value19".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn set_and_remove_returning() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(
+        store.set_returning("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        store.set_returning("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(store.remove_returning("key1".to_owned())?, "value2");
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(matches!(
+        store.remove_returning("key1".to_owned()),
+        Err(KvsError::NonExistantKey)
+    ));
+
+    // An expired value counts as absent
+    store.set_with_ttl("key2".to_owned(), "gone".to_owned(), Duration::ZERO)?;
+    assert_eq!(
+        store.set_returning("key2".to_owned(), "value".to_owned())?,
+        None
+    );
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The manifest written on first open is checked on every open after that
+#[test]
+fn manifest_checked_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest = temp_dir.path().join("MANIFEST");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let written = fs::read_to_string(&manifest)?;
+    assert!(written.contains(r#""format_version":2"#));
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    fs::write(
+        &manifest,
+        written.replace(r#""format_version":2"#, r#""format_version":3"#),
+    )?;
+    for opened in [
+        KvStore::<String, String>::open(temp_dir.path()).map(drop),
+        KvStore::<String, String>::open_read_only(temp_dir.path()).map(drop),
+    ] {
+        match opened {
+            Err(KvsError::IncompatibleFormat(msg)) => assert!(msg.contains("version 3")),
+            Err(e) => panic!("expected format error, got {:?}", e),
+            Ok(_) => panic!("expected format error opening store"),
+        }
+    }
+
+    fs::write(&manifest, b"not a manifest")?;
+    assert!(matches!(
+        KvStore::<String, String>::open(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat(_))
+    ));
+
+    // A store from before the manifest gets one on its next open
+    fs::remove_file(&manifest)?;
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::read_to_string(&manifest)?, written);
+    Ok(())
+}
+
+// The format tag of the first record in the store's only log
+fn first_record_tag(dir: &std::path::Path) -> u8 {
+    let log = fs::read(log_files(dir).pop().unwrap()).unwrap();
+    log[4 + 8] >> 4
+}
+
+// Logs written in the v1 record format read back the same through code that writes v2, and
+// the two can sit side by side in one store
+#[test]
+fn v1_records_read_by_v2() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest = temp_dir.path().join("MANIFEST");
+    let v1 = KvStoreConfig {
+        record_format: RecordFormat::V1,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), v1.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // Already expired, so it only stays gone if the expiry is read back
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::ZERO)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    let (_, written_at) = store.get_meta("key1".to_owned())?.unwrap();
+    drop(store);
+    assert_eq!(first_record_tag(temp_dir.path()), 0);
+    assert!(fs::read_to_string(&manifest)?.contains(r#""format_version":1"#));
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert!(fs::read_to_string(&manifest)?.contains(r#""format_version":2"#));
+    assert_eq!(
+        store.get_meta("key1".to_owned())?,
+        Some(("value1".to_owned(), written_at))
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    assert_eq!(first_record_tag(temp_dir.path()), 2);
+    drop(store);
+
+    // Asking for v1 again doesn't lower the manifest under the v2 records
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), v1)?;
+    assert!(fs::read_to_string(&manifest)?.contains(r#""format_version":2"#));
+    assert_eq!(
+        store.get_meta("key1".to_owned())?,
+        Some(("value1".to_owned(), written_at))
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// A store kept in memory answers exactly like one on disk, through rotation and compaction
+#[test]
+fn in_memory_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size: 256,
+        ..KvStoreConfig::default()
+    };
+    let on_disk = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let in_memory = KvStore::open_in_memory_with_config(config)?;
+
+    for store in [&on_disk, &in_memory] {
+        for iter in 0..20 {
+            for key_id in 0..10 {
+                store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            }
+        }
+        store.remove("key3".to_owned())?;
+        assert!(matches!(
+            store.remove("key3".to_owned()),
+            Err(KvsError::NonExistantKey)
+        ));
+    }
+    let scan = |store: &KvStore<String, String>| store.scan(Bound::Unbounded, Bound::Unbounded);
+    assert_eq!(scan(&in_memory)?, scan(&on_disk)?);
+    assert_eq!(in_memory.get("key3".to_owned())?, None);
+    assert_eq!(
+        in_memory.get("key4".to_owned())?,
+        Some("value19".to_owned())
+    );
+
+    in_memory.compact()?;
+    assert_eq!(scan(&in_memory)?, scan(&on_disk)?);
+    assert_eq!(in_memory.stats()?.keys, 9);
+    assert_eq!(
+        in_memory.stats()?.file_size,
+        on_disk.stats()?.live_bytes + 4
+    );
+    Ok(())
+}
+
+// Mapped reads see every write, across rotation, compaction and reopening
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        mmap_reads: true,
+        max_file_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            // Read straight back, so the active log has to be remapped as it grows
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", iter))
+            );
+        }
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "after".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+    drop(store);
+
+    let store: KvStore<String, String> = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 20);
+    Ok(())
+}
+
+// A record cut short at the end of the active log, as a crash mid-write leaves it, is dropped on
+// open. The same damage anywhere else is still an error
+#[test]
+fn torn_tail_recovered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let log_path = log_files(temp_dir.path()).pop().unwrap();
+    let good_len = fs::metadata(&log_path)?.len();
+    let mut log = OpenOptions::new().append(true).open(&log_path)?;
+    log.write_all(b"\x12\x34\x56\x78\x00\x00\x01\x00partial")?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_path)?.len(), good_len);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // Cut the last record of a log that has since been rotated away from
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size: 64,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+    let mut logs = log_files(temp_dir.path());
+    logs.sort();
+    let oldest = OpenOptions::new().write(true).open(&logs[0])?;
+    let oldest_len = oldest.metadata()?.len();
+    oldest.set_len(oldest_len - 2)?;
+    assert!(matches!(
+        KvStore::<String, String>::open_with_config(temp_dir.path(), config),
+        Err(KvsError::Corruption { .. })
+    ));
+    Ok(())
+}
+
+// Every set records when it was written, and that survives compaction and reopening
+#[test]
+fn write_timestamps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let before = SystemTime::now();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let after = SystemTime::now();
+    let tolerance = Duration::from_millis(1);
+    let (value, written_at) = store.get_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert!(written_at + tolerance >= before && written_at <= after);
+
+    thread::sleep(Duration::from_millis(20));
+    store.write_batch(vec![KvRecord::Set((
+        "key2".to_owned(),
+        "value2".to_owned(),
+    ))])?;
+    let (_, batch_written_at) = store.get_meta("key2".to_owned())?.unwrap();
+    assert!(batch_written_at > written_at);
+    assert_eq!(store.get_meta("key3".to_owned())?, None);
+
+    store.compact()?;
+    assert_eq!(
+        store.get_meta("key1".to_owned())?,
+        Some(("value1".to_owned(), written_at))
+    );
+    drop(store);
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_meta("key1".to_owned())?,
+        Some(("value1".to_owned(), written_at))
+    );
+    Ok(())
+}
+
+// An ingest lands all at once on top of what was there, and a failed one leaves no trace
+#[test]
+fn ingest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_value_size: Some(64),
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    store.set("other".to_owned(), "kept".to_owned())?;
+    let events = store.watch("key".to_owned())?;
+
+    let pairs = (0..1000).map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)));
+    assert_eq!(store.ingest(pairs)?, 1000);
+    assert_eq!(store.len(), 1001);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(events.try_iter().count(), 1000);
+    assert!(store.stats()?.uncompressed_bytes > 0);
+    store.set("key1".to_owned(), "after".to_owned())?;
+
+    let logs = log_files(temp_dir.path()).len();
+    let oversized = vec![
+        ("new1".to_owned(), "value".to_owned()),
+        ("new2".to_owned(), "v".repeat(100)),
+    ];
+    assert!(matches!(
+        store.ingest(oversized),
+        Err(KvsError::ValueTooLarge)
+    ));
+    assert_eq!(store.get("new1".to_owned())?, None);
+    assert_eq!(log_files(temp_dir.path()).len(), logs);
+    drop(store);
+
+    let store: KvStore<String, String> = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.len(), 1001);
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Opening from the index saved at close gives the same store as replaying every log, picks up
+// writes made after it was saved, and falls back to a full replay once it no longer matches
+#[test]
+fn saved_index_matches_replay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size: 1024,
+        ..KvStoreConfig::default()
+    };
+    let replay_config = KvStoreConfig {
+        index_snapshots: false,
+        ..config.clone()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 50..60 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    drop(store);
+    let index_path = temp_dir.path().join("data").join("INDEX");
+    assert!(index_path.exists());
+
+    // Writes the saved index doesn't cover, left behind without saving a new one
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    store.remove("key100".to_owned())?;
+    store.flush()?;
+    std::mem::forget(store);
+
+    let everything =
+        |store: &KvStore<String, String>| store.scan(Bound::Unbounded, Bound::Unbounded);
+    let replayed = KvStore::<String, String>::open_with_config(temp_dir.path(), replay_config)?;
+    let expected = everything(&replayed)?;
+    assert_eq!(expected.len(), 189);
+    drop(replayed);
+
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(everything(&store)?, expected);
+    assert_eq!(store.len(), 189);
+    drop(store);
+
+    fs::write(&index_path, b"garbage")?;
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(everything(&store)?, expected);
+    Ok(())
+}
+
+// The index is saved while the store is still open, after compaction and once the interval
+// has passed, so a store that is never closed still leaves one behind
+#[test]
+fn saved_index_without_close() -> Result<()> {
+    for config in [
+        KvStoreConfig {
+            index_snapshot_interval: None,
+            ..KvStoreConfig::default()
+        },
+        KvStoreConfig {
+            index_snapshot_interval: Some(Duration::ZERO),
+            ..KvStoreConfig::default()
+        },
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let index_path = temp_dir.path().join("data").join("INDEX");
+        let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        if config.index_snapshot_interval.is_none() {
+            assert!(!index_path.exists());
+            store.compact_file()?;
+        }
+        assert!(index_path.exists());
+        store.set("key0".to_owned(), "after".to_owned())?;
+        store.flush()?;
+        std::mem::forget(store);
+
+        let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.len(), 100);
+        assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+        assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    }
+    Ok(())
+}
+
+// A saved index is only trusted for logs whose tail still matches, so one carried over to a
+// store whose log has the same name and length is thrown away
+#[test]
+fn saved_index_checks_log_tail() -> Result<()> {
+    let first_dir = TempDir::new().expect("unable to create temporary working directory");
+    let second_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(first_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::<String, String>::open(second_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let first_logs = log_files(first_dir.path());
+    let second_logs = log_files(second_dir.path());
+    assert_eq!(first_logs.len(), 1);
+    assert_eq!(
+        first_logs[0].file_name(),
+        second_logs[0].file_name(),
+        "both stores should start from the same log"
+    );
+    assert_eq!(
+        fs::metadata(&first_logs[0])?.len(),
+        fs::metadata(&second_logs[0])?.len()
+    );
+    fs::copy(
+        first_dir.path().join("data").join("INDEX"),
+        second_dir.path().join("data").join("INDEX"),
+    )?;
+
+    let store = KvStore::<String, String>::open(second_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Writers racing on the same keys leave the index pointing at the last record in the log for
+// each, so the store reads the same before and after a full replay
+#[test]
+fn concurrent_same_key_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..200 {
+                    let key = format!("key{}", i % 10);
+                    if i % 7 == thread_id {
+                        let _ = store.remove(key);
+                    } else {
+                        store.set(key, format!("{}-{}", thread_id, i))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let live = store.scan(Bound::Unbounded, Bound::Unbounded)?;
+    drop(store);
+    let config = KvStoreConfig {
+        index_snapshots: false,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?, live);
+    Ok(())
+}
+
+// The expected key count only sizes the index, so a store opened with one too small, too large
+// or for an ordered index reads back the same as any other
+#[test]
+fn open_with_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open_with_capacity(temp_dir.path(), 10)?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    for (expected_keys, index) in [
+        (10, IndexKind::Hash),
+        (100_000, IndexKind::Hash),
+        (100_000, IndexKind::Ordered),
+    ] {
+        let config = KvStoreConfig {
+            expected_keys: Some(expected_keys),
+            index,
+            index_snapshots: false,
+            ..KvStoreConfig::default()
+        };
+        let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.len(), 1000);
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Removing a range takes out exactly the keys inside it, its tombstones are gone after
+// compaction, and the removal survives a reopen
+#[test]
+fn remove_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("{:03}", key_id), format!("value{}", key_id))?;
+    }
+    let removed = store.remove_range(
+        Bound::Included("020".to_owned()),
+        Bound::Excluded("050".to_owned()),
+    )?;
+    assert_eq!(removed, 30);
+    assert_eq!(
+        store.remove_range(Bound::Excluded("095".to_owned()), Bound::Unbounded)?,
+        4
+    );
+    assert_eq!(
+        store.remove_range(
+            Bound::Included("020".to_owned()),
+            Bound::Excluded("050".to_owned())
+        )?,
+        0
+    );
+
+    let check = |store: &KvStore<String, String>| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = if (20..50).contains(&key_id) || key_id > 95 {
+                None
+            } else {
+                Some(format!("value{}", key_id))
+            };
+            assert_eq!(store.get(format!("{:03}", key_id))?, expected);
+        }
+        assert_eq!(store.len(), 66);
+        Ok(())
+    };
+    check(&store)?;
+    assert!(store.stats()?.uncompressed_bytes > 0);
+    store.compact()?;
+    assert_eq!(store.stats()?.uncompressed_bytes, 0);
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    check(&store)?;
+    Ok(())
+}
+
+// Each rotation takes the next id, however quickly they come, so the logs list in the order
+// they were written
+#[test]
+fn rotation_file_ids_are_sequential() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        max_file_size: 1,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let mut names: Vec<String> = log_files(temp_dir.path())
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["000001.kvs", "000002.kvs", "000003.kvs"]);
+
+    store.compact_file()?;
+    let names: Vec<String> = log_files(temp_dir.path())
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, ["000004.kvs"]);
+    drop(store);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Writes still sitting in the buffer when the store goes away end up in the log
+#[test]
+fn drop_persists_buffered_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = KvStoreConfig {
+        sync_policy: SyncPolicy::OnDropOnly,
+        write_buffer_size: 1024 * 1024,
+        ..KvStoreConfig::default()
+    };
+    let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+    let empty_len = log_len(temp_dir.path());
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(log_len(temp_dir.path()), empty_len);
+    drop(store);
+    assert!(log_len(temp_dir.path()) > empty_len);
+
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1000);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// A store reopened after churning still knows how much of its log is dead, whether it replays
+// every log or starts from the saved index, so compaction is due as soon as it was before
+#[test]
+fn dead_bytes_survive_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let dead_bytes = store.stats()?.uncompressed_bytes;
+    assert!(dead_bytes > 0);
+    drop(store);
+
+    for index_snapshots in [true, false] {
+        let config = KvStoreConfig {
+            index_snapshots,
+            ..KvStoreConfig::default()
+        };
+        let store = KvStore::<String, String>::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.stats()?.uncompressed_bytes, dead_bytes);
+    }
+
+    // Writes after the saved index was taken are counted on top of it
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    store.set("key10".to_owned(), "again".to_owned())?;
+    let dead_bytes = store.stats()?.uncompressed_bytes;
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.uncompressed_bytes, dead_bytes);
+    store.compact()?;
+    assert_eq!(store.stats()?.uncompressed_bytes, 0);
+    drop(store);
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.uncompressed_bytes, 0);
+    Ok(())
+}
+
+// Clones share one dead byte count, so overwrites through any handle add up towards compaction
+#[test]
+fn clones_share_dead_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let clone = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    clone.set("key1".to_owned(), "value2".to_owned())?;
+    let after_one = store.stats()?.uncompressed_bytes;
+    assert!(after_one > 0);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    clone.remove("key1".to_owned())?;
+    let total = store.stats()?.uncompressed_bytes;
+    assert!(total > after_one * 2);
+    assert_eq!(clone.stats()?.uncompressed_bytes, total);
+
+    clone.compact()?;
+    assert_eq!(store.stats()?.uncompressed_bytes, 0);
+    Ok(())
+}
+
+// A prefix's end bound sits just past every key starting with it, so scanning up to it finds
+// exactly the keys with the prefix, including ones with the largest chars after it
+#[test]
+fn prefix_end_bounds_scan() -> Result<()> {
+    assert_eq!(
+        "user:".to_owned().prefix_end(),
+        Bound::Excluded("user;".to_owned())
+    );
+    assert_eq!(
+        "a\u{10FFFF}".to_owned().prefix_end(),
+        Bound::Excluded("b".to_owned())
+    );
+    assert_eq!(
+        "\u{D7FF}".to_owned().prefix_end(),
+        Bound::Excluded("\u{E000}".to_owned())
+    );
+    assert_eq!("\u{10FFFF}".to_owned().prefix_end(), Bound::Unbounded);
+    assert_eq!(String::new().prefix_end(), Bound::Unbounded);
+    assert_eq!(7u64.prefix_end(), Bound::Included(7));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<String, String>::open(temp_dir.path())?;
+    let keys = [
+        "a",
+        "a\u{10FFFF}",
+        "a\u{10FFFF}z",
+        "ab",
+        "b",
+        "user:1",
+        "user;",
+        "users",
+    ];
+    for key in keys {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    for prefix in ["a", "a\u{10FFFF}", "user:", "user", ""] {
+        let prefix = prefix.to_owned();
+        let scanned: Vec<String> = store
+            .scan(Bound::Included(prefix.clone()), prefix.prefix_end())?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let mut expected: Vec<String> = keys
+            .iter()
+            .map(|key| key.to_string())
+            .filter(|key| key.has_prefix(&prefix))
+            .collect();
+        expected.sort();
+        assert_eq!(scanned, expected, "prefix {:?}", prefix);
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use kvs::engine::positional::read_exact_at;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
+
+#[test]
+fn read_exact_at_offsets() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data");
+    File::create(&path)
+        .unwrap()
+        .write_all(b"0123456789")
+        .unwrap();
+    let file = File::open(&path).unwrap();
+
+    let mut buf = [0u8; 4];
+    read_exact_at(&file, &mut buf, 3).unwrap();
+    assert_eq!(&buf, b"3456");
+    read_exact_at(&file, &mut buf, 0).unwrap();
+    assert_eq!(&buf, b"0123");
+
+    let err = read_exact_at(&file, &mut buf, 8).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+// Many threads reading different offsets of one shared handle must not interfere
+#[test]
+fn read_exact_at_concurrent() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data");
+    let contents: Vec<u8> = (0..=255).collect();
+    File::create(&path).unwrap().write_all(&contents).unwrap();
+    let file = Arc::new(File::open(&path).unwrap());
+
+    let handles: Vec<_> = (0..8u64)
+        .map(|thread_id| {
+            let file = file.clone();
+            thread::spawn(move || {
+                for i in 0..1000u64 {
+                    let offset = (thread_id * 31 + i) % 250;
+                    let mut buf = [0u8; 6];
+                    read_exact_at(&file, &mut buf, offset).unwrap();
+                    let expected: Vec<u8> = (offset as u8..offset as u8 + 6).collect();
+                    assert_eq!(buf.to_vec(), expected);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
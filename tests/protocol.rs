@@ -0,0 +1,1206 @@
+use assert_cmd::prelude::*;
+use kvs::client::{KvsClient, KvsClientPool};
+use kvs::engine::store::{KvRecord, KvStore};
+use kvs::engine::watch::WatchEvent;
+use kvs::engine::KvsEngine;
+use kvs::protocol::{
+    read_frame, write_frame, ErrorCode, KvError, KvRequest, KvResponse, Truncated,
+    DEFAULT_MAX_FRAME_SIZE, TRUNCATED_LEN,
+};
+use kvs::server::{self, AuthToken, ServerConfig};
+use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::ThreadPool;
+use kvs::transport::{Listener, ServerAddr};
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start_server(temp_dir: &TempDir, addr: &str) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child
+}
+
+// Frames are self-delimiting, so requests can be pipelined on one connection
+#[test]
+fn pipelined_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = start_server(&temp_dir, addr);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_frame(
+        &mut stream,
+        &KvRequest::<String, String>::Set(("key1".to_owned(), "value\n\nwith newlines".to_owned())),
+    )
+    .unwrap();
+    write_frame(
+        &mut stream,
+        &KvRequest::<String, String>::Get("key1".to_owned()),
+    )
+    .unwrap();
+    write_frame(
+        &mut stream,
+        &KvRequest::<String, String>::Rm("key1".to_owned()),
+    )
+    .unwrap();
+
+    let set: KvResponse<String> = read_frame(&mut stream).unwrap().unwrap();
+    assert_eq!(set.value.unwrap(), None);
+    let get: KvResponse<String> = read_frame(&mut stream).unwrap().unwrap();
+    assert_eq!(
+        get.value.unwrap(),
+        Some("value\n\nwith newlines".to_owned())
+    );
+    let rm: KvResponse<String> = read_frame(&mut stream).unwrap().unwrap();
+    assert_eq!(rm.value.unwrap(), None);
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn error_response_round_trip() {
+    let response: KvResponse<String> = KvResponse::new(Err(KvError::NonExistantKey));
+    let json = serde_json::to_string(&response).unwrap();
+    let decoded: KvResponse<String> = serde_json::from_str(&json).unwrap();
+    assert!(matches!(decoded.value, Err(KvError::NonExistantKey)));
+
+    let response: KvResponse<String> = KvResponse::new(Err(KvError::Corruption { offset: 42 }));
+    let mut framed = Vec::new();
+    write_frame(&mut framed, &response).unwrap();
+    let decoded: KvResponse<String> = read_frame(&mut framed.as_slice()).unwrap().unwrap();
+    assert!(matches!(
+        decoded.value,
+        Err(KvError::Corruption { offset: 42 })
+    ));
+}
+
+// A stream that ends part way through a length prefix is an error, not a clean end, and
+// read_frame won't allocate for a frame past the default limit
+#[test]
+fn truncated_frame_length() {
+    let mut empty: &[u8] = &[];
+    assert!(read_frame::<_, KvResponse<String>>(&mut empty)
+        .unwrap()
+        .is_none());
+    for len in 1..4 {
+        let mut partial: &[u8] = &[0u8; 3][..len];
+        assert!(read_frame::<_, KvResponse<String>>(&mut partial).is_err());
+    }
+
+    let too_long = ((DEFAULT_MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+    assert!(matches!(
+        read_frame::<_, KvResponse<String>>(&mut &too_long[..]),
+        Err(KvError::ValueTooLarge)
+    ));
+}
+
+// Clients going away at awkward moments shouldn't take workers down with them
+#[test]
+fn server_survives_dropped_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = start_server(&temp_dir, addr);
+
+    // Hang up half way through a frame
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&100u32.to_be_bytes()).unwrap();
+    stream.write_all(b"{\"Get\"").unwrap();
+    drop(stream);
+
+    // Send garbage
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_frame(&mut stream, &"not a request").unwrap();
+    drop(stream);
+
+    // Hang up without waiting for the responses
+    for i in 0..20 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write_frame(
+            &mut stream,
+            &KvRequest::<String, String>::Set(("key1".to_owned(), format!("value{}", i))),
+        )
+        .unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
+    }
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write_frame(
+        &mut stream,
+        &KvRequest::<String, String>::Get("key1".to_owned()),
+    )
+    .unwrap();
+    let get: KvResponse<String> = read_frame(&mut stream).unwrap().unwrap();
+    assert!(get.value.unwrap().is_some());
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// The server isn't tied to strings, any engine's key and value types can go over the wire
+#[test]
+fn typed_server_round_trip() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<u64, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(2)?, &shutdown)
+        })
+    };
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(
+        &mut stream,
+        &KvRequest::<u64, String>::Set((7, "seven".to_owned())),
+    )?;
+    let set: KvResponse<String> = read_frame(&mut stream)?.unwrap();
+    assert_eq!(set.value?, None);
+    write_frame(&mut stream, &KvRequest::<u64, String>::Get(7))?;
+    let get: KvResponse<String> = read_frame(&mut stream)?.unwrap();
+    assert_eq!(get.value?, Some("seven".to_owned()));
+    drop(stream);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+
+    let store: KvStore<u64, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(7)?, Some("seven".to_owned()));
+    Ok(())
+}
+
+// One client can carry any number of requests over a single connection
+#[test]
+fn persistent_client() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(2)?, &shutdown)
+        })
+    };
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    for key_id in 0..500 {
+        client.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..500 {
+        assert_eq!(
+            client.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    client.remove("key0".to_owned())?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key0".to_owned()),
+        Err(KvError::NonExistantKey)
+    ));
+    // An error response leaves the connection usable
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// Many threads can share a pool, which never opens more connections than it was given
+#[test]
+fn client_pool_from_many_threads() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(8)?, &shutdown)
+        })
+    };
+
+    let pool: KvsClientPool = KvsClientPool::new(addr, 4)?;
+    let workers: Vec<_> = (0..16)
+        .map(|thread_id| {
+            let pool = pool.clone();
+            thread::spawn(move || -> kvs::Result<()> {
+                for key_id in 0..50 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    pool.set(key.clone(), format!("value{}", key_id))?;
+                    assert_eq!(pool.get(key)?, Some(format!("value{}", key_id)));
+                    assert!(pool.size() <= 4);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+    assert_eq!(pool.get("key15-49".to_owned())?, Some("value49".to_owned()));
+    assert!(pool.size() <= 4);
+    drop(pool);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// A server that answers the first request on each connection, then reads the second and hangs
+// up without answering, as if the response had been lost. Returns the requests it read
+fn lossy_server(listener: TcpListener) -> Arc<std::sync::Mutex<Vec<String>>> {
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&seen);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let seen = Arc::clone(&recorded);
+            thread::spawn(move || {
+                for answered in [true, false] {
+                    let request: KvRequest<String, String> = match read_frame(&mut stream) {
+                        Ok(Some(request)) => request,
+                        _ => return,
+                    };
+                    let name = format!("{:?}", request);
+                    seen.lock()
+                        .unwrap()
+                        .push(name[..name.find('(').unwrap_or(name.len())].to_owned());
+                    if answered {
+                        let response: KvResponse<String> = KvResponse::new(Ok(None));
+                        write_frame(&mut stream, &response).unwrap();
+                    }
+                }
+            });
+        }
+    });
+    seen
+}
+
+// A pooled connection that fails after sending a write isn't retried, since the server may
+// already have applied it, while a read is retried on a fresh connection
+#[test]
+fn client_pool_retries_only_reads() -> kvs::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let seen = lossy_server(listener);
+    let pool: KvsClientPool = KvsClientPool::new(addr, 1)?;
+
+    pool.ping()?;
+    assert!(matches!(
+        pool.remove("key".to_owned()),
+        Err(KvError::IOError(_))
+    ));
+    assert_eq!(*seen.lock().unwrap(), ["Ping", "Rm"]);
+
+    pool.ping()?;
+    assert_eq!(pool.get("key".to_owned())?, None);
+    assert_eq!(*seen.lock().unwrap(), ["Ping", "Rm", "Ping", "Get", "Get"]);
+    Ok(())
+}
+
+// Errors carry a stable code and message on the wire, next to the error itself
+#[test]
+fn error_codes_over_the_wire() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(2)?, &shutdown)
+        })
+    };
+
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(
+        &mut stream,
+        &KvRequest::<String, String>::Rm("missing".to_owned()),
+    )?;
+    let raw: serde_json::Value = read_frame(&mut stream)?.unwrap();
+    assert_eq!(raw["error"]["code"], "key_not_found");
+    assert_eq!(raw["error"]["message"], "Key not found");
+
+    write_frame(
+        &mut stream,
+        &KvRequest::<String, String>::Set(("key".to_owned(), "value".to_owned())),
+    )?;
+    let set: KvResponse<String> = read_frame(&mut stream)?.unwrap();
+    assert!(set.error.is_none());
+    drop(stream);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn error_code_mapping() {
+    let cases = [
+        (KvError::NonExistantKey, ErrorCode::KeyNotFound),
+        (KvError::Corruption { offset: 0 }, ErrorCode::Corruption),
+        (KvError::Compression(String::new()), ErrorCode::Corruption),
+        (KvError::IOError(String::new()), ErrorCode::Io),
+        (
+            KvError::SerializationError(String::new()),
+            ErrorCode::Serialization,
+        ),
+        (KvError::FileListEmpty, ErrorCode::Config),
+        (KvError::WrongEngine, ErrorCode::Config),
+        (KvError::WrongCodec, ErrorCode::Config),
+        (
+            KvError::IncompatibleFormat("format version 2".to_owned()),
+            ErrorCode::Config,
+        ),
+        (KvError::NoMergeOperator, ErrorCode::Unsupported),
+        (KvError::KeyTooLarge, ErrorCode::TooLarge),
+        (KvError::ValueTooLarge, ErrorCode::TooLarge),
+        (KvError::ReadOnly, ErrorCode::Unsupported),
+        (KvError::QueueFull, ErrorCode::Busy),
+        (KvError::TooManyConnections, ErrorCode::Busy),
+        (KvError::PoolStopped, ErrorCode::Unavailable),
+        (KvError::CorruptConfig(String::new()), ErrorCode::Config),
+        (
+            KvError::ConnectionFailed(String::new()),
+            ErrorCode::Unavailable,
+        ),
+        (
+            KvError::ThreadPoolBuildError(String::new()),
+            ErrorCode::Internal,
+        ),
+        (KvError::PermissionDenied, ErrorCode::PermissionDenied),
+        (KvError::Unauthorized, ErrorCode::Unauthorized),
+        (KvError::Unsupported, ErrorCode::Unsupported),
+        (KvError::Conflict, ErrorCode::Conflict),
+        (KvError::Other, ErrorCode::Internal),
+    ];
+    for (error, code) in cases {
+        let response: KvResponse<String> = KvResponse::new(Err(error));
+        let mut framed = Vec::new();
+        write_frame(&mut framed, &response).unwrap();
+        let decoded: KvResponse<String> = read_frame(&mut framed.as_slice()).unwrap().unwrap();
+        assert_eq!(decoded.error.unwrap().code, code);
+    }
+}
+
+// One GetMany answers for present and absent keys alike, in the order they were asked for
+#[test]
+fn get_many_mixed_keys() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(2)?, &shutdown)
+        })
+    };
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    let keys = ["key3", "key2", "key1", "key3"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    assert_eq!(
+        client.get_many(keys)?,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            Some("value1".to_owned()),
+            Some("value3".to_owned())
+        ]
+    );
+    assert!(client.get_many(Vec::new())?.is_empty());
+    assert_eq!(
+        client.get_or("key2".to_owned(), "fallback".to_owned())?,
+        "fallback"
+    );
+    assert_eq!(
+        client.get_or("key1".to_owned(), "fallback".to_owned())?,
+        "value1"
+    );
+    // Single gets still work alongside
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// An empty prefix matches every key and a prefix nothing starts with matches none
+#[test]
+fn scan_prefix_over_the_wire() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(2)?, &shutdown)
+        })
+    };
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    for key in ["user:2", "user:1", "users", "use", "admin:1"] {
+        client.set(key.to_owned(), format!("{} value", key))?;
+    }
+    let keys =
+        |pairs: Vec<(String, String)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    assert_eq!(
+        keys(client.scan_prefix("user:".to_owned())?),
+        vec!["user:1", "user:2"]
+    );
+    assert_eq!(
+        client.scan_prefix("use".to_owned())?,
+        vec![
+            ("use".to_owned(), "use value".to_owned()),
+            ("user:1".to_owned(), "user:1 value".to_owned()),
+            ("user:2".to_owned(), "user:2 value".to_owned()),
+            ("users".to_owned(), "users value".to_owned()),
+        ]
+    );
+    assert_eq!(
+        keys(client.scan_prefix(String::new())?),
+        vec!["admin:1", "use", "user:1", "user:2", "users"]
+    );
+    assert!(client.scan_prefix("nothing".to_owned())?.is_empty());
+    assert!(client.scan_prefix("zzz".to_owned())?.is_empty());
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// A frame over the server's limit is answered with an error instead of being read into memory
+#[test]
+fn oversized_frame_rejected() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(2)?,
+                &shutdown,
+                ServerConfig {
+                    max_frame_size: 1024,
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    // Only the length goes out, a server that tried to read the payload would hang here
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&(1u32 << 30).to_be_bytes())?;
+    let response: KvResponse<String> = read_frame(&mut stream)?.unwrap();
+    assert!(matches!(response.value, Err(KvError::ValueTooLarge)));
+    assert_eq!(response.error.unwrap().code, ErrorCode::TooLarge);
+
+    // Requests under the limit are still served on new connections
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+fn data_len(dir: &std::path::Path) -> u64 {
+    std::fs::read_dir(dir.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+// Compact is refused unless the server allows admin requests, and shrinks the log when it is
+#[test]
+fn compact_over_the_wire() -> kvs::Result<()> {
+    let serve_store = |temp_dir: &TempDir, allow_admin: bool| -> kvs::Result<_> {
+        let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                server::serve_with_config(
+                    listener,
+                    store,
+                    SharedQueueThreadPool::new(2)?,
+                    &shutdown,
+                    ServerConfig {
+                        allow_admin,
+                        ..ServerConfig::default()
+                    },
+                )
+            })
+        };
+        Ok((addr, shutdown, server))
+    };
+
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, shutdown, server) = serve_store(&temp_dir, false)?;
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    for iter in 0..20 {
+        client.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    let before = data_len(temp_dir.path());
+    assert!(matches!(client.compact(), Err(KvError::PermissionDenied)));
+    assert_eq!(data_len(temp_dir.path()), before);
+    drop(client);
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+
+    let (addr, shutdown, server) = serve_store(&temp_dir, true)?;
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.compact()?;
+    assert!(data_len(temp_dir.path()) < before);
+    assert_eq!(client.get("key".to_owned())?, Some("value19".to_owned()));
+    drop(client);
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// Overwrites and removes show up as dead bytes, and compaction resets them
+#[test]
+fn stats_over_the_wire() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(2)?,
+                &shutdown,
+                ServerConfig {
+                    allow_admin: true,
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    assert_eq!(client.stats()?.keys, 0);
+    for key_id in 0..10 {
+        client.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let fresh = client.stats()?;
+    assert_eq!(fresh.keys, 10);
+    assert_eq!(fresh.uncompressed_bytes, 0);
+
+    for key_id in 0..10 {
+        client.set(format!("key{}", key_id), "other".to_owned())?;
+    }
+    for key_id in 0..4 {
+        client.remove(format!("key{}", key_id))?;
+    }
+    let churned = client.stats()?;
+    assert_eq!(churned.keys, 6);
+    assert!(churned.uncompressed_bytes > 0);
+    assert!(churned.file_size > fresh.file_size);
+
+    client.compact()?;
+    let compacted = client.stats()?;
+    assert_eq!(compacted.keys, 6);
+    assert_eq!(compacted.uncompressed_bytes, 0);
+    assert_eq!(compacted.compactions, churned.compactions + 1);
+    assert!(compacted.file_size < churned.file_size);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// A client that stops part way through a frame is hung up on, freeing the only worker
+#[test]
+fn stalled_client_times_out() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(1)?,
+                &shutdown,
+                ServerConfig {
+                    read_timeout: Some(Duration::from_millis(200)),
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    let mut stalled = TcpStream::connect(addr)?;
+    stalled.write_all(&[0, 0])?;
+    stalled.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buf = [0u8; 1];
+    assert_eq!(stalled.read(&mut buf)?, 0);
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// A client trickling a request in a byte at a time, each well inside the read timeout, is
+// still hung up on once the whole request has taken longer than that
+#[test]
+fn trickling_client_times_out() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(1)?,
+                &shutdown,
+                ServerConfig {
+                    read_timeout: Some(Duration::from_millis(300)),
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    let mut trickling = TcpStream::connect(addr)?;
+    trickling.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut writer = trickling.try_clone()?;
+    let start = Instant::now();
+    let trickler = thread::spawn(move || {
+        writer.write_all(&100u32.to_be_bytes()).unwrap();
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(100));
+            if writer.write_all(b" ").is_err() {
+                break;
+            }
+        }
+    });
+    // Closed, or reset since the server hung up with the trickled bytes unread
+    let mut buf = [0u8; 1];
+    assert!(!matches!(trickling.read(&mut buf), Ok(n) if n > 0));
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    drop(client);
+    trickler.join().unwrap();
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// Shutdown doesn't wait on a connected client with no request in flight, even with no read
+// timeout, and only waits out the shutdown timeout for one stuck part way through a request
+#[test]
+fn shutdown_with_open_connections() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(2)?,
+                &shutdown,
+                ServerConfig {
+                    read_timeout: None,
+                    shutdown_timeout: Duration::from_millis(500),
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    let mut idle: KvsClient = KvsClient::connect(addr)?;
+    idle.set("key".to_owned(), "value".to_owned())?;
+    let mut stalled = TcpStream::connect(addr)?;
+    stalled.write_all(&[0, 0])?;
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(idle.get("key".to_owned()).is_err());
+    drop(stalled);
+
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// One client subscribes and sees the changes another one makes
+#[test]
+fn watch_over_the_wire() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(4)?, &shutdown)
+        })
+    };
+
+    let mut events = KvsClient::<String, String>::connect(addr)?.watch("user:".to_owned())?;
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.set("user:1".to_owned(), "alice".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    client.remove("user:1".to_owned())?;
+    assert_eq!(
+        events.next().unwrap()?,
+        WatchEvent::Set("user:1".to_owned(), "alice".to_owned())
+    );
+    assert_eq!(
+        events.next().unwrap()?,
+        WatchEvent::Removed("user:1".to_owned())
+    );
+
+    // A subscriber hanging up frees its worker
+    drop(events);
+    drop(client);
+    for _ in 0..8 {
+        let mut client: KvsClient = KvsClient::connect(addr)?;
+        client.set("key".to_owned(), "value".to_owned())?;
+    }
+
+    // And one still watching doesn't hold up shutdown
+    let _events = KvsClient::<String, String>::connect(addr)?.watch(String::new())?;
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// Watches don't keep pool workers, so more watchers than workers still leave room for other
+// clients
+#[test]
+fn watches_leave_workers_free() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(1)?, &shutdown)
+        })
+    };
+
+    let mut watches = (0..3)
+        .map(|_| KvsClient::<String, String>::connect(addr)?.watch("key".to_owned()))
+        .collect::<kvs::Result<Vec<_>>>()?;
+    let mut client: KvsClient = KvsClient::connect_with(addr, Duration::from_secs(2), 0)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    for events in &mut watches {
+        assert_eq!(
+            events.next().unwrap()?,
+            WatchEvent::Set("key".to_owned(), "value".to_owned())
+        );
+    }
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// A transaction whose read went stale fails, and the server's state is the winner's
+#[test]
+fn commit_over_the_wire() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(2)?, &shutdown)
+        })
+    };
+
+    let pool: KvsClientPool = KvsClientPool::new(addr, 2)?;
+    pool.set("key".to_owned(), "start".to_owned())?;
+    let (value, version) = pool.get_versioned("key".to_owned())?;
+    assert_eq!(value, Some("start".to_owned()));
+    let (value, absent) = pool.get_versioned("missing".to_owned())?;
+    assert_eq!((value, absent), (None, 0));
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    assert!(client.commit(
+        vec![("key".to_owned(), version)],
+        vec![KvRecord::Set(("key".to_owned(), "first".to_owned()))],
+    )?);
+    assert!(!pool.commit(
+        vec![("key".to_owned(), version), ("missing".to_owned(), absent)],
+        vec![KvRecord::Set(("missing".to_owned(), "second".to_owned()))],
+    )?);
+    assert_eq!(client.get("key".to_owned())?, Some("first".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    drop(client);
+    drop(pool);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// Each request's log lines carry the same id, so they can be matched up across connections
+#[test]
+fn request_ids_in_logs() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "-v"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut first: KvsClient = KvsClient::connect(addr.parse::<ServerAddr>().unwrap()).unwrap();
+    let mut second: KvsClient = KvsClient::connect(addr.parse::<ServerAddr>().unwrap()).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    second.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    first.get("key2".to_owned()).unwrap();
+    drop(first);
+    drop(second);
+    thread::sleep(Duration::from_millis(200));
+
+    let mut server = server;
+    server.kill().expect("server exited before killed");
+    let output = server
+        .wait_with_output()
+        .expect("unable to wait for server");
+    let logs = String::from_utf8_lossy(&output.stderr);
+    let id_of = |needle: &str| {
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Got from stream") && line.contains(needle))
+            .unwrap_or_else(|| panic!("no request line for {} in:\n{}", needle, logs));
+        let start = line.find('[').unwrap();
+        let end = line[start..].find(']').unwrap() + start;
+        line[start..=end].to_owned()
+    };
+    let ids = [id_of("key1"), id_of("key2"), id_of("Get")];
+    assert_ne!(ids[0], ids[1]);
+    assert_ne!(ids[0], ids[2]);
+    // The first connection's second request
+    assert_eq!(
+        ids[0].split('.').next(),
+        ids[2].split('.').next(),
+        "{:?}",
+        ids
+    );
+    for id in &ids {
+        assert!(
+            logs.lines()
+                .any(|line| line.contains(id.as_str()) && line.contains("Response from store")),
+            "no response line for {} in:\n{}",
+            id,
+            logs
+        );
+    }
+}
+
+// Logging a request with a huge value shows only its start and how long it was
+#[test]
+fn truncated_debug_output() {
+    let request: KvRequest<String, String> =
+        KvRequest::Set(("key".to_owned(), "v".repeat(1024 * 1024)));
+    let full = format!("{:?}", request);
+    let truncated = format!("{:?}", Truncated(&request));
+    assert!(truncated.len() < 2 * TRUNCATED_LEN);
+    assert!(truncated.starts_with(&full[..TRUNCATED_LEN]));
+    assert!(truncated.ends_with(&format!("... ({} bytes)", full.len())));
+
+    // Short output is left alone, and multi-byte characters aren't split
+    let small: KvRequest<String, String> = KvRequest::Get("key".to_owned());
+    assert_eq!(format!("{:?}", Truncated(&small)), format!("{:?}", small));
+    let wide: KvRequest<String, String> = KvRequest::Get("é".repeat(100));
+    let truncated = format!("{:?}", Truncated(&wide));
+    assert!(truncated.starts_with("Get(\"é"));
+    assert!(truncated.ends_with(&format!("... ({} bytes)", format!("{:?}", wide).len())));
+}
+
+// The same protocol runs over a UNIX domain socket, and the server removes the socket file it
+// created once it stops
+#[cfg(unix)]
+#[test]
+fn unix_socket_round_trip() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let socket_path = temp_dir.path().join("kvs.sock");
+    let addr: ServerAddr = format!("unix:{}", socket_path.display()).parse()?;
+    assert_eq!(addr, ServerAddr::Unix(socket_path.clone()));
+    let listener = Listener::bind(&addr)?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve(listener, store, SharedQueueThreadPool::new(2)?, &shutdown)
+        })
+    };
+
+    let mut client: KvsClient = KvsClient::connect(addr.clone())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    assert!(!socket_path.exists());
+    Ok(())
+}
+
+// A server with an auth token refuses everything until the connection sends the right one, and
+// a wrong token doesn't let it through
+#[test]
+fn auth_token_required() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(2)?,
+                &shutdown,
+                ServerConfig {
+                    auth_token: Some(AuthToken::new("secret")),
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    let mut missing: KvsClient = KvsClient::connect(addr)?;
+    assert!(matches!(
+        missing.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvError::Unauthorized)
+    ));
+    assert!(matches!(
+        missing.get("key1".to_owned()),
+        Err(KvError::Unauthorized)
+    ));
+    assert!(matches!(
+        missing.remove("key1".to_owned()),
+        Err(KvError::Unauthorized)
+    ));
+    drop(missing);
+
+    let mut wrong: KvsClient = KvsClient::connect(addr)?;
+    assert!(matches!(
+        wrong.authenticate("secreT"),
+        Err(KvError::Unauthorized)
+    ));
+    assert!(matches!(
+        wrong.get("key1".to_owned()),
+        Err(KvError::Unauthorized)
+    ));
+    drop(wrong);
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    client.authenticate("secret")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    drop(client);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// The token is kept out of logged configs
+#[test]
+fn auth_token_debug_hides_secret() {
+    let config = ServerConfig {
+        auth_token: Some(AuthToken::new("secret")),
+        ..ServerConfig::default()
+    };
+    assert!(!format!("{:?}", config).contains("secret"));
+}
+
+// Connections past the limit are told so and closed, and a slot frees up once a connection
+// closes
+#[test]
+fn max_connections_refuses_excess() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(4)?,
+                &shutdown,
+                ServerConfig {
+                    max_connections: Some(2),
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    let mut first: KvsClient = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    let mut second: KvsClient = KvsClient::connect(addr)?;
+    second.set("key2".to_owned(), "value2".to_owned())?;
+    for _ in 0..5 {
+        let mut excess: KvsClient = KvsClient::connect(addr)?;
+        assert!(matches!(
+            excess.get("key1".to_owned()),
+            Err(KvError::TooManyConnections)
+        ));
+    }
+    assert_eq!(first.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The server only notices the close on its next read, so give it a moment
+    drop(first);
+    let mut attempts = 0;
+    let value = loop {
+        let mut client: KvsClient = KvsClient::connect(addr)?;
+        match client.get("key1".to_owned()) {
+            Err(KvError::TooManyConnections) if attempts < 50 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            result => break result?,
+        }
+    };
+    assert_eq!(value, Some("value1".to_owned()));
+    drop(second);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+    Ok(())
+}
+
+// Ping is answered even before authenticating, and against a server that isn't there it fails
+// without waiting out the retries
+#[test]
+fn ping() -> kvs::Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store: KvStore<String, String> = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let server = {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || {
+            server::serve_with_config(
+                listener,
+                store,
+                SharedQueueThreadPool::new(2)?,
+                &shutdown,
+                ServerConfig {
+                    auth_token: Some(AuthToken::new("secret")),
+                    ..ServerConfig::default()
+                },
+            )
+        })
+    };
+
+    let mut client: KvsClient = KvsClient::connect(addr)?;
+    for _ in 0..10 {
+        assert!(client.ping()? < Duration::from_secs(1));
+    }
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvError::Unauthorized)
+    ));
+    drop(client);
+    let pool: KvsClientPool = KvsClientPool::new(addr, 1)?;
+    pool.ping()?;
+    drop(pool);
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap()?;
+
+    let start = std::time::Instant::now();
+    let down = KvsClient::<String, String>::connect_with(addr, Duration::from_millis(200), 0)
+        .and_then(|mut client| client.ping());
+    assert!(matches!(down, Err(KvError::ConnectionFailed(_))));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
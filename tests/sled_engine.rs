@@ -0,0 +1,184 @@
+use kvs::engine::store::{Key, Value};
+use kvs::engine::{sled::SledKvsEngine, KvsEngine};
+use kvs::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Bound;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// sled's background threads can keep the database locked for a moment after the last handle
+// is dropped, so reopening straight away is retried until they let go
+fn reopen<K: Key, V: Value>(path: &Path) -> Result<SledKvsEngine<K, V>> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match SledKvsEngine::new(path) {
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            opened => return opened,
+        }
+    }
+}
+
+// Scans return pairs in key order and respect inclusive and exclusive bounds
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: SledKvsEngine = SledKvsEngine::new(temp_dir.path())?;
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key5".to_owned())?;
+
+    let keys =
+        |pairs: Vec<(String, String)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+
+    let inclusive = store.scan(
+        Bound::Included("key2".to_owned()),
+        Bound::Included("key6".to_owned()),
+    )?;
+    assert_eq!(keys(inclusive), vec!["key2", "key3", "key4", "key6"]);
+    let exclusive = store.scan(
+        Bound::Excluded("key2".to_owned()),
+        Bound::Excluded("key6".to_owned()),
+    )?;
+    assert_eq!(keys(exclusive), vec!["key3", "key4"]);
+    assert!(store
+        .scan(
+            Bound::Included("key8".to_owned()),
+            Bound::Included("key2".to_owned())
+        )?
+        .is_empty());
+    assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 9);
+
+    Ok(())
+}
+
+#[test]
+fn compact_keeps_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: SledKvsEngine = SledKvsEngine::new(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 50..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    store.compact()?;
+    assert!(store.size_on_disk()? > 0);
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+    assert_eq!(store.get("key50".to_owned())?, None);
+
+    store.flush()?;
+    drop(store);
+    let store: SledKvsEngine = reopen(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("9".to_owned()));
+    assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 50);
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Point {
+    x: i32,
+    y: i32,
+    label: String,
+}
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}, {})", self.label, self.x, self.y)
+    }
+}
+
+impl Value for Point {}
+
+// Non-string keys and values round trip, and scans still come back in key order
+#[test]
+fn typed_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: SledKvsEngine<u64, Point> = SledKvsEngine::new(temp_dir.path())?;
+    let point = |id: u64| Point {
+        x: id as i32,
+        y: -(id as i32),
+        label: format!("point{}", id),
+    };
+    // Large and small ids have differently sized encodings, which mustn't affect ordering
+    for id in [1_000_000, 3, 300, 70_000, 0, 12] {
+        store.set(id, point(id))?;
+    }
+    assert_eq!(store.get(300)?, Some(point(300)));
+    assert_eq!(store.get(4)?, None);
+    store.remove(12)?;
+    assert!(matches!(store.remove(12), Err(KvsError::NonExistantKey)));
+
+    let keys = store
+        .scan(Bound::Included(3), Bound::Unbounded)?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, vec![3, 300, 70_000, 1_000_000]);
+
+    store.flush()?;
+    drop(store);
+    let store: SledKvsEngine<u64, Point> = reopen(temp_dir.path())?;
+    assert_eq!(store.get(1_000_000)?, Some(point(1_000_000)));
+    Ok(())
+}
+
+// Bytes written by another tool that aren't valid UTF-8 come back as an error, not a panic
+#[test]
+fn invalid_utf8_is_an_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    {
+        let db = sled::open(temp_dir.path())?;
+        // A two byte MessagePack string holding bytes that aren't UTF-8
+        let invalid = [0xa2, 0xff, 0xfe];
+        db.insert(rmp_serde::to_vec("bad value").unwrap(), &invalid)?;
+        db.insert(invalid, rmp_serde::to_vec("value").unwrap())?;
+        db.flush()?;
+    }
+
+    let store: SledKvsEngine = SledKvsEngine::new(temp_dir.path())?;
+    assert!(matches!(
+        store.get("bad value".to_owned()),
+        Err(KvsError::SerializationError(_))
+    ));
+    assert!(store.scan(Bound::Unbounded, Bound::Unbounded).is_err());
+    store.set("good".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("good".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Threads in this process, from /proc
+#[cfg(target_os = "linux")]
+fn thread_count() -> usize {
+    std::fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+// Watches opened and dropped on an idle database share one thread instead of each leaving one
+// behind waiting for a write that never comes. Counting threads needs /proc
+#[cfg(target_os = "linux")]
+#[test]
+fn dropped_watches_share_a_thread() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store: SledKvsEngine = SledKvsEngine::new(temp_dir.path())?;
+    let before = thread_count();
+    for key_id in 0..200 {
+        drop(store.watch(format!("key{}", key_id))?);
+    }
+    // Other tests run alongside this one, so leave room for their threads
+    assert!(thread_count() < before + 50);
+
+    let events = store.watch("key".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(events
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .is_ok());
+    Ok(())
+}
@@ -1,13 +1,17 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 use kvs::thread_pool::naive::NaiveThreadPool;
 use kvs::thread_pool::rayon::RayonThreadPool;
-use kvs::thread_pool::shared_queue::SharedQueueThreadPool;
+use kvs::thread_pool::shared_queue::{PendingJobs, SharedQueueThreadPool};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     const TASK_NUM: usize = 20;
@@ -24,7 +28,7 @@ fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
                 counter.fetch_add(1, Ordering::SeqCst);
             }
             drop(wg);
-        })
+        })?;
     }
 
     wg.wait();
@@ -43,7 +47,7 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
             panic_control::disable_hook_in_current_thread();
 
             panic!();
-        })
+        })?;
     }
 
     spawn_counter(pool)
@@ -71,3 +75,278 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<NaiveThreadPool>()
+}
+
+// The naive pool starts threads on demand, but never more than it was asked for
+#[test]
+fn naive_thread_pool_bounds_concurrency() -> Result<()> {
+    const TASK_NUM: usize = 1000;
+
+    let pool = NaiveThreadPool::new(4)?;
+    let wg = WaitGroup::new();
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let running = Arc::clone(&running);
+        let peak = Arc::clone(&peak);
+        let wg = wg.clone();
+        pool.spawn(move || {
+            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now_running, Ordering::SeqCst);
+            thread::yield_now();
+            running.fetch_sub(1, Ordering::SeqCst);
+            drop(wg);
+        })?;
+    }
+
+    wg.wait();
+    assert!(peak.load(Ordering::SeqCst) <= 4);
+    Ok(())
+}
+
+// Panics with a payload that panics again when dropped, which gets past the worker's
+// catch_unwind and kills the thread
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("payload dropped");
+    }
+}
+
+#[test]
+fn shared_queue_thread_pool_replaces_dead_worker() -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    let pool = SharedQueueThreadPool::new(1)?;
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        std::panic::panic_any(PanicOnDrop);
+    })?;
+
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..TASK_NUM {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(i).unwrap())?;
+    }
+    for _ in 0..TASK_NUM {
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("job never ran after the worker died");
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_times_out() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    pool.spawn(|| thread::sleep(Duration::from_secs(3)))?;
+    // Give the worker a moment to pick the job up
+    thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    assert!(!pool.shutdown(Duration::from_millis(100), PendingJobs::Run));
+    assert!(start.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
+
+// Once the pool is shut down a job is refused up front instead of being queued where no
+// worker will ever run it
+#[test]
+fn shared_queue_thread_pool_spawn_after_shutdown() -> Result<()> {
+    let pool = SharedQueueThreadPool::new_bounded(2, 4)?;
+    assert!(pool.shutdown(Duration::from_secs(5), PendingJobs::Run));
+    assert!(matches!(pool.spawn(|| {}), Err(KvsError::PoolStopped)));
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvsError::PoolStopped)));
+    assert!(matches!(
+        pool.spawn_handle(|| 1),
+        Err(KvsError::PoolStopped)
+    ));
+    assert!(pool.shutdown(Duration::from_secs(5), PendingJobs::Run));
+    Ok(())
+}
+
+fn shutdown_with_pending(pending: PendingJobs) -> Result<usize> {
+    const TASK_NUM: usize = 20;
+
+    let pool = SharedQueueThreadPool::new(1)?;
+    let (sender, receiver) = mpsc::channel::<()>();
+    // Keep the only worker busy until everything else is queued
+    pool.spawn(move || {
+        let _ = receiver.recv();
+    })?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })?;
+    }
+
+    let handle = thread::spawn(move || pool.shutdown(Duration::from_secs(5), pending));
+    thread::sleep(Duration::from_millis(100));
+    drop(sender);
+    assert!(handle.join().unwrap());
+    Ok(counter.load(Ordering::SeqCst))
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_runs_pending() -> Result<()> {
+    assert_eq!(shutdown_with_pending(PendingJobs::Run)?, 20);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_discards_pending() -> Result<()> {
+    assert_eq!(shutdown_with_pending(PendingJobs::Discard)?, 0);
+    Ok(())
+}
+
+fn spawn_handle_results<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let receivers: Vec<_> = (0..20u64)
+        .map(|i| pool.spawn_handle(move || i * i))
+        .collect::<Result<_>>()?;
+    for (i, receiver) in receivers.into_iter().enumerate() {
+        assert_eq!(receiver.recv().unwrap(), (i * i) as u64);
+    }
+
+    let receiver = pool.spawn_handle(|| -> u64 {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    })?;
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(5)),
+        Err(mpsc::RecvTimeoutError::Disconnected)
+    );
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_spawn_handle() -> Result<()> {
+    spawn_handle_results::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_handle() -> Result<()> {
+    spawn_handle_results::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_spawn_handle() -> Result<()> {
+    spawn_handle_results::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_bounded_backpressure() -> Result<()> {
+    let pool = Arc::new(SharedQueueThreadPool::new_bounded(1, 2)?);
+    let (release, blocked) = mpsc::channel::<()>();
+    let (started_sender, started) = mpsc::channel();
+    pool.spawn(move || {
+        started_sender.send(()).unwrap();
+        let _ = blocked.recv();
+    })?;
+    started.recv().unwrap();
+
+    // The worker is busy, so these fill the queue
+    pool.try_spawn(|| {})?;
+    pool.try_spawn(|| {})?;
+    match pool.try_spawn(|| {}) {
+        Err(KvsError::QueueFull) => {}
+        other => panic!("expected QueueFull, got {:?}", other),
+    }
+
+    let spawned = Arc::new(AtomicUsize::new(0));
+    let producer = {
+        let pool = Arc::clone(&pool);
+        let spawned = Arc::clone(&spawned);
+        thread::spawn(move || {
+            pool.spawn(|| {}).unwrap();
+            spawned.fetch_add(1, Ordering::SeqCst);
+        })
+    };
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(spawned.load(Ordering::SeqCst), 0);
+
+    drop(release);
+    producer.join().unwrap();
+    assert_eq!(spawned.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+// Keeps every log record the pools emit, so tests can check for them
+struct CapturingLogger {
+    records: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.records
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+fn capture_logs() -> &'static CapturingLogger {
+    static LOGGER: OnceLock<&'static CapturingLogger> = OnceLock::new();
+    LOGGER.get_or_init(|| {
+        let logger = Box::leak(Box::new(CapturingLogger {
+            records: Mutex::new(Vec::new()),
+        }));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+        logger
+    })
+}
+
+// Waits for an error record starting with `prefix`
+fn wait_for_error(logger: &CapturingLogger, prefix: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        let logged = logger
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(level, message)| *level == Level::Error && message.starts_with(prefix));
+        if logged {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+// A panicking job is reported through the log crate rather than printed
+#[test]
+fn panicking_job_is_logged() -> Result<()> {
+    let logger = capture_logs();
+    let panicking_job = || {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    };
+
+    let pool = NaiveThreadPool::new(1)?;
+    pool.spawn(panicking_job)?;
+    assert!(wait_for_error(logger, "Naive thread pool job panicked"));
+
+    let pool = SharedQueueThreadPool::new(1)?;
+    pool.spawn(panicking_job)?;
+    assert!(wait_for_error(logger, "Worker 0 panicked running job"));
+
+    let pool = RayonThreadPool::new(1)?;
+    pool.spawn(panicking_job)?;
+    assert!(wait_for_error(logger, "Rayon thread pool job panicked"));
+    Ok(())
+}